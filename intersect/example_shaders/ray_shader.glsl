@@ -84,7 +84,7 @@ float rand_float(inout uint seed)
 	return uintBitsToFloat(one | (msk & (rand_int(seed) >> 9))) - 1;
 }
 
-Ray shade(Ray ray, uint instance_id, uint primitive_id, float t, vec2 attributes, mat4 transform){
+Ray shade(Ray ray, uint instance_id, uint primitive_id, float t, vec2 attributes, mat4 transform, Surface surface){
     if(t < 10000 && t > 0)
     {
         // uint i0 = indices[primitive_id];
@@ -1,4 +1,4 @@
-use std::{rc::Rc, time::Instant};
+use std::{sync::Arc, time::Instant};
 
 use intersect::{
     bvh::Bvh,
@@ -15,9 +15,9 @@ fn main() {
     let (vertices, indices) = read_triangle_file("unity.tri");
     let mut framebuffer = Framebuffer::new(640, 640, HdrColor::new(0.0, 0.0, 0.0, 0.0));
     let camera = Camera::new(Position::new(-5.0, 0.0, -15.0), 2.0);
-    let tracer = CpuTracer {};
+    let tracer = CpuTracer::new();
 
-    let midpoint_split_acc = Rc::new(Bvh::new(&vertices, &indices));
+    let midpoint_split_acc = Arc::new(Bvh::new(&vertices, &indices));
 
     let instances = [Instance::new(midpoint_split_acc, 0, Mat4::from_scale(1.0))];
 
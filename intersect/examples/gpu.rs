@@ -5,6 +5,7 @@ use intersect::{
         blas::Geometry,
         gpu::Gpu,
         gpu_acceleration_structure::GpuTlas,
+        gpu_ray_generator::GpuRay,
         instance::Instance,
         ray_tracing_pipeline::RayTracingPipeline,
         ray_tracing_pipeline_descriptor::{
@@ -12,7 +13,7 @@ use intersect::{
         },
     },
     read_triangle_file,
-    types::{DataType, HdrColor, Mat4, Ray, Vec3, AABB},
+    types::{DataType, HdrColor, Mat4, Vec3, AABB},
     write_hdr_buffer_to_file, write_ray_buffer_to_file,
 };
 use vk_utils::{
@@ -94,7 +95,7 @@ fn main() {
     );
     command_buffer.submit();
 
-    let ray_buffer_data: Vec<Ray> = frame_data.ray_buffer.copy_data();
+    let ray_buffer_data: Vec<GpuRay> = frame_data.ray_buffer.copy_data();
     write_ray_buffer_to_file(
         "ray_buffer.png",
         &ray_buffer_data,
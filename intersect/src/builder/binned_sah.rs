@@ -0,0 +1,196 @@
+use crate::{bvh::BvhBuildOptions, types::AABB};
+
+use super::{BuildNode, BuildOutput, BuildPrimitives, PARALLEL_THRESHOLD};
+
+#[derive(Clone, Copy, Default)]
+struct Bin {
+    aabb: AABB,
+    count: usize,
+}
+
+struct Split {
+    axis: usize,
+    bin: usize,
+    cost: f32,
+}
+
+pub fn build(primitives: &BuildPrimitives, options: &BvhBuildOptions) -> BuildOutput {
+    let mut references: Vec<u32> = (0..primitives.len() as u32).collect();
+    let root = subdivide(primitives, options, &mut references, 0, 0);
+    BuildOutput::new(&root, references)
+}
+
+fn subdivide(
+    primitives: &BuildPrimitives,
+    options: &BvhBuildOptions,
+    references: &mut [u32],
+    offset: usize,
+    depth: usize,
+) -> BuildNode {
+    let mut aabb = AABB::default();
+    let mut centroid_bounds = AABB::default();
+    for reference in references.iter() {
+        aabb.grow(&primitives.bounds[*reference as usize]);
+        centroid_bounds.grow_with_position(&primitives.centroids[*reference as usize]);
+    }
+
+    let count = references.len();
+    if count <= 1 || depth >= options.depth_limit() {
+        return BuildNode::leaf(aabb, offset, count);
+    }
+
+    let leaf_cost = count as f32 * aabb.area();
+    let must_split = count > options.max_leaf_size;
+    let mid = match find_split(primitives, options, references, &aabb, &centroid_bounds) {
+        Some(split) if must_split || split.cost < leaf_cost => {
+            partition(primitives, options, references, &centroid_bounds, &split)
+        }
+        // All centroids coincide, binning can't separate them so split the range in half
+        None if must_split => count / 2,
+        _ => return BuildNode::leaf(aabb, offset, count),
+    };
+
+    let (left, right) = references.split_at_mut(mid);
+    let (left, right) = if count >= PARALLEL_THRESHOLD {
+        rayon::join(
+            || subdivide(primitives, options, left, offset, depth + 1),
+            || subdivide(primitives, options, right, offset + mid, depth + 1),
+        )
+    } else {
+        (
+            subdivide(primitives, options, left, offset, depth + 1),
+            subdivide(primitives, options, right, offset + mid, depth + 1),
+        )
+    };
+
+    BuildNode::interior(aabb, left, right)
+}
+
+fn bin_index(centroid: f32, min: f32, scale: f32, bin_count: usize) -> usize {
+    (((centroid - min) * scale) as usize).min(bin_count - 1)
+}
+
+fn find_split(
+    primitives: &BuildPrimitives,
+    options: &BvhBuildOptions,
+    references: &[u32],
+    aabb: &AABB,
+    centroid_bounds: &AABB,
+) -> Option<Split> {
+    let bin_count = options.bin_count.max(2);
+    let mut best: Option<Split> = None;
+    let mut bins = vec![Bin::default(); bin_count];
+    let mut left_areas = vec![0.0; bin_count - 1];
+    let mut left_counts = vec![0; bin_count - 1];
+
+    for axis in 0..3 {
+        let min = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - min;
+        if extent <= 0.0 {
+            continue;
+        }
+
+        bins.fill(Bin::default());
+        let scale = bin_count as f32 / extent;
+        for reference in references {
+            let bin = &mut bins[bin_index(
+                primitives.centroids[*reference as usize][axis],
+                min,
+                scale,
+                bin_count,
+            )];
+            bin.count += 1;
+            bin.aabb.grow(&primitives.bounds[*reference as usize]);
+        }
+
+        let mut left_box = AABB::default();
+        let mut left_count = 0;
+        for i in 0..bin_count - 1 {
+            left_box.grow(&bins[i].aabb);
+            left_count += bins[i].count;
+            left_areas[i] = if left_count > 0 { left_box.area() } else { 0.0 };
+            left_counts[i] = left_count;
+        }
+
+        let mut right_box = AABB::default();
+        let mut right_count = 0;
+        for i in (1..bin_count).rev() {
+            right_box.grow(&bins[i].aabb);
+            right_count += bins[i].count;
+            let left_count = left_counts[i - 1];
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+
+            let cost = options.traversal_cost * aabb.area()
+                + left_count as f32 * left_areas[i - 1]
+                + right_count as f32 * right_box.area();
+            if best.as_ref().is_none_or(|best| cost < best.cost) {
+                best = Some(Split { axis, bin: i, cost });
+            }
+        }
+    }
+
+    best
+}
+
+fn partition(
+    primitives: &BuildPrimitives,
+    options: &BvhBuildOptions,
+    references: &mut [u32],
+    centroid_bounds: &AABB,
+    split: &Split,
+) -> usize {
+    let bin_count = options.bin_count.max(2);
+    let min = centroid_bounds.min[split.axis];
+    let scale = bin_count as f32 / (centroid_bounds.max[split.axis] - min);
+
+    let mut i = 0;
+    let mut j = references.len();
+    while i < j {
+        let centroid = primitives.centroids[references[i] as usize][split.axis];
+        if bin_index(centroid, min, scale, bin_count) < split.bin {
+            i += 1;
+        } else {
+            j -= 1;
+            references.swap(i, j);
+        }
+    }
+
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::SquareMatrix;
+
+    use crate::{
+        bvh::{Bvh, BvhBuildOptions},
+        testing::{assert_matches_sah_build, closest_hit, soup, Rng},
+        types::{HitRecord, Mat4},
+    };
+
+    #[test]
+    fn binned_builds_find_the_closest_hits() {
+        // Enough triangles for the top of the tree to be built in parallel
+        let (vertices, indices) = soup(6000, 1);
+        let bvh = Bvh::new(&vertices, &indices);
+        assert_eq!(bvh.primitives(), Bvh::new(&vertices, &indices).primitives());
+
+        let mut rng = Rng::new(2);
+        for _ in 0..200 {
+            let ray = rng.ray();
+            let mut hit = HitRecord::new();
+            bvh.traverse(&ray, &Mat4::identity(), &mut hit);
+            let expected = closest_hit(&vertices, &indices, &ray);
+            assert!(hit.t == expected || (hit.t - expected).abs() < expected * 1e-5);
+        }
+
+        let options = BvhBuildOptions::new()
+            .with_bin_count(4)
+            .with_max_leaf_size(1)
+            .with_traversal_cost(2.0);
+        let bvh = Bvh::new_with_options(&vertices, &indices, &options);
+        assert_matches_sah_build(&bvh, &vertices, &indices);
+    }
+}
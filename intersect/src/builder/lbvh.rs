@@ -0,0 +1,101 @@
+use crate::{
+    bvh::{BvhBuildOptions, MortonCode},
+    types::AABB,
+};
+
+use super::{morton, BuildNode, BuildOutput, BuildPrimitives, PARALLEL_THRESHOLD};
+
+struct Context<'a> {
+    primitives: &'a BuildPrimitives,
+    options: &'a BvhBuildOptions,
+}
+
+pub fn build(
+    primitives: &BuildPrimitives,
+    options: &BvhBuildOptions,
+    morton_code: MortonCode,
+) -> BuildOutput {
+    let (codes, references): (Vec<u64>, Vec<u32>) =
+        morton::sorted_morton_codes(primitives, morton_code)
+            .into_iter()
+            .unzip();
+    let context = Context {
+        primitives,
+        options,
+    };
+    let root = subdivide(&context, &codes, &references, 0, 0);
+    BuildOutput::new(&root, references)
+}
+
+fn subdivide(
+    context: &Context,
+    codes: &[u64],
+    references: &[u32],
+    offset: usize,
+    depth: usize,
+) -> BuildNode {
+    let count = codes.len();
+    if count <= context.options.max_leaf_size.max(1) || depth >= context.options.depth_limit() {
+        let mut aabb = AABB::default();
+        references
+            .iter()
+            .for_each(|r| aabb.grow(&context.primitives.bounds[*r as usize]));
+        return BuildNode::leaf(aabb, offset, count);
+    }
+
+    // Split where the highest bit that differs within the range flips. Codes are
+    // sorted so everything before that point has the bit cleared.
+    let first = codes[0];
+    let last = codes[count - 1];
+    let mid = if first == last {
+        count / 2
+    } else {
+        let bit = 63 - (first ^ last).leading_zeros();
+        codes.partition_point(|code| code & (1 << bit) == 0)
+    };
+
+    let (left_codes, right_codes) = codes.split_at(mid);
+    let (left_references, right_references) = references.split_at(mid);
+    let left = || subdivide(context, left_codes, left_references, offset, depth + 1);
+    let right = || {
+        subdivide(
+            context,
+            right_codes,
+            right_references,
+            offset + mid,
+            depth + 1,
+        )
+    };
+    let (left, right) = if count >= PARALLEL_THRESHOLD {
+        rayon::join(left, right)
+    } else {
+        (left(), right())
+    };
+
+    let mut aabb = *left.aabb();
+    aabb.grow(right.aabb());
+    BuildNode::interior(aabb, left, right)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bvh::{BuildStrategy, Bvh, BvhBuildOptions, MortonCode},
+        testing::{assert_matches_sah_build, soup},
+    };
+
+    #[test]
+    fn linear_builds_match_the_sah_build() {
+        // Enough triangles for the tree to be emitted in parallel
+        let (vertices, indices) = soup(6000, 7);
+        for morton_code in [MortonCode::Bits30, MortonCode::Bits63] {
+            let options =
+                BvhBuildOptions::new().with_strategy(BuildStrategy::Linear { morton_code });
+            let bvh = Bvh::new_with_options(&vertices, &indices, &options);
+            assert_eq!(bvh.primitives().len(), 6000);
+            assert_matches_sah_build(&bvh, &vertices, &indices);
+        }
+        let bvh = Bvh::new_linear(&vertices, &indices);
+        assert_matches_sah_build(&bvh, &vertices, &indices);
+    }
+}
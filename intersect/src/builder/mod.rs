@@ -0,0 +1,114 @@
+pub mod binned_sah;
+pub mod lbvh;
+pub mod morton;
+pub mod optimize;
+pub mod ploc;
+pub mod sbvh;
+
+use rayon::prelude::*;
+
+use crate::{
+    bvh::Node,
+    primitive::PrimitiveSet,
+    types::{Vec3, AABB},
+};
+
+// Builders split the work between threads until subtrees get smaller than
+// this, below it spawning tasks costs more than it saves
+pub(crate) const PARALLEL_THRESHOLD: usize = 4096;
+
+// Per primitive data shared by all builders. Primitives are addressed by their
+// position in these arrays, builders only ever shuffle references to them.
+pub struct BuildPrimitives {
+    pub bounds: Vec<AABB>,
+    pub centroids: Vec<Vec3>,
+}
+
+impl BuildPrimitives {
+    pub fn new<G: PrimitiveSet>(primitives: &G) -> Self {
+        let (bounds, centroids) = (0..primitives.len())
+            .into_par_iter()
+            .map(|primitive| (primitives.aabb(primitive), primitives.centroid(primitive)))
+            .unzip();
+
+        Self { bounds, centroids }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bounds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bounds.is_empty()
+    }
+}
+
+pub enum BuildNode {
+    Leaf {
+        aabb: AABB,
+        first: usize,
+        count: usize,
+    },
+    Interior {
+        aabb: AABB,
+        children: Box<(BuildNode, BuildNode)>,
+    },
+}
+
+impl BuildNode {
+    pub fn leaf(aabb: AABB, first: usize, count: usize) -> Self {
+        BuildNode::Leaf { aabb, first, count }
+    }
+
+    pub fn interior(aabb: AABB, left: BuildNode, right: BuildNode) -> Self {
+        BuildNode::Interior {
+            aabb,
+            children: Box::new((left, right)),
+        }
+    }
+
+    pub fn aabb(&self) -> &AABB {
+        match self {
+            BuildNode::Leaf { aabb, .. } => aabb,
+            BuildNode::Interior { aabb, .. } => aabb,
+        }
+    }
+}
+
+pub struct BuildOutput {
+    pub nodes: Vec<Node>,
+    pub primitives: Vec<u32>,
+}
+
+impl BuildOutput {
+    // Flattens the build tree depth first. Siblings are always stored next to each
+    // other so an interior node only needs the index of its left child.
+    pub fn new(root: &BuildNode, primitives: Vec<u32>) -> Self {
+        let mut nodes = vec![Node::default()];
+        Self::flatten(root, 0, &mut nodes);
+        Self { nodes, primitives }
+    }
+
+    fn flatten(build_node: &BuildNode, idx: usize, nodes: &mut Vec<Node>) {
+        match build_node {
+            BuildNode::Leaf { aabb, first, count } => {
+                nodes[idx] = Node {
+                    aabb: *aabb,
+                    first_primitive: *first as u32,
+                    primitive_count: *count as u32,
+                };
+            }
+            BuildNode::Interior { aabb, children } => {
+                let left_child_index = nodes.len();
+                nodes.extend([Node::default(), Node::default()]);
+                nodes[idx] = Node {
+                    aabb: *aabb,
+                    first_primitive: left_child_index as u32,
+                    primitive_count: 0,
+                };
+                Self::flatten(&children.0, left_child_index, nodes);
+                Self::flatten(&children.1, left_child_index + 1, nodes);
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use rayon::prelude::*;
+
+use crate::{bvh::MortonCode, types::AABB};
+
+use super::BuildPrimitives;
+
+const RADIX_BITS: u32 = 8;
+const RADIX: usize = 1 << RADIX_BITS;
+// Below this many keys a bucket is sorted on the current thread.
+const PARALLEL_THRESHOLD: usize = 1 << 14;
+
+impl MortonCode {
+    pub fn bits(&self) -> u32 {
+        match self {
+            MortonCode::Bits30 => 30,
+            MortonCode::Bits63 => 63,
+        }
+    }
+
+    pub(crate) fn encode(&self, x: f32, y: f32, z: f32) -> u64 {
+        let resolution = (1u64 << (self.bits() / 3)) as f32;
+        let quantize = |v: f32| ((v * resolution) as u64).min(resolution as u64 - 1);
+        match self {
+            MortonCode::Bits30 => {
+                (expand_bits_10(quantize(x)) << 2)
+                    | (expand_bits_10(quantize(y)) << 1)
+                    | expand_bits_10(quantize(z))
+            }
+            MortonCode::Bits63 => {
+                (expand_bits_21(quantize(x)) << 2)
+                    | (expand_bits_21(quantize(y)) << 1)
+                    | expand_bits_21(quantize(z))
+            }
+        }
+    }
+}
+
+// Spreads the lower 10 bits so there are two zero bits between each of them
+fn expand_bits_10(v: u64) -> u64 {
+    let mut x = v & 0x3ff;
+    x = (x | x << 16) & 0x30000ff;
+    x = (x | x << 8) & 0x300f00f;
+    x = (x | x << 4) & 0x30c30c3;
+    x = (x | x << 2) & 0x9249249;
+    x
+}
+
+// Spreads the lower 21 bits so there are two zero bits between each of them
+fn expand_bits_21(v: u64) -> u64 {
+    let mut x = v & 0x1fffff;
+    x = (x | x << 32) & 0x1f00000000ffff;
+    x = (x | x << 16) & 0x1f0000ff0000ff;
+    x = (x | x << 8) & 0x100f00f00f00f00f;
+    x = (x | x << 4) & 0x10c30c30c30c30c3;
+    x = (x | x << 2) & 0x1249249249249249;
+    x
+}
+
+// Returns (morton code, primitive) pairs sorted by code. Primitives with equal
+// codes keep their original order so the result is deterministic.
+pub fn sorted_morton_codes(primitives: &BuildPrimitives, code: MortonCode) -> Vec<(u64, u32)> {
+    let centroid_bounds = primitives
+        .centroids
+        .par_iter()
+        .fold(AABB::default, |mut aabb, centroid| {
+            aabb.grow_with_position(centroid);
+            aabb
+        })
+        .reduce(AABB::default, |mut lhs, rhs| {
+            lhs.grow(&rhs);
+            lhs
+        });
+
+    let extent = centroid_bounds.extent();
+    let inv_extent = extent.map(|e| if e > 0.0 { 1.0 / e } else { 0.0 });
+    let mut keys: Vec<(u64, u32)> = primitives
+        .centroids
+        .par_iter()
+        .enumerate()
+        .map(|(primitive, centroid)| {
+            let p = centroid - centroid_bounds.min;
+            let key = code.encode(p.x * inv_extent.x, p.y * inv_extent.y, p.z * inv_extent.z);
+            (key, primitive as u32)
+        })
+        .collect();
+
+    radix_sort(&mut keys, code.bits());
+    keys
+}
+
+fn digit(key: u64, shift: u32) -> usize {
+    ((key >> shift) as usize) & (RADIX - 1)
+}
+
+fn histogram(keys: &[(u64, u32)], shift: u32) -> [usize; RADIX] {
+    let mut counts = [0; RADIX];
+    keys.iter()
+        .for_each(|(key, _)| counts[digit(*key, shift)] += 1);
+    counts
+}
+
+// Stable parallel radix sort. The most significant digit is scattered first,
+// after which every bucket is finished independently with an LSD sort.
+pub fn radix_sort(keys: &mut [(u64, u32)], bits: u32) {
+    if keys.len() <= 1 {
+        return;
+    }
+
+    let passes = bits.div_ceil(RADIX_BITS);
+    let top_shift = (passes - 1) * RADIX_BITS;
+    let counts = keys
+        .par_chunks(PARALLEL_THRESHOLD)
+        .map(|chunk| histogram(chunk, top_shift))
+        .reduce(
+            || [0; RADIX],
+            |mut lhs, rhs| {
+                lhs.iter_mut().zip(rhs).for_each(|(l, r)| *l += r);
+                lhs
+            },
+        );
+
+    let mut offsets = [0; RADIX];
+    let mut sum = 0;
+    for (offset, count) in offsets.iter_mut().zip(counts) {
+        *offset = sum;
+        sum += count;
+    }
+
+    let mut scattered = keys.to_vec();
+    let mut cursor = offsets;
+    for key in keys.iter() {
+        let d = digit(key.0, top_shift);
+        scattered[cursor[d]] = *key;
+        cursor[d] += 1;
+    }
+
+    let mut buckets = Vec::with_capacity(RADIX);
+    let mut remainder = scattered.as_mut_slice();
+    for count in counts {
+        let (bucket, rest) = remainder.split_at_mut(count);
+        buckets.push(bucket);
+        remainder = rest;
+    }
+
+    buckets
+        .into_par_iter()
+        .with_max_len(1)
+        .for_each(|bucket| lsd_sort(bucket, top_shift));
+
+    keys.copy_from_slice(&scattered);
+}
+
+fn lsd_sort(keys: &mut [(u64, u32)], end_shift: u32) {
+    if keys.len() <= 1 {
+        return;
+    }
+
+    let mut scratch = keys.to_vec();
+    let mut shift = 0;
+    while shift < end_shift {
+        let mut offsets = [0; RADIX];
+        let mut sum = 0;
+        for (offset, count) in offsets.iter_mut().zip(histogram(keys, shift)) {
+            *offset = sum;
+            sum += count;
+        }
+
+        for key in keys.iter() {
+            let d = digit(key.0, shift);
+            scratch[offsets[d]] = *key;
+            offsets[d] += 1;
+        }
+
+        keys.copy_from_slice(&scratch);
+        shift += RADIX_BITS;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::radix_sort;
+    use crate::testing::Rng;
+
+    #[test]
+    fn radix_sort_is_stable() {
+        // Enough keys for the buckets to be sorted in parallel, with plenty of
+        // duplicates to check their order is kept
+        let mut rng = Rng::new(8);
+        let mut keys: Vec<(u64, u32)> = (0..50_000)
+            .map(|i| ((rng.next() * 4096.0) as u64 * 0x1_0000_0001, i))
+            .collect();
+        let mut expected = keys.clone();
+        expected.sort_by_key(|&(key, _)| key);
+        radix_sort(&mut keys, 63);
+        assert_eq!(keys, expected);
+    }
+}
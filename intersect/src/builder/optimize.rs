@@ -0,0 +1,199 @@
+use std::time::Instant;
+
+use crate::{
+    bvh::{Node, OptimizationBudget},
+    types::AABB,
+};
+
+struct Rotation {
+    // Slots whose subtrees are exchanged
+    from: usize,
+    to: usize,
+    // The child of the rotated node whose bounds change
+    child: usize,
+    aabb: AABB,
+    delta: f32,
+}
+
+fn merged(lhs: &AABB, rhs: &AABB) -> AABB {
+    let mut aabb = *lhs;
+    aabb.grow(rhs);
+    aabb
+}
+
+// Finds the rotation below `idx` that shrinks one of its children the most.
+// Rotating never changes the bounds of `idx` itself, only those of the child that
+// receives its sibling, so the SAH change is just the area change of that child.
+// The sibling moves a level down, so rotations that would push its subtree past
+// `depth_limit` are skipped.
+fn best_rotation(
+    nodes: &[Node],
+    idx: usize,
+    depth: usize,
+    heights: &[usize],
+    depth_limit: usize,
+) -> Option<Rotation> {
+    let left = nodes[idx].first_primitive as usize;
+    let right = left + 1;
+    let mut best: Option<Rotation> = None;
+    for (child, sibling) in [(left, right), (right, left)] {
+        if nodes[child].primitive_count > 0 || depth + 2 + heights[sibling] > depth_limit {
+            continue;
+        }
+
+        let grand_child = nodes[child].first_primitive as usize;
+        for (swapped, kept) in [
+            (grand_child, grand_child + 1),
+            (grand_child + 1, grand_child),
+        ] {
+            let aabb = merged(&nodes[sibling].aabb, &nodes[kept].aabb);
+            let delta = aabb.area() - nodes[child].aabb.area();
+            if delta < 0.0 && best.as_ref().is_none_or(|best| delta < best.delta) {
+                best = Some(Rotation {
+                    from: sibling,
+                    to: swapped,
+                    child,
+                    aabb,
+                    delta,
+                });
+            }
+        }
+    }
+
+    best
+}
+
+// Height of the subtree below every node, where leaves have height 0
+fn heights(nodes: &[Node]) -> Vec<usize> {
+    let mut heights = vec![0; nodes.len()];
+    for idx in (0..nodes.len()).rev() {
+        heights[idx] = height(nodes, &heights, idx);
+    }
+    heights
+}
+
+fn height(nodes: &[Node], heights: &[usize], idx: usize) -> usize {
+    if nodes[idx].primitive_count > 0 || nodes.len() == 1 {
+        return 0;
+    }
+    let left = nodes[idx].first_primitive as usize;
+    1 + heights[left].max(heights[left + 1])
+}
+
+// Depth of every node, relying on parents being stored before their children
+fn depths(nodes: &[Node]) -> Vec<usize> {
+    let mut depths = vec![0; nodes.len()];
+    for idx in 0..nodes.len() {
+        if nodes[idx].primitive_count == 0 && nodes.len() > 1 {
+            let left = nodes[idx].first_primitive as usize;
+            depths[left] = depths[idx] + 1;
+            depths[left + 1] = depths[idx] + 1;
+        }
+    }
+    depths
+}
+
+// Applies tree rotations until none lower the SAH cost or the budget runs out.
+// Never makes the tree deeper than `depth_limit`. Returns the number of passes
+// over the tree.
+pub fn rotate(nodes: &mut Vec<Node>, budget: OptimizationBudget, depth_limit: usize) -> usize {
+    let start = Instant::now();
+    let mut passes = 0;
+    loop {
+        let out_of_budget = match budget {
+            OptimizationBudget::Passes(max_passes) => passes >= max_passes,
+            OptimizationBudget::Time(duration) => start.elapsed() >= duration,
+        };
+        if out_of_budget {
+            break;
+        }
+
+        passes += 1;
+        let mut rotations = 0;
+        // Nodes are laid out depth first, so walking backwards handles the deeper
+        // rotations before the ones that would move their subtrees around. Those
+        // only touch slots after `idx`, so its depth stays valid for the whole
+        // pass while the heights are kept up to date as subtrees move.
+        let depths = depths(nodes);
+        let mut heights = heights(nodes);
+        for idx in (0..nodes.len()).rev() {
+            if nodes[idx].primitive_count > 0 || nodes.len() == 1 {
+                continue;
+            }
+
+            heights[idx] = height(nodes, &heights, idx);
+            if let Some(rotation) = best_rotation(nodes, idx, depths[idx], &heights, depth_limit) {
+                nodes.swap(rotation.from, rotation.to);
+                heights.swap(rotation.from, rotation.to);
+                nodes[rotation.child].aabb = rotation.aabb;
+                heights[rotation.child] = height(nodes, &heights, rotation.child);
+                heights[idx] = height(nodes, &heights, idx);
+                rotations += 1;
+            }
+        }
+
+        *nodes = relayout(nodes);
+        if rotations == 0 {
+            break;
+        }
+    }
+
+    passes
+}
+
+// Rotations move subtrees between slots, which breaks the invariant that children
+// are stored after their parent. Rewrites the nodes depth first to restore it.
+pub fn relayout(nodes: &[Node]) -> Vec<Node> {
+    let mut ordered = Vec::with_capacity(nodes.len());
+    ordered.push(nodes[0]);
+    let mut stack = vec![(0, 0)];
+    while let Some((old_idx, new_idx)) = stack.pop() {
+        let node = nodes[old_idx];
+        if node.primitive_count > 0 || nodes.len() == 1 {
+            continue;
+        }
+
+        let left_child_index = ordered.len();
+        let old_left = node.first_primitive as usize;
+        ordered.push(nodes[old_left]);
+        ordered.push(nodes[old_left + 1]);
+        ordered[new_idx].first_primitive = left_child_index as u32;
+        stack.push((old_left + 1, left_child_index + 1));
+        stack.push((old_left, left_child_index));
+    }
+
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bvh::{BuildStrategy, Bvh, BvhBuildOptions, MortonCode, OptimizationBudget},
+        testing::{assert_matches_sah_build, slivers, soup},
+    };
+
+    #[test]
+    fn rotations_never_raise_the_sah_cost() {
+        let linear = BuildStrategy::Linear {
+            morton_code: MortonCode::Bits30,
+        };
+        for (vertices, indices) in [soup(3000, 14), slivers(1000)] {
+            for max_depth in [12, 64] {
+                let options = BvhBuildOptions::new()
+                    .with_strategy(linear)
+                    .with_max_depth(max_depth);
+                let mut bvh = Bvh::new_with_options(&vertices, &indices, &options);
+                let mut cost = bvh.sah_cost();
+                for _ in 0..4 {
+                    let report = bvh.optimize(OptimizationBudget::Passes(1));
+                    assert_eq!(report.sah_before, cost);
+                    assert!(report.sah_after <= report.sah_before);
+                    cost = report.sah_after;
+                }
+                assert!(cost < Bvh::new_with_options(&vertices, &indices, &options).sah_cost());
+                assert!(bvh.stats().max_depth <= max_depth);
+                assert_matches_sah_build(&bvh, &vertices, &indices);
+            }
+        }
+    }
+}
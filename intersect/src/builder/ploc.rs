@@ -0,0 +1,259 @@
+use rayon::prelude::*;
+
+use crate::{
+    bvh::{BvhBuildOptions, MortonCode},
+    types::AABB,
+};
+
+use super::{morton, BuildNode, BuildOutput, BuildPrimitives};
+
+enum Cluster {
+    Leaf(AABB, u32),
+    // Also stores the height of the subtree, leaves have height 0
+    Interior(AABB, usize, Box<(Cluster, Cluster)>),
+}
+
+impl Cluster {
+    fn aabb(&self) -> &AABB {
+        match self {
+            Cluster::Leaf(aabb, _) => aabb,
+            Cluster::Interior(aabb, _, _) => aabb,
+        }
+    }
+
+    fn height(&self) -> usize {
+        match self {
+            Cluster::Leaf(_, _) => 0,
+            Cluster::Interior(_, height, _) => *height,
+        }
+    }
+
+    // Primitives of the subtree from left to right, without recursing since
+    // clustering can produce very deep trees
+    fn into_leaves(self) -> Vec<(AABB, u32)> {
+        let mut leaves = Vec::new();
+        let mut stack = vec![self];
+        while let Some(cluster) = stack.pop() {
+            match cluster {
+                Cluster::Leaf(aabb, primitive) => leaves.push((aabb, primitive)),
+                Cluster::Interior(_, _, children) => {
+                    let (left, right) = *children;
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+        }
+        leaves
+    }
+}
+
+fn merged_area(lhs: &AABB, rhs: &AABB) -> f32 {
+    let mut aabb = *lhs;
+    aabb.grow(rhs);
+    aabb.area()
+}
+
+pub fn build(
+    primitives: &BuildPrimitives,
+    options: &BvhBuildOptions,
+    search_radius: usize,
+) -> BuildOutput {
+    let mut clusters: Vec<Option<Cluster>> =
+        morton::sorted_morton_codes(primitives, MortonCode::Bits63)
+            .into_iter()
+            .map(|(_, primitive)| {
+                Some(Cluster::Leaf(
+                    primitives.bounds[primitive as usize],
+                    primitive,
+                ))
+            })
+            .collect();
+
+    if clusters.is_empty() {
+        return BuildOutput::new(&BuildNode::leaf(AABB::default(), 0, 0), Vec::new());
+    }
+
+    let radius = search_radius.max(1);
+    while clusters.len() > 1 {
+        let boxes: Vec<AABB> = clusters
+            .iter()
+            .map(|cluster| *cluster.as_ref().unwrap().aabb())
+            .collect();
+
+        // Pairs are ordered by distance first and indices second, which makes the
+        // globally closest pair mutual so every iteration merges at least once.
+        let neighbours: Vec<usize> = (0..boxes.len())
+            .into_par_iter()
+            .map(|i| {
+                let first = i.saturating_sub(radius);
+                let last = (i + radius).min(boxes.len() - 1);
+                let mut best = (f32::MAX, usize::MAX, usize::MAX);
+                let mut neighbour = i;
+                for j in (first..=last).filter(|j| *j != i) {
+                    let candidate = (merged_area(&boxes[i], &boxes[j]), i.min(j), i.max(j));
+                    if candidate < best {
+                        best = candidate;
+                        neighbour = j;
+                    }
+                }
+                neighbour
+            })
+            .collect();
+
+        for i in 0..clusters.len() {
+            let j = neighbours[i];
+            if i < j && neighbours[j] == i {
+                let left = clusters[i].take().unwrap();
+                let right = clusters[j].take().unwrap();
+                let mut aabb = *left.aabb();
+                aabb.grow(right.aabb());
+                let height = 1 + left.height().max(right.height());
+                clusters[i] = Some(Cluster::Interior(aabb, height, Box::new((left, right))));
+            }
+        }
+
+        clusters.retain(Option::is_some);
+    }
+
+    let root = clusters.pop().flatten().unwrap();
+    let mut references = Vec::with_capacity(primitives.len());
+    let (root, _, _) = collapse(root, 0, options, &mut references);
+    BuildOutput::new(&root, references)
+}
+
+// Lays out the primitives depth first and turns small subtrees into leaves when
+// that is cheaper according to the SAH. Subtrees that would end up deeper than
+// the depth limit are rebuilt as balanced trees. Returns the node, its cost and
+// its primitive count.
+fn collapse(
+    cluster: Cluster,
+    depth: usize,
+    options: &BvhBuildOptions,
+    references: &mut Vec<u32>,
+) -> (BuildNode, f32, usize) {
+    if depth + cluster.height() > options.depth_limit() {
+        return balanced(&cluster.into_leaves(), depth, options, references);
+    }
+
+    match cluster {
+        Cluster::Leaf(aabb, primitive) => {
+            let first = references.len();
+            references.push(primitive);
+            (BuildNode::leaf(aabb, first, 1), aabb.area(), 1)
+        }
+        Cluster::Interior(aabb, _, children) => {
+            let first = references.len();
+            let (left, right) = *children;
+            let left = collapse(left, depth + 1, options, references);
+            let right = collapse(right, depth + 1, options, references);
+            merge(aabb, first, left, right, options)
+        }
+    }
+}
+
+// Splits the leaves in half until they run out or the depth limit is reached.
+// They are still in Morton order, so each half stays spatially coherent.
+fn balanced(
+    leaves: &[(AABB, u32)],
+    depth: usize,
+    options: &BvhBuildOptions,
+    references: &mut Vec<u32>,
+) -> (BuildNode, f32, usize) {
+    let first = references.len();
+    let mut aabb = AABB::default();
+    leaves.iter().for_each(|(bounds, _)| aabb.grow(bounds));
+    if leaves.len() == 1 || depth >= options.depth_limit() {
+        references.extend(leaves.iter().map(|(_, primitive)| *primitive));
+        let count = leaves.len();
+        return (
+            BuildNode::leaf(aabb, first, count),
+            count as f32 * aabb.area(),
+            count,
+        );
+    }
+
+    let (left, right) = leaves.split_at(leaves.len() / 2);
+    let left = balanced(left, depth + 1, options, references);
+    let right = balanced(right, depth + 1, options, references);
+    merge(aabb, first, left, right, options)
+}
+
+fn merge(
+    aabb: AABB,
+    first: usize,
+    (left, left_cost, left_count): (BuildNode, f32, usize),
+    (right, right_cost, right_count): (BuildNode, f32, usize),
+    options: &BvhBuildOptions,
+) -> (BuildNode, f32, usize) {
+    let count = left_count + right_count;
+    let cost = options.traversal_cost * aabb.area() + left_cost + right_cost;
+    let leaf_cost = count as f32 * aabb.area();
+    if count <= options.max_leaf_size && leaf_cost <= cost {
+        (BuildNode::leaf(aabb, first, count), leaf_cost, count)
+    } else {
+        (BuildNode::interior(aabb, left, right), cost, count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::SquareMatrix;
+
+    use crate::{
+        bvh::{BuildStrategy, Bvh, BvhBuildOptions, MAX_DEPTH},
+        testing::{assert_matches_sah_build, slivers, soup},
+        types::{HitRecord, Mat4, Ray, Vec3, AABB},
+    };
+
+    // Triangles spaced exponentially further apart, so every cluster merges with
+    // its closer neighbour and the clustering forms a single chain
+    fn chain(count: usize) -> (Vec<Vec3>, Vec<u32>) {
+        let mut vertices = Vec::new();
+        for k in 0..count {
+            let x = 3f32.powi(k as i32);
+            vertices.extend([
+                Vec3::new(x, 0.0, 0.0),
+                Vec3::new(x, 1.0, 0.0),
+                Vec3::new(x, 0.0, 1.0),
+            ]);
+        }
+        (vertices, (0..3 * count as u32).collect())
+    }
+
+    #[test]
+    fn ploc_respects_depth_limit() {
+        let (vertices, indices) = chain(80);
+        let strategy = BuildStrategy::Ploc { search_radius: 8 };
+        for max_depth in [8, 64] {
+            let options = BvhBuildOptions::new()
+                .with_strategy(strategy)
+                .with_max_depth(max_depth);
+            let bvh = Bvh::new_with_options(&vertices, &indices, &options);
+            assert!(bvh.stats().max_depth <= max_depth.min(MAX_DEPTH));
+
+            let mut hit = HitRecord::new();
+            let ray = Ray::new(Vec3::new(-1.0, 0.2, 0.2), Vec3::new(1.0, 0.0, 0.0));
+            bvh.traverse(&ray, &Mat4::identity(), &mut hit);
+            assert_eq!(hit.t, 2.0);
+
+            let everything = AABB {
+                min: Vec3::new(0.0, 0.0, 0.0),
+                max: Vec3::new(f32::MAX, 1.0, 1.0),
+            };
+            assert_eq!(bvh.overlapping(&everything).len(), 80);
+        }
+    }
+
+    #[test]
+    fn ploc_matches_the_sah_build() {
+        for (vertices, indices) in [soup(3000, 9), slivers(500)] {
+            for search_radius in [1, 16] {
+                let options =
+                    BvhBuildOptions::new().with_strategy(BuildStrategy::Ploc { search_radius });
+                let bvh = Bvh::new_with_options(&vertices, &indices, &options);
+                assert_eq!(bvh.primitives().len(), indices.len() / 3);
+                assert_matches_sah_build(&bvh, &vertices, &indices);
+            }
+        }
+    }
+}
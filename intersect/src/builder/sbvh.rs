@@ -0,0 +1,495 @@
+use crate::{
+    bvh::BvhBuildOptions,
+    primitive::PrimitiveSet,
+    types::{Vertex, AABB},
+};
+
+use super::{BuildNode, BuildOutput, BuildPrimitives, PARALLEL_THRESHOLD};
+// Spatial splits are only evaluated when the children of the best object split
+// overlap by more than this fraction of the root surface area.
+const OVERLAP_THRESHOLD: f32 = 1.0e-5;
+
+#[derive(Clone, Copy)]
+struct Reference {
+    primitive: u32,
+    aabb: AABB,
+}
+
+struct Context<'a, G> {
+    geometry: &'a G,
+    options: &'a BvhBuildOptions,
+    root_area: f32,
+}
+
+impl<G> Context<'_, G> {
+    fn bin_count(&self) -> usize {
+        self.options.bin_count.max(2)
+    }
+}
+
+enum SbvhNode {
+    Leaf(AABB, Vec<u32>),
+    Interior(AABB, Box<(SbvhNode, SbvhNode)>),
+}
+
+#[derive(Clone, Copy, Default)]
+struct ObjectBin {
+    aabb: AABB,
+    count: usize,
+}
+
+struct ObjectSplit {
+    axis: usize,
+    bin: usize,
+    cost: f32,
+    left_aabb: AABB,
+    right_aabb: AABB,
+}
+
+#[derive(Clone, Copy, Default)]
+struct SpatialBin {
+    aabb: AABB,
+    entries: usize,
+    exits: usize,
+}
+
+struct SpatialSplit {
+    axis: usize,
+    position: f32,
+    cost: f32,
+    left_aabb: AABB,
+    right_aabb: AABB,
+    left_count: usize,
+    right_count: usize,
+}
+
+enum Split {
+    Object(ObjectSplit),
+    Spatial(SpatialSplit),
+    Median,
+}
+
+pub fn build<G: PrimitiveSet>(
+    geometry: &G,
+    primitives: &BuildPrimitives,
+    options: &BvhBuildOptions,
+    max_duplication: f32,
+) -> BuildOutput {
+    let references: Vec<Reference> = primitives
+        .bounds
+        .iter()
+        .enumerate()
+        .map(|(primitive, aabb)| Reference {
+            primitive: primitive as u32,
+            aabb: *aabb,
+        })
+        .collect();
+
+    let mut root_aabb = AABB::default();
+    references.iter().for_each(|r| root_aabb.grow(&r.aabb));
+
+    let context = Context {
+        geometry,
+        options,
+        root_area: root_aabb.area(),
+    };
+
+    let budget = (references.len() as f32 * max_duplication.max(0.0)) as usize;
+    let root = subdivide(&context, references, budget, 0);
+
+    let mut leaf_primitives = Vec::new();
+    let root = assign_leaves(root, &mut leaf_primitives);
+    BuildOutput::new(&root, leaf_primitives)
+}
+
+// Leaves own their primitive lists while building because spatial splits change
+// the number of references per subtree. Lay them out depth first afterwards.
+fn assign_leaves(node: SbvhNode, leaf_primitives: &mut Vec<u32>) -> BuildNode {
+    match node {
+        SbvhNode::Leaf(aabb, primitives) => {
+            let first = leaf_primitives.len();
+            leaf_primitives.extend(&primitives);
+            BuildNode::leaf(aabb, first, primitives.len())
+        }
+        SbvhNode::Interior(aabb, children) => {
+            let (left, right) = *children;
+            let left = assign_leaves(left, leaf_primitives);
+            let right = assign_leaves(right, leaf_primitives);
+            BuildNode::interior(aabb, left, right)
+        }
+    }
+}
+
+fn leaf(aabb: AABB, references: &[Reference]) -> SbvhNode {
+    SbvhNode::Leaf(aabb, references.iter().map(|r| r.primitive).collect())
+}
+
+fn subdivide<G: PrimitiveSet>(
+    context: &Context<G>,
+    mut references: Vec<Reference>,
+    budget: usize,
+    depth: usize,
+) -> SbvhNode {
+    let mut aabb = AABB::default();
+    let mut centroid_bounds = AABB::default();
+    for reference in &references {
+        aabb.grow(&reference.aabb);
+        centroid_bounds.grow_with_position(&center(&reference.aabb));
+    }
+
+    let count = references.len();
+    if count <= 1 || depth >= context.options.depth_limit() {
+        return leaf(aabb, &references);
+    }
+
+    let object = find_object_split(context, &references, &aabb, &centroid_bounds);
+
+    // Only look for spatial splits where the object split leaves a lot of overlap
+    let try_spatial = budget > 0
+        && object.as_ref().is_none_or(|split| {
+            let overlap = intersection(&split.left_aabb, &split.right_aabb);
+            is_valid(&overlap) && overlap.area() / context.root_area > OVERLAP_THRESHOLD
+        });
+    let spatial = if try_spatial {
+        find_spatial_split(context, &references, &aabb)
+            .filter(|split| split.left_count + split.right_count - count <= budget)
+    } else {
+        None
+    };
+
+    let split = match (object, spatial) {
+        (Some(object), Some(spatial)) if spatial.cost < object.cost => Split::Spatial(spatial),
+        (Some(object), _) => Split::Object(object),
+        (None, Some(spatial)) => Split::Spatial(spatial),
+        (None, None) => Split::Median,
+    };
+
+    let split_cost = match &split {
+        Split::Object(split) => split.cost,
+        Split::Spatial(split) => split.cost,
+        Split::Median => f32::MAX,
+    };
+    if count <= context.options.max_leaf_size && split_cost >= count as f32 * aabb.area() {
+        return leaf(aabb, &references);
+    }
+
+    let (left, right) = match split {
+        Split::Object(split) => partition_object(context, references, &centroid_bounds, &split),
+        Split::Spatial(split) => partition_spatial(context, references, &split),
+        Split::Median => {
+            let right = references.split_off(count / 2);
+            (references, right)
+        }
+    };
+
+    let duplicates = left.len() + right.len() - count;
+    let remaining = budget.saturating_sub(duplicates);
+    let left_budget = remaining * left.len() / (left.len() + right.len());
+    let right_budget = remaining - left_budget;
+
+    let (left, right) = if count >= PARALLEL_THRESHOLD {
+        rayon::join(
+            || subdivide(context, left, left_budget, depth + 1),
+            || subdivide(context, right, right_budget, depth + 1),
+        )
+    } else {
+        (
+            subdivide(context, left, left_budget, depth + 1),
+            subdivide(context, right, right_budget, depth + 1),
+        )
+    };
+
+    SbvhNode::Interior(aabb, Box::new((left, right)))
+}
+
+fn center(aabb: &AABB) -> Vertex {
+    (aabb.min + aabb.max) * 0.5
+}
+
+fn is_valid(aabb: &AABB) -> bool {
+    aabb.min.x <= aabb.max.x && aabb.min.y <= aabb.max.y && aabb.min.z <= aabb.max.z
+}
+
+fn intersection(lhs: &AABB, rhs: &AABB) -> AABB {
+    AABB::new(
+        crate::types::max(&lhs.min, &rhs.min),
+        crate::types::min(&lhs.max, &rhs.max),
+    )
+}
+
+fn object_bin(centroid: f32, min: f32, scale: f32, bin_count: usize) -> usize {
+    (((centroid - min) * scale) as usize).min(bin_count - 1)
+}
+
+fn find_object_split<G: PrimitiveSet>(
+    context: &Context<G>,
+    references: &[Reference],
+    aabb: &AABB,
+    centroid_bounds: &AABB,
+) -> Option<ObjectSplit> {
+    let bin_count = context.bin_count();
+    let mut best: Option<ObjectSplit> = None;
+    let mut bins = vec![ObjectBin::default(); bin_count];
+    let mut left_boxes = vec![AABB::default(); bin_count - 1];
+    let mut left_counts = vec![0; bin_count - 1];
+
+    for axis in 0..3 {
+        let min = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - min;
+        if extent <= 0.0 {
+            continue;
+        }
+
+        bins.fill(ObjectBin::default());
+        let scale = bin_count as f32 / extent;
+        for reference in references {
+            let bin = &mut bins[object_bin(center(&reference.aabb)[axis], min, scale, bin_count)];
+            bin.count += 1;
+            bin.aabb.grow(&reference.aabb);
+        }
+
+        let mut left_box = AABB::default();
+        let mut left_count = 0;
+        for i in 0..bin_count - 1 {
+            left_box.grow(&bins[i].aabb);
+            left_count += bins[i].count;
+            left_boxes[i] = left_box;
+            left_counts[i] = left_count;
+        }
+
+        let mut right_box = AABB::default();
+        let mut right_count = 0;
+        for i in (1..bin_count).rev() {
+            right_box.grow(&bins[i].aabb);
+            right_count += bins[i].count;
+            let left_count = left_counts[i - 1];
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+
+            let cost = context.options.traversal_cost * aabb.area()
+                + left_count as f32 * left_boxes[i - 1].area()
+                + right_count as f32 * right_box.area();
+            if best.as_ref().is_none_or(|best| cost < best.cost) {
+                best = Some(ObjectSplit {
+                    axis,
+                    bin: i,
+                    cost,
+                    left_aabb: left_boxes[i - 1],
+                    right_aabb: right_box,
+                });
+            }
+        }
+    }
+
+    best
+}
+
+// Splits the part of a triangle inside `reference` by the plane at `position`
+// and returns the bounds of the clipped pieces on either side.
+fn split_reference<G: PrimitiveSet>(
+    context: &Context<G>,
+    reference: &Reference,
+    axis: usize,
+    position: f32,
+) -> (AABB, AABB) {
+    let (left, right) = context
+        .geometry
+        .split(reference.primitive as usize, axis, position);
+    (
+        intersection(&left, &reference.aabb),
+        intersection(&right, &reference.aabb),
+    )
+}
+
+fn find_spatial_split<G: PrimitiveSet>(
+    context: &Context<G>,
+    references: &[Reference],
+    aabb: &AABB,
+) -> Option<SpatialSplit> {
+    let bin_count = context.bin_count();
+    let mut best: Option<SpatialSplit> = None;
+    let mut bins = vec![SpatialBin::default(); bin_count];
+    let mut left_boxes = vec![AABB::default(); bin_count - 1];
+    let mut left_counts = vec![0; bin_count - 1];
+
+    for axis in 0..3 {
+        let min = aabb.min[axis];
+        let extent = aabb.max[axis] - min;
+        if extent <= 0.0 {
+            continue;
+        }
+
+        bins.fill(SpatialBin::default());
+        let width = extent / bin_count as f32;
+        let scale = 1.0 / width;
+        for reference in references {
+            let first = object_bin(reference.aabb.min[axis], min, scale, bin_count);
+            let last = object_bin(reference.aabb.max[axis], min, scale, bin_count).max(first);
+            bins[first].entries += 1;
+            bins[last].exits += 1;
+
+            // Chop the reference at every bin boundary it crosses
+            let mut remainder = *reference;
+            for (bin, slot) in bins.iter_mut().enumerate().take(last).skip(first) {
+                let (left, right) =
+                    split_reference(context, &remainder, axis, min + (bin + 1) as f32 * width);
+                if is_valid(&left) {
+                    slot.aabb.grow(&left);
+                }
+                if !is_valid(&right) {
+                    break;
+                }
+                remainder.aabb = right;
+            }
+            bins[last].aabb.grow(&remainder.aabb);
+        }
+
+        let mut left_box = AABB::default();
+        let mut left_count = 0;
+        for i in 0..bin_count - 1 {
+            left_box.grow(&bins[i].aabb);
+            left_count += bins[i].entries;
+            left_boxes[i] = left_box;
+            left_counts[i] = left_count;
+        }
+
+        let mut right_box = AABB::default();
+        let mut right_count = 0;
+        for i in (1..bin_count).rev() {
+            right_box.grow(&bins[i].aabb);
+            right_count += bins[i].exits;
+            let left_count = left_counts[i - 1];
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+
+            let cost = context.options.traversal_cost * aabb.area()
+                + left_count as f32 * left_boxes[i - 1].area()
+                + right_count as f32 * right_box.area();
+            if best.as_ref().is_none_or(|best| cost < best.cost) {
+                best = Some(SpatialSplit {
+                    axis,
+                    position: min + i as f32 * width,
+                    cost,
+                    left_aabb: left_boxes[i - 1],
+                    right_aabb: right_box,
+                    left_count,
+                    right_count,
+                });
+            }
+        }
+    }
+
+    best
+}
+
+fn partition_object<G: PrimitiveSet>(
+    context: &Context<G>,
+    references: Vec<Reference>,
+    centroid_bounds: &AABB,
+    split: &ObjectSplit,
+) -> (Vec<Reference>, Vec<Reference>) {
+    let bin_count = context.bin_count();
+    let min = centroid_bounds.min[split.axis];
+    let scale = bin_count as f32 / (centroid_bounds.max[split.axis] - min);
+    references.into_iter().partition(|reference| {
+        object_bin(center(&reference.aabb)[split.axis], min, scale, bin_count) < split.bin
+    })
+}
+
+fn partition_spatial<G: PrimitiveSet>(
+    context: &Context<G>,
+    references: Vec<Reference>,
+    split: &SpatialSplit,
+) -> (Vec<Reference>, Vec<Reference>) {
+    let axis = split.axis;
+    let position = split.position;
+    let mut left = Vec::with_capacity(split.left_count);
+    let mut right = Vec::with_capacity(split.right_count);
+    let mut straddling = Vec::new();
+    for reference in references {
+        if reference.aabb.max[axis] <= position {
+            left.push(reference);
+        } else if reference.aabb.min[axis] >= position {
+            right.push(reference);
+        } else {
+            straddling.push(reference);
+        }
+    }
+
+    let mut left_aabb = split.left_aabb;
+    let mut right_aabb = split.right_aabb;
+    let mut left_count = split.left_count as f32;
+    let mut right_count = split.right_count as f32;
+    for reference in straddling {
+        let (left_part, right_part) = split_reference(context, &reference, axis, position);
+        if !is_valid(&left_part) {
+            right.push(reference);
+            continue;
+        }
+        if !is_valid(&right_part) {
+            left.push(reference);
+            continue;
+        }
+
+        // Compare duplicating the reference against moving it to one side entirely
+        let mut grown_left = left_aabb;
+        grown_left.grow(&reference.aabb);
+        let mut grown_right = right_aabb;
+        grown_right.grow(&reference.aabb);
+        let split_cost = left_aabb.area() * left_count + right_aabb.area() * right_count;
+        let left_cost = grown_left.area() * left_count + right_aabb.area() * (right_count - 1.0);
+        let right_cost = left_aabb.area() * (left_count - 1.0) + grown_right.area() * right_count;
+
+        if left_cost < split_cost && left_cost <= right_cost {
+            left.push(reference);
+            left_aabb = grown_left;
+            right_count -= 1.0;
+        } else if right_cost < split_cost {
+            right.push(reference);
+            right_aabb = grown_right;
+            left_count -= 1.0;
+        } else {
+            left.push(Reference {
+                primitive: reference.primitive,
+                aabb: left_part,
+            });
+            right.push(Reference {
+                primitive: reference.primitive,
+                aabb: right_part,
+            });
+        }
+    }
+
+    if left.is_empty() || right.is_empty() {
+        // Unsplitting moved everything to one side, fall back to a median split
+        left.append(&mut right);
+        let mid = left.len() / 2;
+        right = left.split_off(mid);
+    }
+
+    (left, right)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bvh::{BuildStrategy, Bvh, BvhBuildOptions},
+        testing::{assert_matches_sah_build, slivers},
+    };
+
+    #[test]
+    fn spatial_splits_match_the_sah_build() {
+        let (vertices, indices) = slivers(1500);
+        for max_duplication in [0.0, 0.3] {
+            let options =
+                BvhBuildOptions::new().with_strategy(BuildStrategy::SpatialSah { max_duplication });
+            let bvh = Bvh::new_with_options(&vertices, &indices, &options);
+            let references = bvh.primitives().len() as f32;
+            assert!(references <= 1500.0 * (1.0 + max_duplication));
+            assert_eq!(references > 1500.0, max_duplication > 0.0);
+            assert_matches_sah_build(&bvh, &vertices, &indices);
+        }
+    }
+}
@@ -1,9 +1,130 @@
-use cgmath::SquareMatrix;
+use std::{borrow::Cow, collections::HashSet, ops::ControlFlow, path::Path, time::Duration};
+
+use cgmath::{InnerSpace, SquareMatrix};
+use rayon::prelude::*;
 
 use crate::{
-    intersect::{intersect_aabb, intersect_triangle},
-    types::{HitRecord, Mat4, Ray, Vec3, Vertex, AABB},
+    builder::{binned_sah, lbvh, optimize, ploc, sbvh, BuildPrimitives},
+    compressed_bvh::CompressedBvh,
+    filter::{CandidateHit, HitFilter},
+    intersect::{
+        closest_point_on_triangle, intersect_aabb, intersect_triangles, CullMode,
+        TriangleIntersection,
+    },
+    multi_hit::{HitCollector, HitList},
+    packet::{intersect_aabb_packet, lanes, PacketHits, RayPacket},
+    primitive::{PrimitiveHit, PrimitiveSet, TriangleMesh},
+    query::Region,
+    serialize::{as_bytes, read_bvh, write_bvh, Reader, SerializationError, Storage, BVH_MAGIC},
+    stats::{clipped_triangle_area, end_point_overlap, BvhStats},
+    types::{ClosestPoint, HitRecord, Mat4, Ray, Vec3, Vertex, AABB},
+    wide_bvh::{BvhWidth, SimdLevel, WideBvh},
 };
+
+// Deepest level the fixed size traversal stacks can handle, with the root at
+// depth 0. Interior nodes push both children before popping one, so a tree this
+// deep fills all 64 entries.
+pub(crate) const MAX_DEPTH: usize = 63;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BuildStrategy {
+    // Object splits only, every triangle is referenced by exactly one leaf
+    BinnedSah,
+    // Also considers spatial splits, which clip triangles into both children.
+    // `max_duplication` limits the extra references as a fraction of the
+    // triangle count, so 0.3 allows up to 30% more references.
+    SpatialSah { max_duplication: f32 },
+    // Sorts triangle centroids along a Morton curve. Builds in a fraction of the
+    // time of the SAH builders at the cost of tree quality.
+    Linear { morton_code: MortonCode },
+    // Bottom up clustering of Morton sorted triangles. Every iteration merges
+    // mutual nearest neighbours found within `search_radius` clusters.
+    Ploc { search_radius: usize },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MortonCode {
+    Bits30,
+    Bits63,
+}
+
+#[derive(Clone, Copy)]
+pub struct BvhBuildOptions {
+    pub strategy: BuildStrategy,
+    // Number of centroid bins evaluated per axis when searching for a split
+    pub bin_count: usize,
+    // Nodes with more primitives than this are always split
+    pub max_leaf_size: usize,
+    // Cost of visiting a node relative to a single triangle intersection
+    pub traversal_cost: f32,
+    // Leaves are forced at this depth. Traversal uses a fixed size stack, so
+    // builders never go deeper than `MAX_DEPTH` whatever this is set to.
+    pub max_depth: usize,
+}
+
+impl BvhBuildOptions {
+    pub fn new() -> Self {
+        Self {
+            strategy: BuildStrategy::BinnedSah,
+            bin_count: 16,
+            max_leaf_size: 4,
+            traversal_cost: 1.0,
+            max_depth: MAX_DEPTH,
+        }
+    }
+
+    pub fn with_strategy(mut self, strategy: BuildStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn with_bin_count(mut self, bin_count: usize) -> Self {
+        self.bin_count = bin_count;
+        self
+    }
+
+    pub fn with_max_leaf_size(mut self, max_leaf_size: usize) -> Self {
+        self.max_leaf_size = max_leaf_size;
+        self
+    }
+
+    pub fn with_traversal_cost(mut self, traversal_cost: f32) -> Self {
+        self.traversal_cost = traversal_cost;
+        self
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub(crate) fn depth_limit(&self) -> usize {
+        self.max_depth.min(MAX_DEPTH)
+    }
+}
+
+impl Default for BvhBuildOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum OptimizationBudget {
+    // Maximum number of rotation passes over the whole tree
+    Passes(usize),
+    // Stops after the first pass that ends past this duration
+    Time(Duration),
+}
+
+#[derive(Clone, Copy)]
+pub struct OptimizationReport {
+    pub sah_before: f32,
+    pub sah_after: f32,
+    pub passes: usize,
+}
+
+#[derive(Clone, Copy)]
 #[repr(C)]
 pub struct Node {
     pub aabb: AABB,
@@ -23,304 +144,1119 @@ impl Default for Node {
         }
     }
 }
-pub struct Bvh {
-    vertices: Vec<Vertex>,
-    triangles: Vec<u32>,
-    indices: Vec<u32>,
-    nodes: Vec<Node>,
+// Node layout used by `Bvh::traverse`. The wide layout keeps the binary nodes
+// next to it. The compressed layout replaces them to save memory, they're
+// decoded again whenever refitting, optimizing or serializing needs them.
+enum CpuLayout {
+    Binary,
+    Wide(WideBvh),
+    Compressed(CompressedBvh),
+}
+
+// The binary tree as seen by the walkers that work in any layout. Compressed
+// nodes only know their bounds once they're decoded from the parent, so the
+// walkers keep the bounds of every node on their stack.
+#[derive(Clone, Copy)]
+enum Nodes<'a> {
+    Binary(&'a [Node]),
+    Compressed(&'a CompressedBvh),
+}
+
+impl Nodes<'_> {
+    fn root(&self) -> AABB {
+        match self {
+            Nodes::Binary(nodes) => nodes[0].aabb,
+            Nodes::Compressed(compressed) => *compressed.aabb(),
+        }
+    }
+
+    // Range of `primitives` a leaf references, None for interior nodes
+    fn leaf(&self, idx: usize) -> Option<(usize, usize)> {
+        let (first, count, node_count) = match self {
+            Nodes::Binary(nodes) => (
+                nodes[idx].first_primitive,
+                nodes[idx].primitive_count,
+                nodes.len(),
+            ),
+            Nodes::Compressed(compressed) => {
+                let node = &compressed.nodes()[idx];
+                (
+                    node.first_primitive,
+                    node.primitive_count,
+                    compressed.nodes().len(),
+                )
+            }
+        };
+        (count > 0 || node_count == 1).then_some((first as usize, count as usize))
+    }
+
+    // Indices and bounds of the children of interior node `idx`, whose own
+    // bounds are `aabb`
+    fn children(&self, idx: usize, aabb: &AABB) -> [(usize, AABB); 2] {
+        match self {
+            Nodes::Binary(nodes) => {
+                let left = nodes[idx].first_primitive as usize;
+                [(left, nodes[left].aabb), (left + 1, nodes[left + 1].aabb)]
+            }
+            Nodes::Compressed(compressed) => compressed.children(idx, aabb),
+        }
+    }
+}
+
+// Per instance state of a traversal: the cull mode, which may override the
+// geometry's, and the filter candidate hits have to pass
+#[derive(Clone, Copy)]
+pub(crate) struct InstanceTraversal<'a> {
+    pub cull_mode: CullMode,
+    pub instance_id: u32,
+    pub filter: Option<&'a dyn HitFilter>,
+}
+
+impl InstanceTraversal<'_> {
+    fn candidate(&self, primitive: u32, u: f32, v: f32) -> CandidateHit {
+        CandidateHit {
+            instance_id: self.instance_id,
+            primitive_id: primitive,
+            u,
+            v,
+        }
+    }
+
+    fn accepts(&self, primitive: u32, hit: &PrimitiveHit) -> bool {
+        self.filter
+            .is_none_or(|filter| filter.accept(&self.candidate(primitive, hit.u, hit.v)))
+    }
+}
+
+// A tree over any `PrimitiveSet`. Triangle meshes are the default and have a few
+// extras on top, like refitting to new vertices and serialization.
+pub struct Bvh<G: PrimitiveSet = TriangleMesh> {
+    geometry: G,
+    // Primitive indices referenced by the leaves, in leaf order
+    primitives: Storage<u32>,
+    nodes: Storage<Node>,
+    options: BvhBuildOptions,
+    build_cost: f32,
+    layout: CpuLayout,
 }
 
 impl Bvh {
     pub fn new(vertices: &[Vertex], indices: &[u32]) -> Self {
-        // Initialize all nodes to default
-        let mut centroids = Vec::new();
-        let mut nodes = Vec::new();
-        let mut triangle_indices = Vec::new();
-
-        for i in (0..indices.len()).step_by(3) {
-            triangle_indices.push(i as u32);
-            nodes.extend([Node::default(), Node::default()].into_iter());
-            let c = vertices[indices[i] as usize]
-                + vertices[indices[i + 1] as usize]
-                + vertices[indices[i + 2] as usize];
-            centroids.push(Vec3::new(c.x, c.y, c.z) / 3.0)
-        }
-
-        // Root node contains all primitives
-        nodes[0].primitive_count = triangle_indices.len() as u32;
-
-        let mut used_nodes = 1;
-        Self::update_bounds(vertices, &triangle_indices, indices, &mut nodes, 0);
-        Self::subdivide(
-            &mut nodes,
-            0,
-            vertices,
-            &mut triangle_indices,
-            indices,
-            &centroids,
-            &mut used_nodes,
-        );
-        Self {
-            nodes,
-            vertices: vertices.to_vec(),
-            indices: indices.to_vec(),
-            triangles: triangle_indices,
-        }
+        Self::new_with_options(vertices, indices, &BvhBuildOptions::default())
     }
 
-    pub fn aabb(&self) -> &AABB {
-        &self.nodes[0].aabb
+    pub fn new_linear(vertices: &[Vertex], indices: &[u32]) -> Self {
+        let options = BvhBuildOptions::new().with_strategy(BuildStrategy::Linear {
+            morton_code: MortonCode::Bits30,
+        });
+        Self::new_with_options(vertices, indices, &options)
     }
 
-    pub fn nodes(&self) -> &[Node] {
-        &self.nodes
+    pub fn new_with_options(
+        vertices: &[Vertex],
+        indices: &[u32],
+        options: &BvhBuildOptions,
+    ) -> Self {
+        Self::from_primitives(TriangleMesh::new(vertices, indices), options)
     }
 
-    pub fn triangles(&self) -> &[u32] {
-        &self.triangles
+    // Writes the tree and its geometry in a versioned binary format, see
+    // `serialize.rs`. Only the binary nodes are stored.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SerializationError> {
+        write_bvh(self).into_file(BVH_MAGIC, path.as_ref())
     }
 
-    pub fn indices(&self) -> &[u32] {
-        &self.indices
+    // Memory maps a file written by `save`. The arrays are used in place until
+    // they're modified by refitting or optimizing.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SerializationError> {
+        let map = Reader::open(path.as_ref())?;
+        read_bvh(&mut Reader::new(map, 0, BVH_MAGIC, true)?)
     }
 
-    fn update_bounds(
+    // Same as `load`, but fails with `SerializationError::Stale` when the file was
+    // built from different geometry.
+    pub fn load_matching(
+        path: impl AsRef<Path>,
         vertices: &[Vertex],
-        triangle_indices: &[u32],
-        vertex_indices: &[u32],
-        nodes: &mut [Node],
-        idx: usize,
-    ) {
-        let node = &mut nodes[idx];
-        let first = node.first_primitive as usize;
-        let last = first + node.primitive_count as usize;
-        (first..last).for_each(|i| {
-            let t = triangle_indices[i] as usize;
-            let v0 = vertex_indices[t] as usize;
-            let v1 = vertex_indices[t + 1] as usize;
-            let v2 = vertex_indices[t + 2] as usize;
-            node.aabb.min = crate::types::min(&node.aabb.min, &vertices[v0]);
-            node.aabb.min = crate::types::min(&node.aabb.min, &vertices[v1]);
-            node.aabb.min = crate::types::min(&node.aabb.min, &vertices[v2]);
-
-            node.aabb.max = crate::types::max(&node.aabb.max, &vertices[v0]);
-            node.aabb.max = crate::types::max(&node.aabb.max, &vertices[v1]);
-            node.aabb.max = crate::types::max(&node.aabb.max, &vertices[v2]);
+        indices: &[u32],
+    ) -> Result<Self, SerializationError> {
+        let bvh = Self::load(path)?;
+        if as_bytes(bvh.vertices()) != as_bytes(vertices)
+            || as_bytes(bvh.indices()) != as_bytes(indices)
+        {
+            return Err(SerializationError::Stale);
+        }
+        Ok(bvh)
+    }
+
+    // Updates the bounds for moved vertices while keeping the tree topology.
+    // Returns the SAH cost relative to the cost right after building, once this
+    // grows too large a rebuild will trace faster.
+    pub fn refit(&mut self, vertices: &[Vertex]) -> f32 {
+        assert_eq!(
+            vertices.len(),
+            self.geometry.vertices().len(),
+            "Refitting requires the same vertex count"
+        );
+        self.geometry.vertices_mut().copy_from_slice(vertices);
+        self.refit_bounds()
+    }
+
+    // Selects the ray-triangle test used by every query on this Bvh, see
+    // `TriangleIntersection`
+    pub fn set_triangle_intersection(&mut self, intersection: TriangleIntersection) {
+        self.geometry.set_intersection(intersection);
+    }
+
+    pub fn triangle_intersection(&self) -> TriangleIntersection {
+        self.geometry.intersection()
+    }
+
+    // Sides of the triangles rays can't hit, unless an instance overrides it
+    pub fn set_cull_mode(&mut self, cull_mode: CullMode) {
+        self.geometry.set_cull_mode(cull_mode);
+    }
+
+    pub fn cull_mode(&self) -> CullMode {
+        self.geometry.cull_mode()
+    }
+
+    // Nearest point on the mesh closer than `max_distance` to `point`. Visits
+    // the closer child first and skips every node farther away than the best
+    // point found so far.
+    pub fn closest_point(&self, point: Vec3, max_distance: f32) -> Option<ClosestPoint> {
+        self.closest_point_transformed(point, &Mat4::identity(), max_distance)
+    }
+
+    // `closest_point` for the mesh placed in the world by `transform`. Boxes and
+    // triangles are moved into world space, so distances stay correct under
+    // rotation and non-uniform scale.
+    pub fn closest_point_transformed(
+        &self,
+        point: Vec3,
+        transform: &Mat4,
+        max_distance: f32,
+    ) -> Option<ClosestPoint> {
+        let transform = (*transform != Mat4::identity()).then_some(transform);
+        let distance_squared = |aabb: &AABB| {
+            transform
+                .map_or(*aabb, |transform| aabb.transformed(transform))
+                .distance_squared(&point)
+        };
+
+        let nodes = self.node_view();
+        let root = nodes.root();
+        let mut closest = None;
+        let mut best = max_distance * max_distance;
+        let mut stack_ptr = 1;
+        let mut stack = [(0, root, distance_squared(&root)); 64];
+        while stack_ptr > 0 {
+            stack_ptr -= 1;
+            let (node_idx, aabb, node_distance) = stack[stack_ptr];
+            if node_distance >= best {
+                continue;
+            }
+
+            if let Some((first, count)) = nodes.leaf(node_idx) {
+                for primitive in &self.primitives[first..first + count] {
+                    let [v0, v1, v2] = self.geometry.triangle(*primitive as usize).map(|v| {
+                        transform.map_or(v, |transform| (transform * v.extend(1.0)).truncate())
+                    });
+                    let (p, u, v) = closest_point_on_triangle(&point, &v0, &v1, &v2);
+                    let distance = (p - point).magnitude2();
+                    if distance < best {
+                        best = distance;
+                        closest = Some(ClosestPoint {
+                            point: p,
+                            distance: distance.sqrt(),
+                            u,
+                            v,
+                            object_id: 0,
+                            primitive_id: *primitive,
+                        });
+                    }
+                }
+            } else {
+                let [left, right] = nodes
+                    .children(node_idx, &aabb)
+                    .map(|(idx, aabb)| (idx, aabb, distance_squared(&aabb)));
+                let (near, far) = if left.2 <= right.2 {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                if far.2 < best {
+                    stack[stack_ptr] = far;
+                    stack_ptr += 1;
+                }
+                if near.2 < best {
+                    stack[stack_ptr] = near;
+                    stack_ptr += 1;
+                }
+            }
+        }
+        closest
+    }
+
+    // `query` for the mesh placed in the world by `transform`, with `region` in
+    // world space
+    pub fn query_transformed<R, F>(&self, region: &R, transform: &Mat4, visitor: F)
+    where
+        R: Region,
+        F: FnMut(u32) -> ControlFlow<()>,
+    {
+        let _ = self.visit_transformed(region, transform, visitor);
+    }
+
+    pub(crate) fn visit_transformed<R, F>(
+        &self,
+        region: &R,
+        transform: &Mat4,
+        visitor: F,
+    ) -> ControlFlow<()>
+    where
+        R: Region,
+        F: FnMut(u32) -> ControlFlow<()>,
+    {
+        let mut visitor = self.deduplicated(visitor);
+        self.visit_overlapping(
+            |aabb| region.overlaps_aabb(&aabb.transformed(transform)),
+            |primitive| {
+                let triangle = self
+                    .geometry
+                    .triangle(primitive as usize)
+                    .map(|v| (transform * v.extend(1.0)).truncate());
+                if region.overlaps_triangle(&triangle) {
+                    visitor(primitive)
+                } else {
+                    ControlFlow::Continue(())
+                }
+            },
+        )
+    }
+
+    // Calls `visitor` once with every pair of intersecting triangles of this
+    // mesh placed by `transform` and `other` placed by `other_transform`. Both
+    // trees are walked together, splitting the larger node of each overlapping
+    // pair, in the space of this mesh. A singular `transform` finds nothing.
+    // Returning `ControlFlow::Break` ends the search.
+    pub fn query_intersecting<F>(
+        &self,
+        transform: &Mat4,
+        other: &Bvh,
+        other_transform: &Mat4,
+        visitor: F,
+    ) where
+        F: FnMut(u32, u32) -> ControlFlow<()>,
+    {
+        let _ = self.visit_intersecting(transform, other, other_transform, visitor);
+    }
+
+    // Every pair of intersecting triangles, see `query_intersecting`
+    pub fn intersecting_pairs(
+        &self,
+        transform: &Mat4,
+        other: &Bvh,
+        other_transform: &Mat4,
+    ) -> Vec<(u32, u32)> {
+        let mut pairs = Vec::new();
+        self.query_intersecting(transform, other, other_transform, |a, b| {
+            pairs.push((a, b));
+            ControlFlow::Continue(())
         });
+        pairs
     }
 
-    fn find_split_axis(
-        node: &Node,
-        vertices: &[Vertex],
-        triangle_indices: &[u32],
-        vertex_indices: &[u32],
-        centroids: &[Vec3],
-    ) -> (usize, f32, f32) {
-        let mut best_axis = 0;
-        let mut best_position = 0.0;
-        let mut best_cost = f32::MAX;
-
-        let extent = node.aabb.extent();
-        for axis in 0..3 {
-            let min = node.aabb.min[axis];
-            let max = node.aabb.max[axis];
-            if min == max {
+    // True when the meshes touch anywhere. Stops at the first contact.
+    pub fn intersects(&self, transform: &Mat4, other: &Bvh, other_transform: &Mat4) -> bool {
+        self.visit_intersecting(transform, other, other_transform, |_, _| {
+            ControlFlow::Break(())
+        })
+        .is_break()
+    }
+
+    pub(crate) fn visit_intersecting<F>(
+        &self,
+        transform: &Mat4,
+        other: &Bvh,
+        other_transform: &Mat4,
+        mut visitor: F,
+    ) -> ControlFlow<()>
+    where
+        F: FnMut(u32, u32) -> ControlFlow<()>,
+    {
+        // Maps the other mesh into the space of this one. A singular transform
+        // flattens this mesh, which leaves no volume to intersect.
+        let Some(inverse) = transform.invert() else {
+            return ControlFlow::Continue(());
+        };
+        let relative = inverse * other_transform;
+        // Pairs of primitives referenced from several leaves are found once per
+        // pair of leaves, so trees with spatial splits remember what was seen
+        let mut seen = HashSet::new();
+        let deduplicate = self.primitives.len() > self.geometry.len()
+            || other.primitives.len() > other.geometry.len();
+        let (nodes_a, nodes_b) = (self.node_view(), other.node_view());
+
+        // Bounds of `b` stay in the space of the other mesh on the stack
+        let mut stack_ptr = 1;
+        let mut stack = [((0, nodes_a.root()), (0, nodes_b.root())); 128];
+        while stack_ptr > 0 {
+            stack_ptr -= 1;
+            let ((a, aabb_a), (b, local_aabb_b)) = stack[stack_ptr];
+            let aabb_b = local_aabb_b.transformed(&relative);
+            if !aabb_a.overlaps_aabb(&aabb_b) {
                 continue;
             }
-            let bins = 8;
-            let scale = extent[axis] / bins as f32;
-            for i in 0..bins {
-                let candidate = min + i as f32 * scale;
-                let cost = Self::evaluate_sah(
-                    node,
-                    vertices,
-                    triangle_indices,
-                    vertex_indices,
-                    centroids,
-                    axis,
-                    candidate,
-                );
-                if cost < best_cost {
-                    best_position = candidate;
-                    best_axis = axis;
-                    best_cost = cost;
+
+            match (nodes_a.leaf(a), nodes_b.leaf(b)) {
+                (Some((first_a, count_a)), Some((first_b, count_b))) => {
+                    for primitive_b in &other.primitives[first_b..first_b + count_b] {
+                        let triangle_b = other
+                            .geometry
+                            .triangle(*primitive_b as usize)
+                            .map(|v| (relative * v.extend(1.0)).truncate());
+                        if !aabb_a.overlaps_triangle(&triangle_b) {
+                            continue;
+                        }
+                        for primitive_a in &self.primitives[first_a..first_a + count_a] {
+                            let triangle_a = self.geometry.triangle(*primitive_a as usize);
+                            if intersect_triangles(&triangle_a, &triangle_b)
+                                && (!deduplicate || seen.insert((*primitive_a, *primitive_b)))
+                            {
+                                visitor(*primitive_a, *primitive_b)?;
+                            }
+                        }
+                    }
+                }
+                // Splits the node of `self` when it is the only interior one or
+                // the larger one
+                (None, leaf_b) if leaf_b.is_some() || aabb_a.area() >= aabb_b.area() => {
+                    let [left, right] = nodes_a.children(a, &aabb_a);
+                    stack[stack_ptr] = (right, (b, local_aabb_b));
+                    stack[stack_ptr + 1] = (left, (b, local_aabb_b));
+                    stack_ptr += 2;
+                }
+                _ => {
+                    let [left, right] = nodes_b.children(b, &local_aabb_b);
+                    stack[stack_ptr] = ((a, aabb_a), right);
+                    stack[stack_ptr + 1] = ((a, aabb_a), left);
+                    stack_ptr += 2;
                 }
             }
         }
+        ControlFlow::Continue(())
+    }
 
-        (best_axis, best_position, best_cost)
+    // Also computes the end-point overlap, which is a lot slower than the rest
+    pub fn stats_with_epo(&self) -> BvhStats {
+        let epo = end_point_overlap(
+            &self.nodes(),
+            &self.primitives,
+            self.geometry.len(),
+            self.options.traversal_cost,
+            |primitive, aabb| clipped_triangle_area(&self.geometry.triangle(primitive), aabb),
+        );
+        BvhStats {
+            epo: Some(epo),
+            ..self.stats()
+        }
     }
 
-    fn subdivide(
-        nodes: &mut [Node],
-        idx: usize,
-        vertices: &[Vertex],
-        triangle_indices: &mut [u32],
-        vertex_indices: &[u32],
-        centroids: &[Vec3],
-        used_nodes: &mut usize,
-    ) {
-        let node = &nodes[idx];
-        let (axis, split, cost) =
-            Self::find_split_axis(node, vertices, triangle_indices, vertex_indices, centroids);
-        let parent_area = node.aabb.area();
-        let parent_cost = node.primitive_count as f32 * parent_area;
-        // Only split if costs are lower or equal to parent
-        if cost >= parent_cost {
-            return;
+    // True while the nodes or vertices still point into a memory mapped file,
+    // refitting copies both
+    pub fn is_mapped(&self) -> bool {
+        self.nodes.is_mapped() || self.geometry.vertices_mapped()
+    }
+
+    pub fn vertices(&self) -> &[Vertex] {
+        self.geometry.vertices()
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        self.geometry.indices()
+    }
+}
+
+impl<G: PrimitiveSet> Bvh<G> {
+    pub fn from_primitives(geometry: G, options: &BvhBuildOptions) -> Self {
+        let primitives = BuildPrimitives::new(&geometry);
+        let output = match options.strategy {
+            BuildStrategy::BinnedSah => binned_sah::build(&primitives, options),
+            BuildStrategy::SpatialSah { max_duplication } => {
+                sbvh::build(&geometry, &primitives, options, max_duplication)
+            }
+            BuildStrategy::Linear { morton_code } => lbvh::build(&primitives, options, morton_code),
+            BuildStrategy::Ploc { search_radius } => {
+                ploc::build(&primitives, options, search_radius)
+            }
+        };
+
+        let mut this = Self::from_parts(
+            geometry,
+            output.primitives.into(),
+            output.nodes.into(),
+            *options,
+            0.0,
+        );
+        this.build_cost = this.sah_cost();
+        this
+    }
+
+    pub(crate) fn from_parts(
+        geometry: G,
+        primitives: Storage<u32>,
+        nodes: Storage<Node>,
+        options: BvhBuildOptions,
+        build_cost: f32,
+    ) -> Self {
+        Self {
+            geometry,
+            primitives,
+            nodes,
+            options,
+            build_cost,
+            layout: CpuLayout::Binary,
         }
+    }
 
-        let mut i = node.first_primitive as i64;
-        let mut j = i + node.primitive_count as i64 - 1;
-        while i <= j {
-            if centroids[triangle_indices[i as usize] as usize / 3][axis] < split {
-                i += 1;
+    // Replaces the primitives with moved versions of themselves and updates the
+    // bounds to match, see `refit`.
+    pub fn refit_primitives(&mut self, geometry: G) -> f32 {
+        assert_eq!(
+            geometry.len(),
+            self.geometry.len(),
+            "Refitting requires the same primitive count"
+        );
+        self.geometry = geometry;
+        self.refit_bounds()
+    }
+
+    pub(crate) fn refit_bounds(&mut self) -> f32 {
+        // Children are always stored after their parent, so walking backwards
+        // updates them before the parent reads their bounds.
+        self.restore_nodes();
+        let nodes = self.nodes.to_mut();
+        for idx in (0..nodes.len()).rev() {
+            let node = nodes[idx];
+            let mut aabb = AABB::default();
+            if node.primitive_count > 0 {
+                let first = node.first_primitive as usize;
+                let last = first + node.primitive_count as usize;
+                for primitive in &self.primitives[first..last] {
+                    aabb.grow(&self.geometry.aabb(*primitive as usize));
+                }
             } else {
-                triangle_indices.swap(i as usize, j as usize);
-                j -= 1;
+                let left_child_index = node.first_primitive as usize;
+                aabb.grow(&nodes[left_child_index].aabb);
+                aabb.grow(&nodes[left_child_index + 1].aabb);
             }
+            nodes[idx].aabb = aabb;
+        }
+
+        self.update_layout();
+        self.sah_degradation()
+    }
+
+    // Lowers the SAH cost with tree rotations, mostly useful after a linear build
+    // or refitting. Stops early when a pass finds nothing to improve.
+    pub fn optimize(&mut self, budget: OptimizationBudget) -> OptimizationReport {
+        let sah_before = self.sah_cost();
+        self.restore_nodes();
+        let passes = optimize::rotate(self.nodes.to_mut(), budget, self.options.depth_limit());
+        self.update_layout();
+        OptimizationReport {
+            sah_before,
+            sah_after: self.sah_cost(),
+            passes,
         }
+    }
 
-        let left_count = i as usize - node.first_primitive as usize;
-        if left_count == 0 || left_count == node.primitive_count as usize {
+    // Collapses the binary tree into nodes with up to 4 or 8 children, which
+    // `traverse` then uses on the cpu. The binary nodes are kept for the gpu.
+    pub fn collapse(&mut self, width: BvhWidth) {
+        self.collapse_with_simd(width, SimdLevel::detect());
+    }
+
+    // Same as `collapse`, but forces the instruction set used to test the children.
+    // Levels the cpu doesn't support fall back to the best one it does.
+    pub fn collapse_with_simd(&mut self, width: BvhWidth, simd: SimdLevel) {
+        self.restore_nodes();
+        self.layout = CpuLayout::Wide(WideBvh::new(&self.nodes, width, simd));
+    }
+
+    // Quantizes the child bounds to 8 bits, see `CompressedNode`, and drops the
+    // binary nodes. `traverse` then uses the compressed nodes, which are slower
+    // to decode but use less memory.
+    pub fn compress(&mut self) {
+        // Nothing to do when already compressed and not restored since
+        if self.nodes.is_empty() {
             return;
         }
+        self.layout = CpuLayout::Compressed(CompressedBvh::new(&self.nodes));
+        self.nodes = Vec::new().into();
+    }
+
+    // Decodes the binary nodes a compressed tree dropped, for the operations
+    // that change them. The layout stays compressed until it is rebuilt.
+    fn restore_nodes(&mut self) {
+        if let CpuLayout::Compressed(compressed) = &self.layout {
+            if self.nodes.is_empty() {
+                self.nodes = compressed.decompress().into();
+            }
+        }
+    }
 
-        let left_child_index = *used_nodes;
-        *used_nodes += 1;
-        let right_child_index = *used_nodes;
-        *used_nodes += 1;
-        nodes[left_child_index].first_primitive = nodes[idx].first_primitive;
-        nodes[left_child_index].primitive_count = left_count as u32;
-        nodes[right_child_index].first_primitive = i as u32;
-        nodes[right_child_index].primitive_count = nodes[idx].primitive_count - left_count as u32;
-        nodes[idx].first_primitive = left_child_index as u32;
-        nodes[idx].primitive_count = 0;
-
-        Self::update_bounds(
-            vertices,
-            triangle_indices,
-            vertex_indices,
-            nodes,
-            left_child_index,
-        );
-        Self::update_bounds(
-            vertices,
-            triangle_indices,
-            vertex_indices,
-            nodes,
-            right_child_index,
-        );
-        Self::subdivide(
-            nodes,
-            left_child_index,
-            vertices,
-            triangle_indices,
-            vertex_indices,
-            centroids,
-            used_nodes,
-        );
-        Self::subdivide(
-            nodes,
-            right_child_index,
-            vertices,
-            triangle_indices,
-            vertex_indices,
-            centroids,
-            used_nodes,
+    fn node_view(&self) -> Nodes<'_> {
+        match &self.layout {
+            CpuLayout::Compressed(compressed) if self.nodes.is_empty() => {
+                Nodes::Compressed(compressed)
+            }
+            _ => Nodes::Binary(&self.nodes),
+        }
+    }
+
+    pub fn wide(&self) -> Option<&WideBvh> {
+        match &self.layout {
+            CpuLayout::Wide(wide) => Some(wide),
+            _ => None,
+        }
+    }
+
+    pub fn compressed(&self) -> Option<&CompressedBvh> {
+        match &self.layout {
+            CpuLayout::Compressed(compressed) => Some(compressed),
+            _ => None,
+        }
+    }
+
+    fn update_layout(&mut self) {
+        match &self.layout {
+            CpuLayout::Binary => {}
+            CpuLayout::Wide(wide) => self.collapse_with_simd(wide.width(), wide.simd()),
+            CpuLayout::Compressed(_) => self.compress(),
+        }
+    }
+
+    // Expected cost of tracing a ray through the tree, relative to the
+    // cost of intersecting a single primitive. Decodes compressed trees first,
+    // see `nodes`.
+    pub fn sah_cost(&self) -> f32 {
+        let nodes = self.nodes();
+        let root_area = nodes[0].aabb.area();
+        if root_area <= 0.0 {
+            return 0.0;
+        }
+
+        let cost: f32 = nodes
+            .iter()
+            .map(|node| {
+                if node.primitive_count > 0 {
+                    node.primitive_count as f32 * node.aabb.area()
+                } else {
+                    self.options.traversal_cost * node.aabb.area()
+                }
+            })
+            .sum();
+        cost / root_area
+    }
+
+    pub fn sah_degradation(&self) -> f32 {
+        if self.build_cost > 0.0 {
+            self.sah_cost() / self.build_cost
+        } else {
+            1.0
+        }
+    }
+
+    // Decodes compressed trees first, see `nodes`
+    pub fn stats(&self) -> BvhStats {
+        let memory = self.size() + std::mem::size_of_val(self.primitives());
+        BvhStats::from_nodes(&self.nodes(), self.options.traversal_cost, memory)
+    }
+
+    pub(crate) fn build_cost(&self) -> f32 {
+        self.build_cost
+    }
+
+    pub fn options(&self) -> &BvhBuildOptions {
+        &self.options
+    }
+
+    pub fn geometry(&self) -> &G {
+        &self.geometry
+    }
+
+    pub fn aabb(&self) -> &AABB {
+        match self.node_view() {
+            Nodes::Binary(nodes) => &nodes[0].aabb,
+            Nodes::Compressed(compressed) => compressed.aabb(),
+        }
+    }
+
+    // The binary nodes. Compressed trees don't keep them, so every call decodes
+    // the whole tree into a new copy.
+    pub fn nodes(&self) -> Cow<'_, [Node]> {
+        match self.node_view() {
+            Nodes::Binary(nodes) => Cow::Borrowed(nodes),
+            Nodes::Compressed(compressed) => Cow::Owned(compressed.decompress()),
+        }
+    }
+
+    // Primitive indices in the order the leaves reference them
+    pub fn primitives(&self) -> &[u32] {
+        &self.primitives
+    }
+
+    // Bytes used by the nodes of the cpu layout in use: the binary nodes, plus
+    // the wide nodes once collapsed. Compressed trees only keep their quantized
+    // nodes, unless refitting restored the binary ones.
+    pub fn size(&self) -> usize {
+        let binary = std::mem::size_of::<Node>() * self.nodes.len();
+        match &self.layout {
+            CpuLayout::Binary => binary,
+            CpuLayout::Wide(wide) => binary + wide.size(),
+            CpuLayout::Compressed(compressed) => binary + compressed.size(),
+        }
+    }
+
+    // How the Bvh is traversed when it isn't part of an instance
+    fn own_traversal<'a>(&self, filter: Option<&'a dyn HitFilter>) -> InstanceTraversal<'a> {
+        InstanceTraversal {
+            cull_mode: self.geometry.cull_mode(),
+            instance_id: 0,
+            filter,
+        }
+    }
+
+    // Finds the closest hit inside the ray's interval and writes it to
+    // `hit_record`. `t` is measured along the untransformed ray.
+    pub fn traverse(&self, ray: &Ray, transform: &Mat4, hit_record: &mut HitRecord) {
+        self.traverse_instance(ray, transform, &self.own_traversal(None), hit_record);
+    }
+
+    // `traverse` that only records hits accepted by `filter`
+    pub fn traverse_filtered(
+        &self,
+        ray: &Ray,
+        transform: &Mat4,
+        filter: &dyn HitFilter,
+        hit_record: &mut HitRecord,
+    ) {
+        self.traverse_instance(
+            ray,
+            transform,
+            &self.own_traversal(Some(filter)),
+            hit_record,
         );
     }
 
-    fn evaluate_sah(
-        node: &Node,
-        vertices: &[Vertex],
-        triangle_indices: &[u32],
-        vertex_indices: &[u32],
-        centroids: &[Vec3],
-        axis: usize,
-        position: f32,
-    ) -> f32 {
-        let mut left_box = AABB::default();
-        let mut right_box = AABB::default();
-
-        let mut left_count = 0;
-        let mut right_count = 0;
-
-        let first = node.first_primitive as usize;
-        let count = node.primitive_count as usize;
-
-        (first..first + count).for_each(|i| {
-            let t = triangle_indices[i] as usize;
-            let i0 = vertex_indices[t] as usize;
-            let i1 = vertex_indices[t + 1] as usize;
-            let i2 = vertex_indices[t + 2] as usize;
-            let centroid = &centroids[t / 3];
-            let v0 = vertices[i0];
-            let v1 = vertices[i1];
-            let v2 = vertices[i2];
-
-            if centroid[axis] < position {
-                left_count += 1;
-                left_box.grow_with_position(&v0);
-                left_box.grow_with_position(&v1);
-                left_box.grow_with_position(&v2);
+    pub(crate) fn traverse_instance(
+        &self,
+        ray: &Ray,
+        transform: &Mat4,
+        instance: &InstanceTraversal,
+        hit_record: &mut HitRecord,
+    ) {
+        let inverse = transform.invert().unwrap();
+        let inv_ray = ray.transformed(&inverse);
+        let scale = ray.transformed_scale(&inverse);
+        let mut closest_hit = None;
+        self.walk(&inv_ray, |first, count, closest| {
+            for primitive in &self.primitives[first..first + count] {
+                let hit = self.geometry.intersect_culled(
+                    *primitive as usize,
+                    &inv_ray,
+                    instance.cull_mode,
+                );
+                if let Some(hit) = hit {
+                    if hit.t < *closest && instance.accepts(*primitive, &hit) {
+                        *closest = hit.t;
+                        closest_hit = Some((*primitive, hit));
+                    }
+                }
+            }
+            false
+        });
+
+        // Only the closest hit needs its surface
+        if let Some((primitive, hit)) = closest_hit {
+            hit_record.t = hit.t / scale;
+            hit_record.u = hit.u;
+            hit_record.v = hit.v;
+            hit_record.primitive_id = primitive;
+            hit_record.ray = *ray;
+            self.record_surface(hit_record, primitive, &inv_ray, &hit, transform);
+        }
+    }
+
+    // Passes every hit inside the ray's interval to `collector`, instead of only
+    // the closest one. Once the collector is full, nodes beyond its farthest hit
+    // are skipped.
+    pub fn traverse_all<C: HitCollector>(&self, ray: &Ray, transform: &Mat4, collector: &mut C) {
+        self.traverse_all_instance(ray, transform, &self.own_traversal(None), collector);
+    }
+
+    // `traverse_all` that only passes on hits accepted by `filter`
+    pub fn traverse_all_filtered<C: HitCollector>(
+        &self,
+        ray: &Ray,
+        transform: &Mat4,
+        filter: &dyn HitFilter,
+        collector: &mut C,
+    ) {
+        self.traverse_all_instance(ray, transform, &self.own_traversal(Some(filter)), collector);
+    }
+
+    pub(crate) fn traverse_all_instance<C: HitCollector>(
+        &self,
+        ray: &Ray,
+        transform: &Mat4,
+        instance: &InstanceTraversal,
+        collector: &mut C,
+    ) {
+        let inverse = transform.invert().unwrap();
+        let inv_ray = ray.transformed(&inverse);
+        let scale = ray.transformed_scale(&inverse);
+        self.walk(&inv_ray, |first, count, closest| {
+            for primitive in &self.primitives[first..first + count] {
+                let hit = self.geometry.intersect_culled(
+                    *primitive as usize,
+                    &inv_ray,
+                    instance.cull_mode,
+                );
+                if let Some(hit) = hit {
+                    if hit.t < *closest && instance.accepts(*primitive, &hit) {
+                        let mut hit_record = HitRecord {
+                            t: hit.t / scale,
+                            u: hit.u,
+                            v: hit.v,
+                            ray: *ray,
+                            object_id: 0,
+                            primitive_id: *primitive,
+                            obj_to_world: *transform,
+                            ..HitRecord::new()
+                        };
+                        self.record_surface(&mut hit_record, *primitive, &inv_ray, &hit, transform);
+                        collector.insert(hit_record);
+                    }
+                }
+            }
+            *closest = closest.min(collector.t_max() * scale);
+            false
+        });
+    }
+
+    // Moves the surface of a hit found by `inv_ray`, the ray in object space, to
+    // world space and stores it in `hit_record`
+    fn record_surface(
+        &self,
+        hit_record: &mut HitRecord,
+        primitive: u32,
+        inv_ray: &Ray,
+        hit: &PrimitiveHit,
+        transform: &Mat4,
+    ) {
+        let surface = self
+            .geometry
+            .surface(primitive as usize, inv_ray, hit)
+            .transformed(transform);
+        hit_record.position = surface.position;
+        hit_record.geometric_normal = surface.normal;
+        hit_record.position_error = surface.error;
+    }
+
+    // Every hit along the ray, sorted by t
+    pub fn all_hits(&self, ray: &Ray, transform: &Mat4) -> Vec<HitRecord> {
+        let mut hits = HitList::all();
+        self.traverse_all(ray, transform, &mut hits);
+        hits.into_hits()
+    }
+
+    // True when anything blocks the ray inside its interval and before `t_max`.
+    // Stops at the first hit found instead of searching for the closest one,
+    // which makes it a lot cheaper for shadow and visibility rays.
+    pub fn occluded(&self, ray: &Ray, transform: &Mat4, t_max: f32) -> bool {
+        self.occluded_instance(ray, transform, &self.own_traversal(None), t_max)
+    }
+
+    // `occluded` that ignores hits rejected by `filter`
+    pub fn occluded_filtered(
+        &self,
+        ray: &Ray,
+        transform: &Mat4,
+        filter: &dyn HitFilter,
+        t_max: f32,
+    ) -> bool {
+        self.occluded_instance(ray, transform, &self.own_traversal(Some(filter)), t_max)
+    }
+
+    pub(crate) fn occluded_instance(
+        &self,
+        ray: &Ray,
+        transform: &Mat4,
+        instance: &InstanceTraversal,
+        t_max: f32,
+    ) -> bool {
+        let inv_ray = ray
+            .with_interval(ray.t_min, ray.t_max.min(t_max))
+            .transformed(&transform.invert().unwrap());
+
+        let mut occluded = false;
+        self.walk(&inv_ray, |first, count, _| {
+            occluded = self.primitives[first..first + count]
+                .iter()
+                .any(|primitive| {
+                    self.geometry
+                        .intersect_culled(*primitive as usize, &inv_ray, instance.cull_mode)
+                        .is_some_and(|hit| instance.accepts(*primitive, &hit))
+                });
+            occluded
+        });
+        occluded
+    }
+
+    // `occluded` for many rays at once, traced in parallel. Each ray has its own
+    // `t_max`.
+    pub fn occluded_batch(&self, rays: &[Ray], transform: &Mat4, t_max: &[f32]) -> Vec<bool> {
+        assert_eq!(rays.len(), t_max.len(), "Every ray needs a t_max");
+        rays.par_iter()
+            .zip(t_max)
+            .map(|(ray, t_max)| self.occluded(ray, transform, *t_max))
+            .collect()
+    }
+
+    // `traverse` for all active rays of a packet at once. Always walks the binary
+    // nodes, testing each box against the whole packet and skipping it when the
+    // packet frustum misses it. Returns the lanes whose record was updated.
+    pub fn traverse_packet<const N: usize>(
+        &self,
+        packet: &RayPacket<N>,
+        transform: &Mat4,
+        hit_records: &mut [HitRecord; N],
+    ) -> u32 {
+        let instance = self.own_traversal(None);
+        self.traverse_packet_instance(packet, transform, &instance, hit_records)
+    }
+
+    // `traverse_packet` that only records hits accepted by `filter`
+    pub fn traverse_packet_filtered<const N: usize>(
+        &self,
+        packet: &RayPacket<N>,
+        transform: &Mat4,
+        filter: &dyn HitFilter,
+        hit_records: &mut [HitRecord; N],
+    ) -> u32 {
+        let instance = self.own_traversal(Some(filter));
+        self.traverse_packet_instance(packet, transform, &instance, hit_records)
+    }
+
+    pub(crate) fn traverse_packet_instance<const N: usize>(
+        &self,
+        packet: &RayPacket<N>,
+        transform: &Mat4,
+        instance: &InstanceTraversal,
+        hit_records: &mut [HitRecord; N],
+    ) -> u32 {
+        let (inv_packet, scale) = packet.transformed(&transform.invert().unwrap());
+        let frustum = inv_packet.frustum();
+        let mut hits = PacketHits::new(&inv_packet);
+
+        let nodes = self.node_view();
+        let mut stack_ptr = 1;
+        let mut stack = [(0, nodes.root(), inv_packet.active()); 64];
+        while stack_ptr > 0 {
+            stack_ptr -= 1;
+            let (node_idx, aabb, active) = stack[stack_ptr];
+            if frustum
+                .as_ref()
+                .is_some_and(|frustum| frustum.misses(&aabb, &hits.t, active))
+            {
+                continue;
+            }
+            let active = intersect_aabb_packet(&aabb, &inv_packet, &hits.t, active);
+            if active == 0 {
+                continue;
+            }
+
+            if let Some((first, count)) = nodes.leaf(node_idx) {
+                for primitive in &self.primitives[first..first + count] {
+                    let cull_mode = instance.cull_mode;
+                    let Some(filter) = instance.filter else {
+                        self.geometry.intersect_packet(
+                            *primitive as usize,
+                            &inv_packet,
+                            active,
+                            cull_mode,
+                            &mut hits,
+                        );
+                        continue;
+                    };
+                    // Candidates go to a copy first, a primitive gives at most one
+                    // per lane so the filter sees all of them
+                    let mut candidates = PacketHits { hit: 0, ..hits };
+                    self.geometry.intersect_packet(
+                        *primitive as usize,
+                        &inv_packet,
+                        active,
+                        cull_mode,
+                        &mut candidates,
+                    );
+                    for lane in lanes(candidates.hit) {
+                        let (t, u, v) =
+                            (candidates.t[lane], candidates.u[lane], candidates.v[lane]);
+                        if filter.accept(&instance.candidate(*primitive, u, v)) {
+                            hits.record(lane, t, u, v, *primitive);
+                        }
+                    }
+                }
             } else {
-                right_count += 1;
-                right_box.grow_with_position(&v0);
-                right_box.grow_with_position(&v1);
-                right_box.grow_with_position(&v2);
+                // The nearer child goes on top of the stack
+                let [left, right] = nodes.children(node_idx, &aabb);
+                let (near, far) = if inv_packet.in_order(active, &left.1, &right.1) {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                stack[stack_ptr] = (far.0, far.1, active);
+                stack[stack_ptr + 1] = (near.0, near.1, active);
+                stack_ptr += 2;
             }
+        }
+
+        for lane in lanes(hits.hit) {
+            let hit_record = &mut hit_records[lane];
+            hit_record.t = hits.t[lane] / scale[lane];
+            hit_record.u = hits.u[lane];
+            hit_record.v = hits.v[lane];
+            hit_record.primitive_id = hits.primitive_id[lane];
+            hit_record.ray = packet.ray(lane);
+            let hit = PrimitiveHit {
+                t: hits.t[lane],
+                u: hits.u[lane],
+                v: hits.v[lane],
+            };
+            let primitive = hits.primitive_id[lane];
+            self.record_surface(
+                hit_record,
+                primitive,
+                &inv_packet.ray(lane),
+                &hit,
+                transform,
+            );
+        }
+        hits.hit
+    }
+
+    // Calls `visitor` once with every primitive overlapping `region`. Only
+    // allocates for trees built with spatial splits, to skip the primitives
+    // referenced from several leaves. Returning `ControlFlow::Break` from the
+    // visitor ends the query.
+    pub fn query<R, F>(&self, region: &R, visitor: F)
+    where
+        R: Region,
+        F: FnMut(u32) -> ControlFlow<()>,
+    {
+        let mut visitor = self.deduplicated(visitor);
+        let _ = self.visit_overlapping(
+            |aabb| region.overlaps_aabb(aabb),
+            |primitive| {
+                if self.geometry.overlaps(primitive as usize, region) {
+                    visitor(primitive)
+                } else {
+                    ControlFlow::Continue(())
+                }
+            },
+        );
+    }
+
+    // Every primitive overlapping `region`
+    pub fn overlapping<R: Region>(&self, region: &R) -> Vec<u32> {
+        let mut primitives = Vec::new();
+        self.query(region, |primitive| {
+            primitives.push(primitive);
+            ControlFlow::Continue(())
         });
+        primitives
+    }
 
-        let cost = left_count as f32 * left_box.area() + right_count as f32 * right_box.area();
-        if cost > 0.0 {
-            cost
+    // Spatial splits reference some primitives from several leaves. For those
+    // trees the returned visitor remembers what it has seen and only passes
+    // each primitive on the first time.
+    fn deduplicated<F>(&self, mut visitor: F) -> impl FnMut(u32) -> ControlFlow<()>
+    where
+        F: FnMut(u32) -> ControlFlow<()>,
+    {
+        let mut seen = if self.primitives.len() > self.geometry.len() {
+            vec![false; self.geometry.len()]
         } else {
-            f32::MAX
+            Vec::new()
+        };
+        move |primitive| match seen.get_mut(primitive as usize) {
+            Some(true) => ControlFlow::Continue(()),
+            Some(seen) => {
+                *seen = true;
+                visitor(primitive)
+            }
+            None => visitor(primitive),
         }
     }
 
-    pub fn size(&self) -> usize {
-        std::mem::size_of::<Node>() * self.nodes.len()
+    // Passes the primitives of every leaf whose box passes `node_overlaps` to
+    // `leaf`, until it breaks
+    fn visit_overlapping<N, F>(&self, node_overlaps: N, mut leaf: F) -> ControlFlow<()>
+    where
+        N: Fn(&AABB) -> bool,
+        F: FnMut(u32) -> ControlFlow<()>,
+    {
+        let nodes = self.node_view();
+        let mut stack_ptr = 1;
+        let mut stack = [(0, nodes.root()); 64];
+        while stack_ptr > 0 {
+            stack_ptr -= 1;
+            let (node_idx, aabb) = stack[stack_ptr];
+            if !node_overlaps(&aabb) {
+                continue;
+            }
+
+            if let Some((first, count)) = nodes.leaf(node_idx) {
+                for primitive in &self.primitives[first..first + count] {
+                    leaf(*primitive)?;
+                }
+            } else {
+                let [left, right] = nodes.children(node_idx, &aabb);
+                stack[stack_ptr] = right;
+                stack[stack_ptr + 1] = left;
+                stack_ptr += 2;
+            }
+        }
+        ControlFlow::Continue(())
     }
 
-    pub fn traverse(&self, ray: &Ray, transform: &Mat4, hit_record: &mut HitRecord) {
+    // Visits the leaves the ray hits, nearest first, in whichever layout the cpu
+    // uses. `leaf` gets the range of `primitives` in the leaf and the current
+    // `t_max`, which starts at the ray's and can be lowered to skip everything
+    // farther away. Returning true ends the traversal.
+    pub(crate) fn walk<F>(&self, inv_ray: &Ray, mut leaf: F)
+    where
+        F: FnMut(usize, usize, &mut f32) -> bool,
+    {
+        match &self.layout {
+            CpuLayout::Binary => {}
+            CpuLayout::Wide(wide) => return wide.walk(inv_ray, leaf),
+            CpuLayout::Compressed(compressed) => return compressed.walk(inv_ray, leaf),
+        }
+
         let mut node_idx = 0;
         let mut stack_ptr = 0;
         let mut stack = [0; 64];
-        let inv_ray = ray.transformed(&transform.invert().unwrap());
-        let mut d = f32::MAX;
+        let mut d = inv_ray.t_max;
         loop {
             let node = &self.nodes[node_idx];
-            if self.nodes[node_idx].primitive_count > 0 {
+            if node.primitive_count > 0 {
                 let first = node.first_primitive as usize;
-                let last = first + node.primitive_count as usize;
-                for (index, p) in self.triangles[first..last].iter().enumerate() {
-                    let mut t = 0.0;
-                    let mut u = 0.0;
-                    let mut v = 0.0;
-
-                    let triangle = *p as usize;
-                    let v0 = self.indices[triangle];
-                    let v1 = self.indices[triangle + 1];
-                    let v2 = self.indices[triangle + 2];
-                    let hit = intersect_triangle(
-                        &inv_ray,
-                        &self.vertices[v0 as usize],
-                        &self.vertices[v1 as usize],
-                        &self.vertices[v2 as usize],
-                        &mut t,
-                        &mut u,
-                        &mut v,
-                    );
-                    if hit && t < d {
-                        d = t;
-                        hit_record.t = t;
-                        hit_record.u = u;
-                        hit_record.v = v;
-                        hit_record.primitive_id = index as _;
-                        hit_record.ray = *ray;
-                    }
+                if leaf(first, node.primitive_count as usize, &mut d) {
+                    return;
                 }
                 if stack_ptr == 0 {
                     break;
@@ -335,8 +1271,8 @@ impl Bvh {
             let mut right_child_idx = left_child_idx + 1;
             let left_child = &self.nodes[left_child_idx];
             let right_child = &self.nodes[right_child_idx];
-            let mut left_distance = intersect_aabb(&left_child.aabb, &inv_ray, f32::MAX);
-            let mut right_distance = intersect_aabb(&right_child.aabb, &inv_ray, f32::MAX);
+            let mut left_distance = intersect_aabb(&left_child.aabb, inv_ray, d);
+            let mut right_distance = intersect_aabb(&right_child.aabb, inv_ray, d);
 
             if left_distance > right_distance {
                 std::mem::swap(&mut left_child_idx, &mut right_child_idx);
@@ -359,3 +1295,248 @@ impl Bvh {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{InnerSpace, Rad, SquareMatrix};
+
+    use super::{BuildStrategy, Bvh, BvhBuildOptions};
+    use crate::wide_bvh::BvhWidth;
+    use crate::{
+        intersect::{closest_point_on_triangle, intersect_triangles},
+        testing::{closest_hit, encloses, slivers, soup, Rng},
+        types::{HitRecord, Mat4, Vec3, Vertex, AABB},
+    };
+
+    fn triangles(vertices: &[Vertex], transform: &Mat4) -> Vec<[Vertex; 3]> {
+        vertices
+            .chunks(3)
+            .map(|triangle| [0, 1, 2].map(|i| (transform * triangle[i].extend(1.0)).truncate()))
+            .collect()
+    }
+
+    #[test]
+    fn intersecting_pairs_are_reported_once() {
+        let (vertices, indices) = slivers(1500);
+        let options = BvhBuildOptions::new().with_strategy(BuildStrategy::SpatialSah {
+            max_duplication: 0.5,
+        });
+        let bvh = Bvh::new_with_options(&vertices, &indices, &options);
+        assert!(bvh.primitives().len() > indices.len() / 3);
+
+        let transform = Mat4::from_translation(Vec3::new(0.5, -0.25, 0.1));
+        let lhs = triangles(&vertices, &Mat4::identity());
+        let rhs = triangles(&vertices, &transform);
+        let mut expected = Vec::new();
+        for (a, triangle_a) in lhs.iter().enumerate() {
+            for (b, triangle_b) in rhs.iter().enumerate() {
+                if intersect_triangles(triangle_a, triangle_b) {
+                    expected.push((a as u32, b as u32));
+                }
+            }
+        }
+
+        let mut pairs = bvh.intersecting_pairs(&Mat4::identity(), &bvh, &transform);
+        pairs.sort_unstable();
+        assert!(!expected.is_empty());
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn singular_transforms_intersect_nothing() {
+        let (vertices, indices) = slivers(100);
+        let bvh = Bvh::new(&vertices, &indices);
+        let flat = Mat4::from_nonuniform_scale(1.0, 1.0, 0.0);
+        assert!(bvh
+            .intersecting_pairs(&flat, &bvh, &Mat4::identity())
+            .is_empty());
+        assert!(!bvh.intersects(&flat, &bvh, &Mat4::identity()));
+    }
+
+    #[test]
+    fn refit_bounds_contain_moved_triangles() {
+        let (vertices, indices) = soup(2000, 12);
+        let spatial = BvhBuildOptions::new().with_strategy(BuildStrategy::SpatialSah {
+            max_duplication: 0.3,
+        });
+        for (options, clipped) in [(BvhBuildOptions::new(), false), (spatial, true)] {
+            let mut bvh = Bvh::new_with_options(&vertices, &indices, &options);
+            let moved: Vec<Vertex> = vertices
+                .iter()
+                .map(|v| Vec3::new(v.x + (3.0 * v.y).sin(), 1.5 * v.y, v.z - 0.5 * v.x))
+                .collect();
+            assert!(bvh.refit(&moved) > 1.0);
+
+            let nodes = bvh.nodes();
+            for node in nodes.iter() {
+                let first = node.first_primitive as usize;
+                if node.primitive_count == 0 {
+                    assert!(encloses(&node.aabb, &nodes[first].aabb));
+                    assert!(encloses(&node.aabb, &nodes[first + 1].aabb));
+                    continue;
+                }
+                for &primitive in &bvh.primitives()[first..first + node.primitive_count as usize] {
+                    let mut aabb = AABB::default();
+                    for i in 0..3 {
+                        aabb.grow_with_position(
+                            &moved[indices[3 * primitive as usize + i] as usize],
+                        );
+                    }
+                    assert!(encloses(&node.aabb, &aabb));
+                }
+            }
+
+            let mut rng = Rng::new(13);
+            for _ in 0..300 {
+                let ray = rng.ray();
+                let mut hit = HitRecord::new();
+                bvh.traverse(&ray, &Mat4::identity(), &mut hit);
+                let expected = closest_hit(&moved, &indices, &ray);
+                assert!(hit.t == expected || (hit.t - expected).abs() < expected * 1e-5);
+            }
+
+            // Moving the vertices back restores the original cost, except that
+            // refitted leaves hold whole triangles instead of their clipped parts
+            let degradation = bvh.refit(&vertices);
+            if clipped {
+                assert!(degradation > 1.0);
+            } else {
+                assert!((degradation - 1.0).abs() < 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn occlusion_matches_the_closest_hit() {
+        let (vertices, indices) = soup(2000, 28);
+        let binary = Bvh::new(&vertices, &indices);
+        let mut wide = Bvh::new(&vertices, &indices);
+        wide.collapse(BvhWidth::Four);
+        let mut compressed = Bvh::new(&vertices, &indices);
+        compressed.compress();
+
+        let transform = Mat4::from_translation(Vec3::new(0.0, 0.5, 1.0)) * Mat4::from_scale(1.5);
+        let mut rng = Rng::new(29);
+        for bvh in [&binary, &wide, &compressed] {
+            for transform in [Mat4::identity(), transform] {
+                let mut rays = Vec::new();
+                let mut t_max = Vec::new();
+                for _ in 0..500 {
+                    let ray = rng.ray();
+                    let mut hit = HitRecord::new();
+                    binary.traverse(&ray, &transform, &mut hit);
+                    // Just before and after the closest hit, or anywhere for misses
+                    let limit = if hit.t < f32::MAX {
+                        hit.t * [0.99, 1.01][(rng.next() * 2.0) as usize]
+                    } else {
+                        rng.range(0.0, 30.0)
+                    };
+                    assert_eq!(bvh.occluded(&ray, &transform, limit), hit.t < limit);
+                    rays.push(ray);
+                    t_max.push(limit);
+                }
+
+                let batch = bvh.occluded_batch(&rays, &transform, &t_max);
+                for ((ray, t_max), occluded) in rays.iter().zip(&t_max).zip(batch) {
+                    assert_eq!(occluded, bvh.occluded(ray, &transform, *t_max));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn traversal_honours_the_ray_interval() {
+        let (vertices, indices) = soup(1000, 31);
+        let binary = Bvh::new(&vertices, &indices);
+        let mut wide = Bvh::new(&vertices, &indices);
+        wide.collapse(BvhWidth::Eight);
+        let mut compressed = Bvh::new(&vertices, &indices);
+        compressed.compress();
+
+        let transform = Mat4::from_translation(Vec3::new(0.5, 0.0, 2.0)) * Mat4::from_scale(2.0);
+        let moved: Vec<Vertex> = vertices
+            .iter()
+            .map(|v| (transform * v.extend(1.0)).truncate())
+            .collect();
+        let mut rng = Rng::new(32);
+        let mut tested = 0;
+        while tested < 300 {
+            let ray = rng.ray();
+            let mut hits: Vec<f32> = moved
+                .chunks(3)
+                .map(|triangle| closest_hit(triangle, &[0, 1, 2], &ray))
+                .filter(|t| *t < f32::MAX)
+                .collect();
+            if hits.len() < 2 {
+                continue;
+            }
+            hits.sort_by(f32::total_cmp);
+            tested += 1;
+
+            // Bounds halfway between hits, so rounding can't move a hit across them
+            let first = (rng.next() * (hits.len() - 1) as f32) as usize;
+            let last = first + 1 + (rng.next() * (hits.len() - first) as f32) as usize;
+            let t_min = (hits[first] + hits[first + 1]) * 0.5;
+            let t_max = match last {
+                last if last == hits.len() => f32::MAX,
+                last => (hits[last - 1] + hits[last]) * 0.5,
+            };
+            let expected = hits[first + 1..last].first().copied();
+
+            let ray = ray.with_interval(t_min, t_max);
+            for bvh in [&binary, &wide, &compressed] {
+                let mut hit = HitRecord::new();
+                bvh.traverse(&ray, &transform, &mut hit);
+                match expected {
+                    Some(t) => assert!((hit.t - t).abs() < t * 1e-5),
+                    None => assert_eq!(hit.t, f32::MAX),
+                }
+                assert_eq!(bvh.occluded(&ray, &transform, f32::MAX), expected.is_some());
+                assert_eq!(bvh.all_hits(&ray, &transform).len(), last - first - 1);
+            }
+        }
+    }
+
+    #[test]
+    fn closest_points_match_brute_force() {
+        let (vertices, indices) = soup(1000, 39);
+        let binary = Bvh::new(&vertices, &indices);
+        let mut compressed = Bvh::new(&vertices, &indices);
+        compressed.compress();
+
+        let rotated = Mat4::from_translation(Vec3::new(1.0, 0.0, -2.0))
+            * Mat4::from_angle_x(Rad(0.8))
+            * Mat4::from_nonuniform_scale(0.5, 1.0, 2.0);
+        let mut rng = Rng::new(40);
+        for transform in [Mat4::identity(), rotated] {
+            let world = triangles(&vertices, &transform);
+            for bvh in [&binary, &compressed] {
+                for _ in 0..200 {
+                    let point = rng.vec3(-5.0, 5.0);
+                    let expected = world
+                        .iter()
+                        .map(|[v0, v1, v2]| {
+                            let (closest, _, _) = closest_point_on_triangle(&point, v0, v1, v2);
+                            (closest - point).magnitude()
+                        })
+                        .fold(f32::MAX, f32::min);
+
+                    let closest = bvh
+                        .closest_point_transformed(point, &transform, f32::MAX)
+                        .unwrap();
+                    assert!((closest.distance - expected).abs() < 1e-4);
+                    assert!(((closest.point - point).magnitude() - closest.distance).abs() < 1e-4);
+                    let [v0, v1, v2] = world[closest.primitive_id as usize];
+                    let on_triangle = v0 + (v1 - v0) * closest.u + (v2 - v0) * closest.v;
+                    assert!((on_triangle - closest.point).magnitude() < 1e-4);
+
+                    let limited = |max_distance| {
+                        bvh.closest_point_transformed(point, &transform, max_distance)
+                    };
+                    assert!(limited(expected * 0.99).is_none());
+                    assert!(limited(expected * 1.01).is_some());
+                }
+            }
+        }
+    }
+}
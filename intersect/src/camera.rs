@@ -1,6 +1,9 @@
 use cgmath::InnerSpace;
 
-use crate::types::{Position, Ray};
+use crate::{
+    query::Frustum,
+    types::{Position, Ray, Vec4},
+};
 
 pub struct Camera {
     position: Position,
@@ -27,4 +30,27 @@ impl Camera {
         let direction = (pixel_position - self.position).normalize();
         Ray::new(self.position, direction)
     }
+
+    // Volume seen through the image, with the near plane at the camera and no far
+    // plane
+    pub fn frustum(&self) -> Frustum {
+        let p3 = self.p1 + self.p2 - self.p0;
+        let center = (self.p1 + self.p2) * 0.5;
+        let side = |a: Position, b: Position| {
+            let mut normal = (a - self.position).cross(b - self.position);
+            if normal.dot(center - self.position) < 0.0 {
+                normal = -normal;
+            }
+            normal.extend(-normal.dot(self.position))
+        };
+        let forward = center - self.position;
+        Frustum::new([
+            side(self.p0, self.p1),
+            side(self.p1, p3),
+            side(p3, self.p2),
+            side(self.p2, self.p0),
+            forward.extend(-forward.dot(self.position)),
+            Vec4::new(0.0, 0.0, 0.0, 1.0),
+        ])
+    }
 }
@@ -0,0 +1,290 @@
+use crate::{
+    bvh::Node,
+    intersect::intersect_aabb,
+    types::{Ray, AABB},
+};
+
+const LEVELS: f32 = 255.0;
+// Multiplying by the reciprocal instead of dividing keeps `decode` to adds and
+// multiplies, which are correctly rounded on the gpu as well. The shader uses
+// the same constant, so both sides decode to the same bits.
+const INV_LEVELS: f32 = 1.0 / LEVELS;
+
+// Mirrors `Node`, but the bounds of both children are stored in their parent as
+// 8 bit offsets into the parent's bounds. Each child is quantized as min x, y, z
+// followed by max x, y, z. A node's own bounds are only known while traversing,
+// by decoding them from its parent, and the root bounds are kept as floats.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct CompressedNode {
+    pub child_bounds: [[u8; 6]; 2],
+    pub first_primitive: u32,
+    pub primitive_count: u32,
+}
+
+// Decodes quantized bounds relative to `frame`. Minimums are measured from the
+// frame's minimum and maximums from its maximum, so 0 and 255 decode exactly to
+// the frame itself. `decode_child` in ray_intersector.comp must match this
+// operation for operation.
+pub fn decode(frame: &AABB, quantized: &[u8; 6]) -> AABB {
+    let scale = frame.extent() * INV_LEVELS;
+    let mut aabb = *frame;
+    for axis in 0..3 {
+        aabb.min[axis] = frame.min[axis] + quantized[axis] as f32 * scale[axis];
+        aabb.max[axis] = frame.max[axis] - (LEVELS - quantized[axis + 3] as f32) * scale[axis];
+    }
+    aabb
+}
+
+// Rounds outwards and then checks the result with `decode` itself, which makes
+// the decoded bounds conservative regardless of float rounding.
+fn encode(frame: &AABB, aabb: &AABB) -> [u8; 6] {
+    let extent = frame.extent();
+    let mut quantized = [0, 0, 0, 255, 255, 255];
+    for axis in 0..3 {
+        if extent[axis] <= 0.0 {
+            continue;
+        }
+
+        let relative = |v: f32| (v - frame.min[axis]) / extent[axis] * LEVELS;
+        quantized[axis] = relative(aabb.min[axis]).floor().clamp(0.0, LEVELS) as u8;
+        quantized[axis + 3] = relative(aabb.max[axis]).ceil().clamp(0.0, LEVELS) as u8;
+        while quantized[axis] > 0 && decode(frame, &quantized).min[axis] > aabb.min[axis] {
+            quantized[axis] -= 1;
+        }
+        while quantized[axis + 3] < 255 && decode(frame, &quantized).max[axis] < aabb.max[axis] {
+            quantized[axis + 3] += 1;
+        }
+    }
+    quantized
+}
+
+pub struct CompressedBvh {
+    aabb: AABB,
+    nodes: Vec<CompressedNode>,
+}
+
+impl CompressedBvh {
+    pub fn new(nodes: &[Node]) -> Self {
+        let mut compressed: Vec<CompressedNode> = nodes
+            .iter()
+            .map(|node| CompressedNode {
+                child_bounds: [[0; 6]; 2],
+                first_primitive: node.first_primitive,
+                primitive_count: node.primitive_count,
+            })
+            .collect();
+
+        // Children are quantized against the decoded bounds of their parent, not the
+        // exact ones, so traversal can decode them from the same frame. Parents are
+        // stored before their children, which makes a single forward pass enough.
+        let mut frames = vec![AABB::default(); nodes.len()];
+        frames[0] = nodes[0].aabb;
+        for (idx, node) in nodes.iter().enumerate() {
+            if node.primitive_count > 0 || nodes.len() == 1 {
+                continue;
+            }
+
+            let left_child_index = node.first_primitive as usize;
+            for (slot, child) in [left_child_index, left_child_index + 1]
+                .into_iter()
+                .enumerate()
+            {
+                let quantized = encode(&frames[idx], &nodes[child].aabb);
+                compressed[idx].child_bounds[slot] = quantized;
+                frames[child] = decode(&frames[idx], &quantized);
+            }
+        }
+
+        Self {
+            aabb: nodes[0].aabb,
+            nodes: compressed,
+        }
+    }
+
+    pub fn aabb(&self) -> &AABB {
+        &self.aabb
+    }
+
+    pub fn nodes(&self) -> &[CompressedNode] {
+        &self.nodes
+    }
+
+    // Bytes used by the nodes and the root bounds
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<AABB>() + std::mem::size_of::<CompressedNode>() * self.nodes.len()
+    }
+
+    fn is_leaf(&self, node: &CompressedNode) -> bool {
+        node.primitive_count > 0 || self.nodes.len() == 1
+    }
+
+    // Indices and decoded bounds of the children of interior node `idx`, whose
+    // own decoded bounds are `frame`
+    pub(crate) fn children(&self, idx: usize, frame: &AABB) -> [(usize, AABB); 2] {
+        let node = &self.nodes[idx];
+        let left_child_index = node.first_primitive as usize;
+        [
+            (left_child_index, decode(frame, &node.child_bounds[0])),
+            (left_child_index + 1, decode(frame, &node.child_bounds[1])),
+        ]
+    }
+
+    // Binary nodes with the decoded bounds, which contain the original ones
+    pub fn decompress(&self) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self
+            .nodes
+            .iter()
+            .map(|node| Node {
+                aabb: AABB::default(),
+                first_primitive: node.first_primitive,
+                primitive_count: node.primitive_count,
+            })
+            .collect();
+        nodes[0].aabb = self.aabb;
+        for (idx, node) in self.nodes.iter().enumerate() {
+            if !self.is_leaf(node) {
+                for (child, aabb) in self.children(idx, &nodes[idx].aabb) {
+                    nodes[child].aabb = aabb;
+                }
+            }
+        }
+        nodes
+    }
+
+    // Visits the leaves hit by the ray from near to far, see `Bvh::walk`
+    pub(crate) fn walk<F>(&self, inv_ray: &Ray, mut leaf: F)
+    where
+        F: FnMut(usize, usize, &mut f32) -> bool,
+    {
+        let mut node_idx = 0;
+        let mut frame = self.aabb;
+        let mut stack_ptr = 0;
+        let mut stack = [(0, AABB::default()); 64];
+        let mut d = inv_ray.t_max;
+        loop {
+            let node = &self.nodes[node_idx];
+            if node.primitive_count > 0 {
+                let first = node.first_primitive as usize;
+                if leaf(first, node.primitive_count as usize, &mut d) {
+                    return;
+                }
+                if stack_ptr == 0 {
+                    break;
+                } else {
+                    stack_ptr -= 1;
+                    (node_idx, frame) = stack[stack_ptr];
+                    continue;
+                }
+            }
+
+            let mut left_child_idx = node.first_primitive as usize;
+            let mut right_child_idx = left_child_idx + 1;
+            let mut left_aabb = decode(&frame, &node.child_bounds[0]);
+            let mut right_aabb = decode(&frame, &node.child_bounds[1]);
+            let mut left_distance = intersect_aabb(&left_aabb, inv_ray, d);
+            let mut right_distance = intersect_aabb(&right_aabb, inv_ray, d);
+
+            if left_distance > right_distance {
+                std::mem::swap(&mut left_child_idx, &mut right_child_idx);
+                std::mem::swap(&mut left_aabb, &mut right_aabb);
+                std::mem::swap(&mut left_distance, &mut right_distance);
+            }
+            if left_distance == f32::MAX {
+                if stack_ptr == 0 {
+                    break;
+                } else {
+                    stack_ptr -= 1;
+                    (node_idx, frame) = stack[stack_ptr];
+                }
+            } else {
+                node_idx = left_child_idx;
+                frame = left_aabb;
+                if right_distance != f32::MAX {
+                    stack[stack_ptr] = (right_child_idx, right_aabb);
+                    stack_ptr += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{ElementWise, SquareMatrix};
+
+    use crate::{
+        bvh::Bvh,
+        testing::{closest_hit, encloses, soup, Rng},
+        types::{HitRecord, Mat4, Vec3, Vertex, AABB},
+    };
+
+    #[test]
+    fn compressed_trees_match_binary() {
+        let (vertices, indices) = soup(2000, 3);
+        let binary = Bvh::new(&vertices, &indices);
+        let mut compressed = Bvh::new(&vertices, &indices);
+        compressed.compress();
+
+        // Only the compressed nodes are kept, the binary ones decode to bounds
+        // containing the exact ones
+        assert_eq!(compressed.size(), compressed.compressed().unwrap().size());
+        assert!(compressed.size() < binary.size());
+        assert!(compressed.stats().memory < binary.stats().memory);
+        for (decoded, exact) in compressed.nodes().iter().zip(binary.nodes().iter()) {
+            assert!(encloses(&decoded.aabb, &exact.aabb));
+        }
+
+        let mut rng = Rng::new(5);
+        for _ in 0..2000 {
+            let ray = rng.ray();
+            let mut expected = HitRecord::new();
+            let mut hit = HitRecord::new();
+            binary.traverse(&ray, &Mat4::identity(), &mut expected);
+            compressed.traverse(&ray, &Mat4::identity(), &mut hit);
+            assert_eq!(
+                (hit.t, hit.u, hit.v, hit.primitive_id),
+                (expected.t, expected.u, expected.v, expected.primitive_id)
+            );
+
+            let point = rng.vec3(-3.0, 3.0);
+            assert_eq!(
+                compressed
+                    .closest_point(point, f32::MAX)
+                    .map(|p| p.distance),
+                binary.closest_point(point, f32::MAX).map(|p| p.distance)
+            );
+
+            let aabb = AABB::new(point, point + rng.vec3(0.0, 0.5));
+            let mut found = compressed.overlapping(&aabb);
+            let mut expected = binary.overlapping(&aabb);
+            found.sort_unstable();
+            expected.sort_unstable();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn refitting_keeps_the_tree_compressed() {
+        let (vertices, indices) = soup(1000, 4);
+        let mut bvh = Bvh::new(&vertices, &indices);
+        bvh.compress();
+
+        let moved: Vec<Vertex> = vertices
+            .iter()
+            .map(|v| v.mul_element_wise(Vec3::new(1.5, 0.5, 1.0)))
+            .collect();
+        bvh.refit(&moved);
+        assert!(bvh.compressed().is_some());
+        assert_eq!(bvh.size(), bvh.compressed().unwrap().size());
+
+        let mut rng = Rng::new(6);
+        for _ in 0..1000 {
+            let ray = rng.ray();
+            let mut hit = HitRecord::new();
+            bvh.traverse(&ray, &Mat4::identity(), &mut hit);
+            let expected = closest_hit(&moved, &indices, &ray);
+            assert!(hit.t == expected || (hit.t - expected).abs() < expected * 1e-5);
+        }
+    }
+}
@@ -1,8 +1,12 @@
 use rayon::prelude::*;
 
 use crate::{
-    camera::Camera, frame_buffer::Framebuffer,
-    top_level_acceleration_structure::TopLevelAccelerationStructure, types::HdrColor,
+    camera::Camera,
+    filter::HitFilter,
+    frame_buffer::Framebuffer,
+    packet::{lanes, RayPacket16},
+    top_level_acceleration_structure::TopLevelAccelerationStructure,
+    types::HdrColor,
 };
 
 pub trait Tracer {
@@ -14,7 +18,27 @@ pub trait Tracer {
     );
 }
 
-pub struct CpuTracer {}
+// Width and height of the pixel tiles traced as one packet
+const TILE_SIZE: usize = 4;
+
+#[derive(Default)]
+pub struct CpuTracer {
+    filter: Option<Box<dyn HitFilter + Send + Sync>>,
+}
+
+impl CpuTracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Only hits accepted by `filter` are shaded, for example to see through
+    // alpha tested cutouts
+    pub fn with_filter(mut self, filter: impl HitFilter + Send + Sync + 'static) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+}
+
 impl Tracer for CpuTracer {
     fn trace(
         &self,
@@ -25,19 +49,41 @@ impl Tracer for CpuTracer {
         let width = framebuffer.width();
         let height = framebuffer.height();
 
+        // Primary rays of a small tile are coherent, so they are traced as one
+        // packet. Lanes outside the image stay inactive.
         let pixels = framebuffer.pixels_mut();
         let bands: Vec<(usize, &mut [cgmath::Vector4<f32>])> =
-            pixels.chunks_mut(width).enumerate().collect();
+            pixels.chunks_mut(width * TILE_SIZE).enumerate().collect();
 
-        bands.into_par_iter().for_each(|(y, row)| {
-            (0..width).for_each(|x| {
-                let ray = camera.ray(x, y, width, height);
+        bands.into_par_iter().for_each(|(band, rows)| {
+            let y0 = band * TILE_SIZE;
+            let rows_in_band = rows.len() / width;
+            (0..width).step_by(TILE_SIZE).for_each(|x0| {
+                let mut packet = RayPacket16::new().with_frustum_culling(true);
+                for dy in 0..rows_in_band {
+                    for dx in 0..TILE_SIZE.min(width - x0) {
+                        let ray = camera.ray(x0 + dx, y0 + dy, width, height);
+                        packet.set(dy * TILE_SIZE + dx, &ray);
+                    }
+                }
 
-                let record = acceleration_structure.traverse(&ray);
-                let pixel = &mut row[x];
-                if record.t < f32::MAX {
-                    *pixel =
-                        cgmath::Vector4::new(1.0 - record.u - record.v, record.u, record.v, 1.0);
+                let records = match &self.filter {
+                    Some(filter) => {
+                        acceleration_structure.traverse_packet_filtered(&packet, filter.as_ref())
+                    }
+                    None => acceleration_structure.traverse_packet(&packet),
+                };
+                for lane in lanes(packet.active()) {
+                    let record = &records[lane];
+                    let pixel = &mut rows[(lane / TILE_SIZE) * width + x0 + lane % TILE_SIZE];
+                    if record.t < f32::MAX {
+                        *pixel = cgmath::Vector4::new(
+                            1.0 - record.u - record.v,
+                            record.u,
+                            record.v,
+                            1.0,
+                        );
+                    }
                 }
             });
         });
@@ -0,0 +1,614 @@
+use cgmath::Matrix4;
+use rayon::prelude::*;
+
+use crate::{
+    filter::HitFilter,
+    intersect::intersect_aabb,
+    motion::MotionTransform,
+    top_level_acceleration_structure::Instance,
+    types::{HitRecord, Ray, AABB},
+};
+
+const NULL_NODE: u32 = u32::MAX;
+
+// Refers to an instance in a `DynamicTlas`. Handles of removed instances are
+// never handed out again, so using one after `remove` is caught.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct InstanceHandle {
+    index: u32,
+    generation: u32,
+}
+
+impl InstanceHandle {
+    // Stable for the lifetime of the instance, and what hit records report as
+    // `object_id`
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+#[derive(Clone, Copy)]
+struct DynamicNode {
+    aabb: AABB,
+    parent: u32,
+    children: [u32; 2],
+    // Slot of the instance for leaves, `NULL_NODE` for interior nodes
+    instance: u32,
+    height: u32,
+}
+
+impl DynamicNode {
+    fn is_leaf(&self) -> bool {
+        self.instance != NULL_NODE
+    }
+}
+
+struct Slot {
+    instance: Option<Instance>,
+    leaf: u32,
+    generation: u32,
+}
+
+// A top level tree that is updated in place as instances are added, moved and
+// removed, instead of being rebuilt from the full instance list. New leaves are
+// placed where they add the least surface area and the tree is rebalanced on
+// the way back up, which keeps it close to what a full rebuild would produce.
+pub struct DynamicTlas {
+    nodes: Vec<DynamicNode>,
+    free_nodes: Vec<u32>,
+    slots: Vec<Slot>,
+    free_slots: Vec<u32>,
+    root: u32,
+}
+
+impl DynamicTlas {
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            free_nodes: Vec::new(),
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            root: NULL_NODE,
+        }
+    }
+
+    pub fn insert(&mut self, instance: Instance) -> InstanceHandle {
+        let aabb = instance.aabb();
+        let index = match self.free_slots.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot {
+                    instance: None,
+                    leaf: NULL_NODE,
+                    generation: 0,
+                });
+                self.slots.len() as u32 - 1
+            }
+        };
+
+        let leaf = self.allocate_node(DynamicNode {
+            aabb,
+            parent: NULL_NODE,
+            children: [NULL_NODE; 2],
+            instance: index,
+            height: 0,
+        });
+        self.insert_leaf(leaf);
+
+        let slot = &mut self.slots[index as usize];
+        slot.instance = Some(instance);
+        slot.leaf = leaf;
+        InstanceHandle {
+            index,
+            generation: slot.generation,
+        }
+    }
+
+    pub fn remove(&mut self, handle: InstanceHandle) -> Option<Instance> {
+        let leaf = self.leaf(handle)?;
+        self.remove_leaf(leaf);
+        self.free_nodes.push(leaf);
+
+        let slot = &mut self.slots[handle.index as usize];
+        slot.generation = slot.generation.wrapping_add(1);
+        slot.leaf = NULL_NODE;
+        self.free_slots.push(handle.index);
+        slot.instance.take()
+    }
+
+    // Moves an instance and reinserts it where its new bounds fit best. Moving
+    // instances stop moving. Returns false for handles of removed instances.
+    pub fn set_transform(&mut self, handle: InstanceHandle, transform: Matrix4<f32>) -> bool {
+        self.update(handle, |instance| instance.with_transform(transform))
+    }
+
+    // `set_transform` for a transform that changes over the shutter interval
+    pub fn set_motion(&mut self, handle: InstanceHandle, motion: MotionTransform) -> bool {
+        self.update(handle, |instance| instance.with_motion(motion))
+    }
+
+    fn update<F>(&mut self, handle: InstanceHandle, update: F) -> bool
+    where
+        F: FnOnce(Instance) -> Instance,
+    {
+        let Some(leaf) = self.leaf(handle) else {
+            return false;
+        };
+
+        let slot = &mut self.slots[handle.index as usize];
+        let instance = slot.instance.as_mut().unwrap();
+        *instance = update(instance.clone());
+        let aabb = instance.aabb();
+
+        self.remove_leaf(leaf);
+        self.nodes[leaf as usize].aabb = aabb;
+        self.insert_leaf(leaf);
+        true
+    }
+
+    pub fn get(&self, handle: InstanceHandle) -> Option<&Instance> {
+        self.leaf(handle)?;
+        self.slots[handle.index as usize].instance.as_ref()
+    }
+
+    pub fn instances(&self) -> impl Iterator<Item = (InstanceHandle, &Instance)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let handle = InstanceHandle {
+                index: index as u32,
+                generation: slot.generation,
+            };
+            slot.instance.as_ref().map(|instance| (handle, instance))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free_slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root == NULL_NODE
+    }
+
+    pub fn aabb(&self) -> AABB {
+        match self.root {
+            NULL_NODE => AABB::default(),
+            root => self.nodes[root as usize].aabb,
+        }
+    }
+
+    // Number of levels below the root, 0 for a single instance
+    pub fn height(&self) -> u32 {
+        match self.root {
+            NULL_NODE => 0,
+            root => self.nodes[root as usize].height,
+        }
+    }
+
+    // Expected cost of tracing a ray through the tree relative to intersecting a
+    // single instance, comparable to `TopLevelAccelerationStructure::stats`
+    pub fn sah_cost(&self) -> f32 {
+        let root_area = self.aabb().area();
+        if self.is_empty() || root_area <= 0.0 {
+            return 0.0;
+        }
+
+        let mut cost = 0.0;
+        let mut stack = vec![self.root];
+        while let Some(idx) = stack.pop() {
+            let node = &self.nodes[idx as usize];
+            cost += node.aabb.area();
+            if !node.is_leaf() {
+                stack.extend(node.children);
+            }
+        }
+        cost / root_area
+    }
+
+    // Hits report the handle index of the instance as `object_id`
+    pub fn traverse(&self, ray: &Ray) -> HitRecord {
+        self.traverse_with(ray, None)
+    }
+
+    // `traverse` that only records hits accepted by `filter`. The filter sees
+    // the id the instance was created with, not its handle.
+    pub fn traverse_filtered(&self, ray: &Ray, filter: &dyn HitFilter) -> HitRecord {
+        self.traverse_with(ray, Some(filter))
+    }
+
+    fn traverse_with(&self, ray: &Ray, filter: Option<&dyn HitFilter>) -> HitRecord {
+        let mut record = HitRecord::new();
+        if self.is_empty() {
+            return record;
+        }
+
+        let mut node_idx = self.root;
+        let mut stack_ptr = 0;
+        let mut stack = [NULL_NODE; 64];
+        let mut d = ray.t_max;
+        loop {
+            let node = &self.nodes[node_idx as usize];
+            if node.is_leaf() {
+                let instance = self.slots[node.instance as usize]
+                    .instance
+                    .as_ref()
+                    .unwrap();
+                let transform = instance.transform_at(ray.time);
+                // Only hits closer than the current one are recorded
+                let ray = ray.with_interval(ray.t_min, d);
+                let traversal = instance.traversal(filter);
+                instance
+                    .blas
+                    .traverse_instance(&ray, &transform, &traversal, &mut record);
+                if record.t < d {
+                    d = record.t;
+                    record.object_id = node.instance;
+                    record.obj_to_world = transform;
+                }
+
+                if stack_ptr == 0 {
+                    break;
+                }
+                stack_ptr -= 1;
+                node_idx = stack[stack_ptr];
+                continue;
+            }
+
+            let [mut near, mut far] = node.children;
+            let mut near_distance = intersect_aabb(&self.nodes[near as usize].aabb, ray, d);
+            let mut far_distance = intersect_aabb(&self.nodes[far as usize].aabb, ray, d);
+            if near_distance > far_distance {
+                std::mem::swap(&mut near, &mut far);
+                std::mem::swap(&mut near_distance, &mut far_distance);
+            }
+
+            if near_distance == f32::MAX {
+                if stack_ptr == 0 {
+                    break;
+                }
+                stack_ptr -= 1;
+                node_idx = stack[stack_ptr];
+            } else {
+                node_idx = near;
+                if far_distance != f32::MAX {
+                    stack[stack_ptr] = far;
+                    stack_ptr += 1;
+                }
+            }
+        }
+
+        record
+    }
+
+    // True when any instance blocks the ray before `t_max`, see `Bvh::occluded`
+    pub fn occluded(&self, ray: &Ray, t_max: f32) -> bool {
+        self.occluded_with(ray, t_max, None)
+    }
+
+    // `occluded` that ignores hits rejected by `filter`
+    pub fn occluded_filtered(&self, ray: &Ray, t_max: f32, filter: &dyn HitFilter) -> bool {
+        self.occluded_with(ray, t_max, Some(filter))
+    }
+
+    fn occluded_with(&self, ray: &Ray, t_max: f32, filter: Option<&dyn HitFilter>) -> bool {
+        if self.is_empty() {
+            return false;
+        }
+
+        let mut stack_ptr = 1;
+        let mut stack = [self.root; 64];
+        while stack_ptr > 0 {
+            stack_ptr -= 1;
+            let node = &self.nodes[stack[stack_ptr] as usize];
+            if intersect_aabb(&node.aabb, ray, t_max) == f32::MAX {
+                continue;
+            }
+
+            if node.is_leaf() {
+                let instance = self.slots[node.instance as usize]
+                    .instance
+                    .as_ref()
+                    .unwrap();
+                let traversal = instance.traversal(filter);
+                let transform = instance.transform_at(ray.time);
+                if instance
+                    .blas
+                    .occluded_instance(ray, &transform, &traversal, t_max)
+                {
+                    return true;
+                }
+            } else {
+                stack[stack_ptr] = node.children[1];
+                stack[stack_ptr + 1] = node.children[0];
+                stack_ptr += 2;
+            }
+        }
+        false
+    }
+
+    // `occluded` for many rays at once, traced in parallel
+    pub fn occluded_batch(&self, rays: &[Ray], t_max: &[f32]) -> Vec<bool> {
+        assert_eq!(rays.len(), t_max.len(), "Every ray needs a t_max");
+        rays.par_iter()
+            .zip(t_max)
+            .map(|(ray, t_max)| self.occluded(ray, *t_max))
+            .collect()
+    }
+
+    fn leaf(&self, handle: InstanceHandle) -> Option<u32> {
+        let slot = self.slots.get(handle.index as usize)?;
+        (slot.generation == handle.generation && slot.instance.is_some()).then_some(slot.leaf)
+    }
+
+    fn allocate_node(&mut self, node: DynamicNode) -> u32 {
+        match self.free_nodes.pop() {
+            Some(idx) => {
+                self.nodes[idx as usize] = node;
+                idx
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() as u32 - 1
+            }
+        }
+    }
+
+    fn insert_leaf(&mut self, leaf: u32) {
+        if self.root == NULL_NODE {
+            self.root = leaf;
+            self.nodes[leaf as usize].parent = NULL_NODE;
+            return;
+        }
+
+        let aabb = self.nodes[leaf as usize].aabb;
+        let sibling = self.find_sibling(&aabb);
+        let old_parent = self.nodes[sibling as usize].parent;
+        let mut parent_aabb = self.nodes[sibling as usize].aabb;
+        parent_aabb.grow(&aabb);
+        let parent = self.allocate_node(DynamicNode {
+            aabb: parent_aabb,
+            parent: old_parent,
+            children: [sibling, leaf],
+            instance: NULL_NODE,
+            height: self.nodes[sibling as usize].height + 1,
+        });
+        self.nodes[sibling as usize].parent = parent;
+        self.nodes[leaf as usize].parent = parent;
+        self.replace_child(old_parent, sibling, parent);
+
+        self.refit_ancestors(old_parent);
+    }
+
+    // Branch and bound search for the node whose bounds grow the least, counting
+    // the growth of every ancestor as well. Subtrees are skipped once even a
+    // perfect fit inside them can't beat the best node found so far.
+    fn find_sibling(&self, aabb: &AABB) -> u32 {
+        let area = aabb.area();
+        let mut best = self.root;
+        let mut best_cost = f32::MAX;
+        let mut stack = vec![(self.root, 0.0)];
+        while let Some((idx, inherited_cost)) = stack.pop() {
+            let node = &self.nodes[idx as usize];
+            let mut combined = node.aabb;
+            combined.grow(aabb);
+            let direct_cost = combined.area();
+            if direct_cost + inherited_cost < best_cost {
+                best = idx;
+                best_cost = direct_cost + inherited_cost;
+            }
+
+            let child_cost = inherited_cost + direct_cost - node.aabb.area();
+            if !node.is_leaf() && area + child_cost < best_cost {
+                stack.push((node.children[0], child_cost));
+                stack.push((node.children[1], child_cost));
+            }
+        }
+        best
+    }
+
+    fn remove_leaf(&mut self, leaf: u32) {
+        if leaf == self.root {
+            self.root = NULL_NODE;
+            return;
+        }
+
+        let parent = self.nodes[leaf as usize].parent;
+        let [left, right] = self.nodes[parent as usize].children;
+        let sibling = if left == leaf { right } else { left };
+        let grandparent = self.nodes[parent as usize].parent;
+        self.nodes[sibling as usize].parent = grandparent;
+        self.replace_child(grandparent, parent, sibling);
+        self.free_nodes.push(parent);
+
+        self.refit_ancestors(grandparent);
+    }
+
+    // Points `parent` at `new_child` instead of `old_child`, or makes
+    // `new_child` the root when there is no parent
+    fn replace_child(&mut self, parent: u32, old_child: u32, new_child: u32) {
+        if parent == NULL_NODE {
+            self.root = new_child;
+            return;
+        }
+
+        let children = &mut self.nodes[parent as usize].children;
+        let slot = if children[0] == old_child { 0 } else { 1 };
+        children[slot] = new_child;
+    }
+
+    fn refit_ancestors(&mut self, mut idx: u32) {
+        while idx != NULL_NODE {
+            idx = self.balance(idx);
+            self.update_node(idx);
+            idx = self.nodes[idx as usize].parent;
+        }
+    }
+
+    fn update_node(&mut self, idx: u32) {
+        let [left, right] = self.nodes[idx as usize].children;
+        let left = &self.nodes[left as usize];
+        let right = &self.nodes[right as usize];
+        let mut aabb = left.aabb;
+        aabb.grow(&right.aabb);
+        let height = 1 + left.height.max(right.height);
+
+        let node = &mut self.nodes[idx as usize];
+        node.aabb = aabb;
+        node.height = height;
+    }
+
+    // Rotates the taller child up when the heights of the children differ by
+    // more than one level, which keeps the tree depth logarithmic regardless of
+    // the insertion order. Returns the node now at the position of `idx`.
+    fn balance(&mut self, idx: u32) -> u32 {
+        let node = &self.nodes[idx as usize];
+        if node.is_leaf() || node.height < 2 {
+            return idx;
+        }
+
+        let [left, right] = node.children;
+        let left_height = self.nodes[left as usize].height as i64;
+        let right_height = self.nodes[right as usize].height as i64;
+        if right_height - left_height > 1 {
+            self.rotate_up(idx, 1)
+        } else if left_height - right_height > 1 {
+            self.rotate_up(idx, 0)
+        } else {
+            idx
+        }
+    }
+
+    // Makes the child in `slot` the parent of `idx`. The child keeps its taller
+    // child and hands the other one down to `idx`.
+    fn rotate_up(&mut self, idx: u32, slot: usize) -> u32 {
+        let child = self.nodes[idx as usize].children[slot];
+        let [first, second] = self.nodes[child as usize].children;
+        let (keep, give) = if self.nodes[first as usize].height > self.nodes[second as usize].height
+        {
+            (first, second)
+        } else {
+            (second, first)
+        };
+
+        let parent = self.nodes[idx as usize].parent;
+        self.nodes[child as usize].children = [idx, keep];
+        self.nodes[child as usize].parent = parent;
+        self.nodes[idx as usize].parent = child;
+        self.replace_child(parent, idx, child);
+
+        self.nodes[idx as usize].children[slot] = give;
+        self.nodes[give as usize].parent = idx;
+        self.update_node(idx);
+        self.update_node(child);
+        child
+    }
+}
+
+impl Default for DynamicTlas {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{DynamicTlas, InstanceHandle};
+    use crate::{
+        bvh::Bvh,
+        motion::MotionTransform,
+        testing::{soup, Rng},
+        top_level_acceleration_structure::{Instance, TopLevelAccelerationStructure},
+        types::Mat4,
+    };
+
+    fn random_transform(rng: &mut Rng) -> Mat4 {
+        Mat4::from_translation(rng.vec3(-3.0, 3.0)) * Mat4::from_scale(rng.range(0.2, 0.5))
+    }
+
+    // Checks that the dynamic tree finds the same hits as a tree built from
+    // scratch over the same instances
+    fn assert_matches_rebuild(tlas: &DynamicTlas, rng: &mut Rng) {
+        let instances: Vec<Instance> = tlas.instances().map(|(_, i)| i.clone()).collect();
+        assert_eq!(instances.len(), tlas.len());
+        let rebuilt = TopLevelAccelerationStructure::new(&instances);
+        let mut hits = 0;
+        for _ in 0..300 {
+            let ray = rng.ray().with_time(rng.next());
+            let hit = tlas.traverse(&ray);
+            let expected = rebuilt.traverse(&ray);
+            assert_eq!(
+                (hit.t, hit.primitive_id),
+                (expected.t, expected.primitive_id)
+            );
+            if expected.t < f32::MAX {
+                // The dynamic tree reports handles, the static one positions
+                let handle = tlas.instances().find(|(h, _)| h.index() == hit.object_id);
+                let id = rebuilt.instances()[expected.object_id as usize].id();
+                assert_eq!(handle.unwrap().1.id(), id);
+                hits += 1;
+            }
+            assert_eq!(tlas.occluded(&ray, 5.0), rebuilt.occluded(&ray, 5.0));
+        }
+        assert!(hits > 0);
+    }
+
+    fn assert_thread_safe<T: Send + Sync>() {}
+
+    #[test]
+    fn trees_can_be_shared_between_threads() {
+        assert_thread_safe::<DynamicTlas>();
+        assert_thread_safe::<TopLevelAccelerationStructure>();
+    }
+
+    #[test]
+    fn updates_match_a_rebuild() {
+        let (vertices, indices) = soup(100, 26);
+        let blas = Arc::new(Bvh::new(&vertices, &indices));
+        let mut rng = Rng::new(27);
+        let mut tlas = DynamicTlas::new();
+        let mut handles: Vec<InstanceHandle> = (0..60)
+            .map(|id| {
+                let transform = random_transform(&mut rng);
+                tlas.insert(Instance::new(blas.clone(), id, transform))
+            })
+            .collect();
+        assert_matches_rebuild(&tlas, &mut rng);
+
+        let mut removed = Vec::new();
+        for _ in 0..20 {
+            let handle = handles.swap_remove((rng.next() * handles.len() as f32) as usize);
+            assert!(tlas.remove(handle).is_some());
+            assert!(tlas.remove(handle).is_none());
+            removed.push(handle);
+        }
+        assert_matches_rebuild(&tlas, &mut rng);
+
+        for id in 60..70 {
+            let transform = random_transform(&mut rng);
+            handles.push(tlas.insert(Instance::new(blas.clone(), id, transform)));
+        }
+        // Handles of removed instances stay invalid once their slot is reused
+        for &handle in &removed {
+            assert!(tlas.get(handle).is_none());
+            assert!(!tlas.set_transform(handle, Mat4::from_scale(1.0)));
+        }
+        for &handle in &handles[10..30] {
+            assert!(tlas.set_transform(handle, random_transform(&mut rng)));
+        }
+        for &handle in &handles[30..] {
+            let motion = MotionTransform::linear(vec![
+                random_transform(&mut rng),
+                random_transform(&mut rng),
+            ]);
+            assert!(tlas.set_motion(handle, motion));
+        }
+        assert_matches_rebuild(&tlas, &mut rng);
+
+        for handle in handles {
+            tlas.remove(handle);
+        }
+        assert!(tlas.is_empty());
+        assert_eq!(tlas.traverse(&rng.ray()).t, f32::MAX);
+    }
+}
@@ -0,0 +1,114 @@
+// A hit traversal found but hasn't accepted yet. `instance_id` is the id the
+// instance was created with, 0 when a Bvh is traversed on its own.
+#[derive(Clone, Copy, Debug)]
+pub struct CandidateHit {
+    pub instance_id: u32,
+    pub primitive_id: u32,
+    pub u: f32,
+    pub v: f32,
+}
+
+// Decides which hits traversal may report, for example to let alpha tested
+// cutouts reject hits on transparent texels. Called for every candidate closer
+// than the current closest hit, in no particular order, so it may be called
+// more than once per ray and primitive. Implemented for closures.
+pub trait HitFilter {
+    fn accept(&self, hit: &CandidateHit) -> bool;
+}
+
+impl<F: Fn(&CandidateHit) -> bool> HitFilter for F {
+    fn accept(&self, hit: &CandidateHit) -> bool {
+        self(hit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::SquareMatrix;
+
+    use super::CandidateHit;
+    use crate::{
+        bvh::Bvh,
+        intersect::intersect_triangle,
+        multi_hit::HitList,
+        packet::RayPacket16,
+        testing::{soup, Rng},
+        types::{HitRecord, Mat4, Ray, Vec3},
+    };
+
+    // Cuts every third triangle in half along u, like an alpha tested leaf
+    fn cutout(hit: &CandidateHit) -> bool {
+        !hit.primitive_id.is_multiple_of(3) || hit.u < 0.5
+    }
+
+    // Distances to every triangle along the ray that `cutout` accepts, sorted
+    fn accepted_hits(vertices: &[Vec3], indices: &[u32], ray: &Ray) -> Vec<(f32, u32)> {
+        let mut hits = Vec::new();
+        for (primitive_id, triangle) in indices.chunks(3).enumerate() {
+            let [v0, v1, v2] = [0, 1, 2].map(|i| vertices[triangle[i] as usize]);
+            let (mut t, mut u, mut v) = (0.0, 0.0, 0.0);
+            let candidate = |u, v| CandidateHit {
+                instance_id: 0,
+                primitive_id: primitive_id as u32,
+                u,
+                v,
+            };
+            if intersect_triangle(ray, &v0, &v1, &v2, &mut t, &mut u, &mut v)
+                && cutout(&candidate(u, v))
+            {
+                hits.push((t, primitive_id as u32));
+            }
+        }
+        hits.sort_by(|a, b| a.0.total_cmp(&b.0));
+        hits
+    }
+
+    #[test]
+    fn filters_reject_hits() {
+        let (vertices, indices) = soup(400, 24);
+        let bvh = Bvh::new(&vertices, &indices);
+        let identity = Mat4::identity();
+        let close = |t: f32, expected: f32| t == expected || (t - expected).abs() < expected * 1e-5;
+
+        let mut rng = Rng::new(25);
+        let mut rejected = 0;
+        for _ in 0..50 {
+            let rays: Vec<_> = (0..16).map(|_| rng.ray()).collect();
+            let mut packed = [HitRecord::new(); 16];
+            bvh.traverse_packet_filtered(
+                &RayPacket16::from_rays(&rays),
+                &identity,
+                &cutout,
+                &mut packed,
+            );
+
+            for (ray, packed) in rays.iter().zip(&packed) {
+                let expected = accepted_hits(&vertices, &indices, ray);
+                let (t, primitive_id) = expected.first().copied().unwrap_or((f32::MAX, 0));
+                let mut unfiltered = HitRecord::new();
+                bvh.traverse(ray, &identity, &mut unfiltered);
+                if unfiltered.t != t {
+                    rejected += 1;
+                }
+
+                let mut hit = HitRecord::new();
+                bvh.traverse_filtered(ray, &identity, &cutout, &mut hit);
+                assert!(close(hit.t, t) && close(packed.t, t));
+                if t < f32::MAX {
+                    assert_eq!(hit.primitive_id, primitive_id);
+                    assert_eq!(packed.primitive_id, primitive_id);
+                }
+                assert_eq!(
+                    bvh.occluded_filtered(ray, &identity, &cutout, f32::MAX),
+                    t < f32::MAX
+                );
+
+                let mut all = HitList::all();
+                bvh.traverse_all_filtered(ray, &identity, &cutout, &mut all);
+                let all: Vec<_> = all.into_hits().iter().map(|hit| hit.primitive_id).collect();
+                assert_eq!(all, expected.iter().map(|hit| hit.1).collect::<Vec<_>>());
+            }
+        }
+        assert!(rejected > 10);
+    }
+}
@@ -2,9 +2,12 @@ use std::rc::Rc;
 
 use vk_utils::{buffer_resource::BufferResource, device_context::DeviceContext};
 
-use crate::types::AABB;
+use crate::{intersect::CullMode, types::AABB};
 
-use super::{procedural_blas::ProceduralGeometry, triangle_blas::TriangleGeometry};
+use super::{
+    procedural_blas::ProceduralGeometry,
+    triangle_blas::{NodeLayout, TriangleGeometry},
+};
 
 pub enum Geometry {
     Triangle(TriangleGeometry),
@@ -26,6 +29,14 @@ impl Geometry {
         }
     }
 
+    // Procedural geometry has no sides, so it is never culled
+    pub fn cull_mode(&self) -> CullMode {
+        match self {
+            Geometry::Triangle(triangle) => triangle.cull_mode(),
+            Geometry::Procedural(_) => CullMode::None,
+        }
+    }
+
     pub fn new_triangles(
         device: Rc<DeviceContext>,
         vertex_buffer: &BufferResource,
@@ -34,6 +45,20 @@ impl Geometry {
         Geometry::Triangle(TriangleGeometry::new(device, vertex_buffer, index_buffer))
     }
 
+    pub fn new_triangles_with_layout(
+        device: Rc<DeviceContext>,
+        vertex_buffer: &BufferResource,
+        index_buffer: &BufferResource,
+        layout: NodeLayout,
+    ) -> Self {
+        Geometry::Triangle(TriangleGeometry::new_with_layout(
+            device,
+            vertex_buffer,
+            index_buffer,
+            layout,
+        ))
+    }
+
     pub fn new_procedural(aabb: AABB, intersection_function_offset: u32) -> Self {
         Geometry::Procedural(ProceduralGeometry::new(aabb, intersection_function_offset))
     }
@@ -7,10 +7,15 @@ use vk_utils::{
 
 use crate::{
     bvh::Node,
+    intersect::CullMode,
     types::{Mat4, AABB},
 };
 
-use super::{blas::Geometry, instance::Instance};
+use super::{
+    blas::Geometry,
+    instance::Instance,
+    triangle_blas::{NodeLayout, COMPRESSED_NODES_FLAG, CULL_BACK_FLAG, CULL_FRONT_FLAG},
+};
 
 pub struct GpuTlas {
     pub tlas_buffer: BufferResource,
@@ -39,10 +44,20 @@ impl GpuTlas {
             .map(|proxy| GpuInstance {
                 blas: proxy.address(),
                 instance_id: proxy.id(),
-                flags: if let Geometry::Procedural(p) = proxy.blas() {
-                    p.intersection_function_offset()
-                } else {
-                    0
+                flags: match proxy.blas() {
+                    Geometry::Procedural(p) => p.intersection_function_offset(),
+                    Geometry::Triangle(t) => {
+                        let layout = match t.layout() {
+                            NodeLayout::Compressed => COMPRESSED_NODES_FLAG,
+                            NodeLayout::Uncompressed => 0,
+                        };
+                        let cull_mode = match proxy.cull_mode() {
+                            CullMode::None => 0,
+                            CullMode::Back => CULL_BACK_FLAG,
+                            CullMode::Front => CULL_FRONT_FLAG,
+                        };
+                        layout | cull_mode
+                    }
                 },
                 transform: *proxy.transform(),
             })
@@ -5,7 +5,7 @@ use vk_utils::{
     pipeline_descriptor::ComputePipeline, BufferUsageFlags, MemoryPropertyFlags,
 };
 
-use crate::types::Ray;
+use crate::types::{Direction, HdrColor, Origin};
 
 use super::frame_data::FrameData;
 
@@ -76,7 +76,7 @@ impl GpuRayGenerator {
     }
 
     pub fn ray_buffer_size(&self, frame_data: &FrameData) -> usize {
-        frame_data.width * frame_data.height * size_of::<Ray>()
+        frame_data.width * frame_data.height * size_of::<GpuRay>()
     }
 
     pub fn allocate_ray_buffer(
@@ -122,3 +122,13 @@ impl GpuRayGenerator {
         self.pipeline.set_storage_buffer(0, 0, ray_buffer);
     }
 }
+
+// Layout of the rays in the ray buffer. Only carries what the shaders write,
+// the traversal interval and time of `Ray` stay on the cpu.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct GpuRay {
+    pub origin: Origin,
+    pub direction: Direction,
+    pub color: HdrColor,
+}
@@ -4,6 +4,8 @@ use vk_utils::{
     pipeline_descriptor::ComputePipeline, BufferUsageFlags, MemoryPropertyFlags,
 };
 
+use crate::{intersect::TriangleIntersection, types::Vec3};
+
 use super::{frame_data::FrameData, gpu_acceleration_structure::GpuTlas};
 
 pub struct IntersectionFunction {
@@ -31,6 +33,7 @@ impl GpuIntersector {
         ray_struct: &str,
         intersection_struct: &str,
         intersection_table: &[IntersectionFunction],
+        triangle_intersection: TriangleIntersection,
         _max_frames_in_flight: usize,
     ) -> Self {
         let shader_path = std::env::current_dir()
@@ -50,6 +53,15 @@ impl GpuIntersector {
         let shader = shader.replace("___CUSTOM_INTERSECTION_FUNCTIONS___", &intersection_code);
         let shader = shader.replace("___RAY_STRUCT___", ray_struct);
         let shader = shader.replace("___INTERSECTION_STRUCT___", intersection_struct);
+        let shader = match triangle_intersection {
+            TriangleIntersection::MollerTrumbore => {
+                shader.replace("___TRIANGLE_INTERSECTION___", "")
+            }
+            TriangleIntersection::Watertight => shader.replace(
+                "___TRIANGLE_INTERSECTION___",
+                "#define WATERTIGHT_TRIANGLES",
+            ),
+        };
 
         let shader = if !intersection_table.is_empty() {
             let cases = "match(instance_flags) {\n".to_string()
@@ -135,4 +147,8 @@ pub struct IntersectionResult {
     pub v: f32,
     instance: u32,
     primitive: u32,
+    // See the fields of the same name in `HitRecord`
+    pub position: Vec3,
+    pub geometric_normal: Vec3,
+    pub position_error: Vec3,
 }
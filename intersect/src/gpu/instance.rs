@@ -2,7 +2,10 @@ use std::rc::Rc;
 
 use cgmath::SquareMatrix;
 
-use crate::types::{Mat4, AABB};
+use crate::{
+    intersect::CullMode,
+    types::{Mat4, AABB},
+};
 
 use super::blas::Geometry;
 
@@ -10,6 +13,7 @@ pub struct Instance {
     blas: Rc<Geometry>,
     id: u32,
     transform: Mat4,
+    cull_mode: Option<CullMode>,
 }
 
 impl Instance {
@@ -18,6 +22,7 @@ impl Instance {
             blas,
             id,
             transform: Mat4::identity(),
+            cull_mode: None,
         }
     }
 
@@ -26,6 +31,12 @@ impl Instance {
         self
     }
 
+    // Overrides the cull mode of the geometry for this instance only
+    pub fn with_cull_mode(mut self, cull_mode: CullMode) -> Self {
+        self.cull_mode = Some(cull_mode);
+        self
+    }
+
     pub fn transform(&self) -> &Mat4 {
         &self.transform
     }
@@ -38,6 +49,11 @@ impl Instance {
         self.id
     }
 
+    // The override if there is one, otherwise the cull mode of the geometry
+    pub fn cull_mode(&self) -> CullMode {
+        self.cull_mode.unwrap_or_else(|| self.blas.cull_mode())
+    }
+
     pub fn aabb(&self) -> &AABB {
         match self.blas.as_ref() {
             Geometry::Triangle(triangle) => triangle.aabb(),
@@ -65,6 +65,9 @@ impl RayTracingPipeline {
             float v;
             uint instance_id;
             uint primitive_id;
+            vec3 position;
+            vec3 geometric_normal;
+            vec3 position_error;
         "
         .to_owned();
 
@@ -101,6 +104,7 @@ impl RayTracingPipeline {
             &ray_struct,
             &intersection_struct,
             &[],
+            descriptor.triangle_intersection,
             descriptor.max_frames_in_flight as usize,
         );
 
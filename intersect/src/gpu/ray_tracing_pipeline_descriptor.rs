@@ -1,6 +1,6 @@
 use std::{collections::HashMap, path::PathBuf};
 
-use crate::types::DataType;
+use crate::{intersect::TriangleIntersection, types::DataType};
 
 pub enum ShaderSource {
     File(PathBuf),
@@ -47,6 +47,8 @@ pub struct RayTracingPipelineDescriptor {
     pub ray_shader_source: ShaderSource,
     pub max_frames_in_flight: u32,
     pub intersection_functions: Vec<ShaderSource>,
+    // Triangle test used by the generated intersector
+    pub triangle_intersection: TriangleIntersection,
 }
 
 impl RayTracingPipelineDescriptor {
@@ -58,6 +60,7 @@ impl RayTracingPipelineDescriptor {
             ray_shader_source,
             max_frames_in_flight: 1,
             intersection_functions: Vec::new(),
+            triangle_intersection: TriangleIntersection::default(),
         }
     }
 
@@ -86,6 +89,14 @@ impl RayTracingPipelineDescriptor {
         self
     }
 
+    pub fn with_triangle_intersection(
+        mut self,
+        triangle_intersection: TriangleIntersection,
+    ) -> Self {
+        self.triangle_intersection = triangle_intersection;
+        self
+    }
+
     pub fn ray_payload_descriptor(&self) -> &PayloadDescriptor {
         &self.ray_payload_descriptor
     }
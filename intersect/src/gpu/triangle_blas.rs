@@ -5,12 +5,48 @@ use vk_utils::{
     MemoryPropertyFlags,
 };
 
-use crate::{bvh::Bvh, types::AABB};
+use crate::{
+    bvh::{Bvh, BvhBuildOptions, Node},
+    compressed_bvh::{CompressedBvh, CompressedNode},
+    intersect::CullMode,
+    primitive::TriangleMesh,
+    types::{Vertex, AABB},
+};
+
+// Set in the instance flags of geometry uploaded with `NodeLayout::Compressed`
+pub const COMPRESSED_NODES_FLAG: u32 = 1 << 31;
+// Set in the instance flags of triangle instances culling back or front faces
+pub const CULL_BACK_FLAG: u32 = 1 << 30;
+pub const CULL_FRONT_FLAG: u32 = 1 << 29;
+
+// Byte offset of the nodes in the blas buffer, after the vertex, index and
+// triangle buffer addresses
+const NODE_OFFSET: usize = 24;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum NodeLayout {
+    // `bvh::Node`s with full precision bounds
+    Uncompressed,
+    // The root bounds followed by `CompressedNode`s with 8 bit child bounds
+    Compressed,
+}
 
+// The mesh itself lives in the vertex and index buffers on the gpu. Only the
+// parts of the bvh needed to refit it are kept on the host.
 pub struct TriangleGeometry {
+    nodes: Vec<Node>,
+    primitives: Vec<u32>,
+    options: BvhBuildOptions,
+    build_cost: f32,
+    cull_mode: CullMode,
     aabb: AABB,
-    _vertex_buffer: u64,
-    _index_buffer: u64,
+    layout: NodeLayout,
+    // The buffers the shader reads the mesh from and their sizes, refits have to
+    // read the same ones
+    vertex_buffer: u64,
+    index_buffer: u64,
+    vertex_count: usize,
+    index_count: usize,
     _triangle_buffer: BufferResource,
     blas_buffer: BufferResource,
 }
@@ -21,35 +57,150 @@ impl TriangleGeometry {
         vertex_buffer: &BufferResource,
         index_buffer: &BufferResource,
     ) -> Self {
-        let bvh = Bvh::new(&vertex_buffer.copy_data(), &index_buffer.copy_data());
+        Self::new_with_layout(
+            device,
+            vertex_buffer,
+            index_buffer,
+            NodeLayout::Uncompressed,
+        )
+    }
+
+    pub fn new_with_layout(
+        device: Rc<DeviceContext>,
+        vertex_buffer: &BufferResource,
+        index_buffer: &BufferResource,
+        layout: NodeLayout,
+    ) -> Self {
+        let vertices: Vec<Vertex> = vertex_buffer.copy_data();
+        let indices: Vec<u32> = index_buffer.copy_data();
+        let bvh = Bvh::new(&vertices, &indices);
+        // The shader reads triangles as offsets into the index buffer
+        let triangles: Vec<u32> = bvh.primitives().iter().map(|p| p * 3).collect();
         let mut triangle_buffer = BufferResource::new(
             device.clone(),
-            std::mem::size_of_val(bvh.triangles()),
+            std::mem::size_of_val(triangles.as_slice()),
             MemoryPropertyFlags::HOST_VISIBLE,
             BufferUsageFlags::STORAGE_BUFFER | BufferUsageFlags::SHADER_DEVICE_ADDRESS,
         );
-        triangle_buffer.upload(bvh.triangles());
+        triangle_buffer.upload(&triangles);
 
         let mut blas_buffer = BufferResource::new(
             device,
-            bvh.size() + 24,
+            NODE_OFFSET + Self::node_size(bvh.nodes().len(), layout),
             MemoryPropertyFlags::HOST_VISIBLE,
             BufferUsageFlags::STORAGE_BUFFER | BufferUsageFlags::SHADER_DEVICE_ADDRESS,
         );
         blas_buffer.upload(&[vertex_buffer.device_address()]);
         blas_buffer.upload_at(8, &[index_buffer.device_address()]);
         blas_buffer.upload_at(16, &[triangle_buffer.device_address()]);
-        blas_buffer.upload_at(24, bvh.nodes());
+        Self::upload_nodes(&mut blas_buffer, &bvh.nodes(), layout);
 
         Self {
+            nodes: bvh.nodes().into_owned(),
+            primitives: bvh.primitives().to_vec(),
+            options: *bvh.options(),
+            build_cost: bvh.build_cost(),
+            cull_mode: bvh.cull_mode(),
             aabb: *bvh.aabb(),
-            _vertex_buffer: vertex_buffer.device_address(),
-            _index_buffer: index_buffer.device_address(),
+            layout,
+            vertex_buffer: vertex_buffer.device_address(),
+            index_buffer: index_buffer.device_address(),
+            vertex_count: vertices.len(),
+            index_count: indices.len(),
             _triangle_buffer: triangle_buffer,
             blas_buffer,
         }
     }
 
+    // Refits the nodes to the current contents of the buffers this geometry was
+    // created with and rewrites them in place. The mesh is read back from the
+    // buffers for the refit, only the topology is kept between refits. Instances
+    // referencing this geometry need a new GpuTlas afterwards since the bounds
+    // changed. Panics when given other buffers or a different number of vertices
+    // or triangles.
+    pub fn refit(&mut self, vertex_buffer: &BufferResource, index_buffer: &BufferResource) -> f32 {
+        assert!(
+            vertex_buffer.device_address() == self.vertex_buffer
+                && index_buffer.device_address() == self.index_buffer,
+            "Refit with buffers the geometry wasn't created with"
+        );
+        let vertices: Vec<Vertex> = vertex_buffer.copy_data();
+        let indices: Vec<u32> = index_buffer.copy_data();
+        assert!(
+            vertices.len() == self.vertex_count && indices.len() == self.index_count,
+            "Refit changed the number of vertices or triangles"
+        );
+
+        let geometry = TriangleMesh::from_storage(vertices.into(), indices.into());
+        let mut bvh = Bvh::from_parts(
+            geometry,
+            self.primitives.clone().into(),
+            std::mem::take(&mut self.nodes).into(),
+            self.options,
+            self.build_cost,
+        );
+        let degradation = bvh.refit_bounds();
+        self.nodes = bvh.nodes().into_owned();
+        self.aabb = *bvh.aabb();
+        Self::upload_nodes(&mut self.blas_buffer, &self.nodes, self.layout);
+        degradation
+    }
+
+    fn node_size(node_count: usize, layout: NodeLayout) -> usize {
+        match layout {
+            NodeLayout::Uncompressed => std::mem::size_of::<Node>() * node_count,
+            NodeLayout::Compressed => {
+                std::mem::size_of::<AABB>() + std::mem::size_of::<CompressedNode>() * node_count
+            }
+        }
+    }
+
+    fn upload_nodes(blas_buffer: &mut BufferResource, nodes: &[Node], layout: NodeLayout) {
+        match layout {
+            NodeLayout::Uncompressed => blas_buffer.upload_at(NODE_OFFSET, nodes),
+            NodeLayout::Compressed => {
+                let compressed = CompressedBvh::new(nodes);
+                blas_buffer.upload_at(NODE_OFFSET, &[*compressed.aabb()]);
+                blas_buffer.upload_at(
+                    NODE_OFFSET + std::mem::size_of::<AABB>(),
+                    compressed.nodes(),
+                );
+            }
+        }
+    }
+
+    // Sides of the triangles rays can't hit, unless an instance overrides it
+    pub fn with_cull_mode(mut self, cull_mode: CullMode) -> Self {
+        self.cull_mode = cull_mode;
+        self
+    }
+
+    pub fn set_cull_mode(&mut self, cull_mode: CullMode) {
+        self.cull_mode = cull_mode;
+    }
+
+    pub fn cull_mode(&self) -> CullMode {
+        self.cull_mode
+    }
+
+    pub fn layout(&self) -> NodeLayout {
+        self.layout
+    }
+
+    // Bytes used by the nodes on the gpu
+    pub fn size(&self) -> usize {
+        Self::node_size(self.nodes.len(), self.layout)
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    // Triangle indices in the order the leaves reference them
+    pub fn primitives(&self) -> &[u32] {
+        &self.primitives
+    }
+
     pub fn aabb(&self) -> &AABB {
         &self.aabb
     }
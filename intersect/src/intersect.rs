@@ -1,6 +1,6 @@
 use cgmath::InnerSpace;
 
-use crate::types::{Ray, Vertex, AABB};
+use crate::types::{Ray, Vec3, Vertex, AABB};
 
 pub fn intersect_aabb(aabb: &AABB, ray: &Ray, t_far: f32) -> f32 {
     let inv_direction = ray.direction.map(|x| 1.0 / x);
@@ -20,7 +20,8 @@ pub fn intersect_aabb(aabb: &AABB, ray: &Ray, t_far: f32) -> f32 {
     let t_min = t_min.max(tz1.min(tz2));
     let t_max = t_max.min(tz1.max(tz2));
 
-    let hit = t_max >= t_min && t_min < t_far && t_max > 0.0;
+    // Hit when the slab interval overlaps both the ray interval and `t_far`
+    let hit = t_max >= t_min && t_min < t_far.min(ray.t_max) && t_max > ray.t_min;
     if hit {
         t_min.min(t_max)
     } else {
@@ -59,5 +60,376 @@ pub fn intersect_triangle(
     }
 
     *t = f * edge2.dot(q);
-    *t > 0.000001
+    *t > ray.t_min.max(0.000001) && *t < ray.t_max
+}
+
+// Which ray-triangle test triangle meshes use. Möller-Trumbore is the fastest but
+// skips rays nearly parallel to a triangle and can let rays slip through the
+// edge shared by two triangles. The watertight test never misses where
+// triangles share an edge or vertex, at a small cost.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum TriangleIntersection {
+    #[default]
+    MollerTrumbore,
+    Watertight,
+}
+
+// Which side of a triangle rays may not hit. The front is the side the normal
+// `(v1 - v0) x (v2 - v0)` points to in object space, so instances with a
+// mirroring transform cull the same triangles.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum CullMode {
+    #[default]
+    None,
+    Back,
+    Front,
+}
+
+impl CullMode {
+    // Whether a ray travelling in `direction` reaches a triangle with `normal`
+    // from a culled side
+    #[inline]
+    pub fn culls(&self, normal: Vec3, direction: Vec3) -> bool {
+        match self {
+            CullMode::None => false,
+            CullMode::Back => direction.dot(normal) > 0.0,
+            CullMode::Front => direction.dot(normal) < 0.0,
+        }
+    }
+}
+
+// Watertight ray-triangle test after Woop, Benthin and Wald, "Watertight
+// Ray/Triangle Intersection". The vertices are moved into a space where the ray
+// points along +z from the origin, so the edge tests only depend on the edge
+// and always agree between the two triangles sharing it. Edge functions that
+// come out as exactly zero are recomputed in double precision. Same outputs as
+// `intersect_triangle`.
+pub fn intersect_triangle_watertight(
+    ray: &Ray,
+    v0: &Vertex,
+    v1: &Vertex,
+    v2: &Vertex,
+    t: &mut f32,
+    u: &mut f32,
+    v: &mut f32,
+) -> bool {
+    let d = ray.direction;
+    let kz = if d.x.abs() > d.y.abs() {
+        if d.x.abs() > d.z.abs() {
+            0
+        } else {
+            2
+        }
+    } else if d.y.abs() > d.z.abs() {
+        1
+    } else {
+        2
+    };
+    let mut kx = (kz + 1) % 3;
+    let mut ky = (kx + 1) % 3;
+    // Keeps the winding of the triangle
+    if d[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+
+    let sx = d[kx] / d[kz];
+    let sy = d[ky] / d[kz];
+    let sz = 1.0 / d[kz];
+
+    let a = v0 - ray.origin;
+    let b = v1 - ray.origin;
+    let c = v2 - ray.origin;
+    let ax = a[kx] - sx * a[kz];
+    let ay = a[ky] - sy * a[kz];
+    let bx = b[kx] - sx * b[kz];
+    let by = b[ky] - sy * b[kz];
+    let cx = c[kx] - sx * c[kz];
+    let cy = c[ky] - sy * c[kz];
+
+    let mut e0 = cx * by - cy * bx;
+    let mut e1 = ax * cy - ay * cx;
+    let mut e2 = bx * ay - by * ax;
+    if e0 == 0.0 || e1 == 0.0 || e2 == 0.0 {
+        let edge = |px: f32, py: f32, qx: f32, qy: f32| {
+            (px as f64 * qy as f64 - py as f64 * qx as f64) as f32
+        };
+        e0 = edge(cx, cy, bx, by);
+        e1 = edge(ax, ay, cx, cy);
+        e2 = edge(bx, by, ax, ay);
+    }
+
+    if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
+        return false;
+    }
+    let det = e0 + e1 + e2;
+    if det == 0.0 {
+        return false;
+    }
+
+    let az = sz * a[kz];
+    let bz = sz * b[kz];
+    let cz = sz * c[kz];
+    let inv_det = 1.0 / det;
+    *t = (e0 * az + e1 * bz + e2 * cz) * inv_det;
+    *u = e1 * inv_det;
+    *v = e2 * inv_det;
+    *t > ray.t_min.max(0.000001) && *t < ray.t_max
+}
+
+// Point of the triangle closest to `p` and its barycentrics (u, v), using the
+// same convention as `intersect_triangle`: the point is
+// `v0 + u * (v1 - v0) + v * (v2 - v0)`. Works out which feature of the triangle
+// is closest by the Voronoi region `p` lies in.
+pub fn closest_point_on_triangle(
+    p: &Vec3,
+    v0: &Vertex,
+    v1: &Vertex,
+    v2: &Vertex,
+) -> (Vec3, f32, f32) {
+    let ab = v1 - v0;
+    let ac = v2 - v0;
+    let ap = p - v0;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return (*v0, 0.0, 0.0);
+    }
+
+    let bp = p - v1;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return (*v1, 1.0, 0.0);
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        let u = d1 / (d1 - d3);
+        return (v0 + ab * u, u, 0.0);
+    }
+
+    let cp = p - v2;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return (*v2, 0.0, 1.0);
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        let v = d2 / (d2 - d6);
+        return (v0 + ac * v, 0.0, v);
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        let w = (d4 - d3) / ((d4 - d3) + (d5 - d6));
+        return (v1 + (v2 - v1) * w, 1.0 - w, w);
+    }
+
+    let denominator = 1.0 / (va + vb + vc);
+    let u = vb * denominator;
+    let v = vc * denominator;
+    (v0 + ab * u + ac * v, u, v)
+}
+
+// Exact triangle-box overlap using the separating axis theorem: the box axes,
+// the triangle normal and the nine cross products of box axes and edges
+pub fn overlap_triangle_aabb(v0: &Vertex, v1: &Vertex, v2: &Vertex, aabb: &AABB) -> bool {
+    let center = (aabb.min + aabb.max) * 0.5;
+    let half = (aabb.max - aabb.min) * 0.5;
+    let v = [v0 - center, v1 - center, v2 - center];
+    let separated = |axis: Vec3| {
+        let p = v.map(|v| v.dot(axis));
+        let r = half.x * axis.x.abs() + half.y * axis.y.abs() + half.z * axis.z.abs();
+        p[0].min(p[1]).min(p[2]) > r || p[0].max(p[1]).max(p[2]) < -r
+    };
+
+    let unit = [Vec3::unit_x(), Vec3::unit_y(), Vec3::unit_z()];
+    if unit.iter().any(|axis| separated(*axis)) {
+        return false;
+    }
+
+    let edges = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+    if separated(edges[0].cross(edges[1])) {
+        return false;
+    }
+
+    !edges
+        .iter()
+        .any(|edge| unit.iter().any(|axis| separated(axis.cross(*edge))))
+}
+
+// Exact triangle-triangle overlap using the separating axis theorem. Besides the
+// normals and the nine edge cross products, the edge normals within each plane
+// are tested so coplanar triangles are handled too. Touching counts as overlap.
+pub fn intersect_triangles(a: &[Vertex; 3], b: &[Vertex; 3]) -> bool {
+    let separated = |axis: Vec3| {
+        let pa = a.map(|v| v.dot(axis));
+        let pb = b.map(|v| v.dot(axis));
+        pa[0].max(pa[1]).max(pa[2]) < pb[0].min(pb[1]).min(pb[2])
+            || pb[0].max(pb[1]).max(pb[2]) < pa[0].min(pa[1]).min(pa[2])
+    };
+
+    let edges_a = [a[1] - a[0], a[2] - a[1], a[0] - a[2]];
+    let edges_b = [b[1] - b[0], b[2] - b[1], b[0] - b[2]];
+    let normal_a = edges_a[0].cross(edges_a[1]);
+    let normal_b = edges_b[0].cross(edges_b[1]);
+    if separated(normal_a) || separated(normal_b) {
+        return false;
+    }
+
+    let crossed = edges_a
+        .iter()
+        .any(|ea| edges_b.iter().any(|eb| separated(ea.cross(*eb))));
+    let in_plane = edges_a.iter().any(|e| separated(normal_a.cross(*e)))
+        || edges_b.iter().any(|e| separated(normal_b.cross(*e)));
+    !crossed && !in_plane
+}
+
+// Bound on the relative rounding error of `n` consecutive floating point
+// operations, as in Physically Based Rendering
+pub fn gamma(n: u32) -> f32 {
+    let e = n as f32 * f32::EPSILON * 0.5;
+    e / (1.0 - e)
+}
+
+// Origin for a ray leaving a surface in `direction`. The hit point is pushed
+// along the geometric normal past its error bound, to the side `direction`
+// points to, and rounded away from the surface so the new ray can't hit it
+// again. `error` is the absolute error of `position` on every axis.
+pub fn offset_ray_origin(position: Vec3, error: Vec3, normal: Vec3, direction: Vec3) -> Vec3 {
+    let distance = normal.map(f32::abs).dot(error);
+    let mut offset = normal * distance;
+    if direction.dot(normal) < 0.0 {
+        offset = -offset;
+    }
+
+    let mut origin = position + offset;
+    for axis in 0..3 {
+        if offset[axis] > 0.0 {
+            origin[axis] = origin[axis].next_up();
+        } else if offset[axis] < 0.0 {
+            origin[axis] = origin[axis].next_down();
+        }
+    }
+    origin
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{InnerSpace, Rad, SquareMatrix};
+
+    use crate::{
+        bvh::Bvh,
+        cube::Cube,
+        intersect::TriangleIntersection,
+        testing::{soup, Rng},
+        types::{HitRecord, Mat4, Ray, Vec3},
+    };
+
+    // Fires rays from outside at points on every triangle edge of the cube,
+    // including the diagonals splitting each face and the corners. Every ray has
+    // to hit where it was aimed, no matter how small the cube is.
+    fn assert_closed(scale: f32) {
+        let cube = Cube::new();
+        let vertices: Vec<Vec3> = cube.vertices().iter().map(|v| v * scale).collect();
+        let mut bvh = Bvh::new(&vertices, cube.indices());
+        bvh.set_triangle_intersection(TriangleIntersection::Watertight);
+
+        let jitter = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(0.3, -0.2, 0.1),
+            Vec3::new(-0.15, 0.35, -0.25),
+        ];
+        for triangle in cube.indices().chunks(3) {
+            for edge in 0..3 {
+                let a = vertices[triangle[edge] as usize];
+                let b = vertices[triangle[(edge + 1) % 3] as usize];
+                for s in [0.0, 0.25, 0.5, 0.77, 1.0] {
+                    let target = a + (b - a) * s;
+                    // Sum of the normals of the cube faces the point lies on, so
+                    // the ray enters all of them
+                    let outward = target.map(|x| if x.abs() == scale { x.signum() } else { 0.0 });
+                    for offset in &jitter {
+                        let origin = target + (outward * 3.0 + offset) * scale;
+                        let ray = Ray::new(origin, (target - origin).normalize());
+                        let mut hit = HitRecord::new();
+                        bvh.traverse(&ray, &Mat4::identity(), &mut hit);
+                        let distance = (target - origin).magnitude();
+                        assert!(
+                            (hit.t - distance).abs() < distance * 1e-4,
+                            "Ray at {:?} hit at {} instead of {}",
+                            target,
+                            hit.t,
+                            distance
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn watertight_hits_shared_edges_and_vertices() {
+        assert_closed(1.0);
+    }
+
+    #[test]
+    fn watertight_hits_small_triangles() {
+        assert_closed(0.001);
+    }
+
+    // Spawns rays in random directions on both sides of every hit, far from the
+    // origin and through a rotated, non-uniformly scaled transform where the
+    // rounding error in the hit position is large. None of them may hit the
+    // triangle they start on, and on the closed cube rays leaving outwards and
+    // shadow rays back to the camera can't hit anything at all.
+    fn assert_no_self_hits(bvh: &Bvh, transform: &Mat4, closed: bool) {
+        let center = (transform * Vec3::new(0.0, 0.0, 0.0).extend(1.0)).truncate();
+        let mut rng = Rng::new(22);
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let origin = center + rng.vec3(-1.0, 1.0).normalize() * 40.0;
+            let target = center + rng.vec3(-1.0, 1.0);
+            let ray = Ray::new(origin, (target - origin).normalize());
+            let mut hit = HitRecord::new();
+            bvh.traverse(&ray, transform, &mut hit);
+            if hit.t == f32::MAX {
+                continue;
+            }
+            hits += 1;
+
+            for _ in 0..4 {
+                let direction = rng.vec3(-1.0, 1.0).normalize();
+                let mut again = HitRecord::new();
+                bvh.traverse(&hit.spawn_ray(direction), transform, &mut again);
+                assert!(again.t == f32::MAX || again.primitive_id != hit.primitive_id);
+                let outwards = direction.dot(hit.geometric_normal)
+                    * ray.direction.dot(hit.geometric_normal)
+                    < 0.0;
+                if closed && outwards {
+                    assert_eq!(again.t, f32::MAX);
+                }
+            }
+            if closed {
+                assert!(!bvh.occluded(&hit.spawn_ray_to(origin), transform, f32::MAX));
+            }
+        }
+        assert!(hits > 500);
+    }
+
+    #[test]
+    fn offset_rays_leave_the_surface() {
+        let transform = Mat4::from_translation(Vec3::new(3000.0, -1700.0, 900.0))
+            * Mat4::from_axis_angle(Vec3::new(1.0, 2.0, 3.0).normalize(), Rad(0.7))
+            * Mat4::from_nonuniform_scale(2.0, 0.5, 1.3);
+        let cube = Cube::new();
+        let cube = Bvh::new(cube.vertices(), cube.indices());
+        assert_no_self_hits(&cube, &transform, true);
+
+        let (vertices, indices) = soup(500, 22);
+        let bvh = Bvh::new(&vertices, &indices);
+        assert_no_self_hits(&bvh, &transform, false);
+    }
 }
@@ -1,20 +1,36 @@
+pub mod builder;
 pub mod bvh;
 pub mod camera;
+pub mod compressed_bvh;
 pub mod cpu;
 pub mod cube;
+pub mod dynamic_tlas;
+pub mod filter;
 pub mod frame_buffer;
 pub mod gpu;
 pub mod intersect;
 pub mod material;
+pub mod motion;
+pub mod multi_hit;
+pub mod packet;
+pub mod primitive;
+pub mod query;
+pub mod ray_stream;
 pub mod scene;
+pub mod serialize;
+pub mod stats;
 pub mod top_level_acceleration_structure;
 pub mod types;
+pub mod wide_bvh;
+
+#[cfg(test)]
+mod testing;
 
 use std::io::BufRead;
 
-use gpu::gpu_ray_intersector::IntersectionResult;
+use gpu::{gpu_ray_generator::GpuRay, gpu_ray_intersector::IntersectionResult};
 use image::ColorType;
-use types::{Ray, Vertex};
+use types::Vertex;
 
 use crate::{frame_buffer::Framebuffer, types::HdrColor};
 
@@ -123,7 +139,7 @@ pub fn write_intersection_buffer_to_file(
         .expect("Image write failed");
 }
 
-pub fn write_ray_buffer_to_file(name: &str, buffer: &[Ray], width: usize, height: usize) {
+pub fn write_ray_buffer_to_file(name: &str, buffer: &[GpuRay], width: usize, height: usize) {
     let pixels: Vec<u8> = buffer
         .iter()
         .flat_map(|result| {
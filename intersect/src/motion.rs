@@ -0,0 +1,214 @@
+use cgmath::{ElementWise, InnerSpace, Quaternion, VectorSpace};
+
+use crate::types::{Mat4, Vec3, AABB};
+
+// Steps every SRT segment is sampled at to bound its motion, the curvature in
+// between is covered by padding
+const SRT_BOUND_STEPS: usize = 8;
+
+// A transform split into scale, rotation and translation, applied in that order
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Srt {
+    pub scale: Vec3,
+    pub rotation: Quaternion<f32>,
+    pub translation: Vec3,
+}
+
+impl Srt {
+    pub fn new(scale: Vec3, rotation: Quaternion<f32>, translation: Vec3) -> Self {
+        Self {
+            scale,
+            rotation,
+            translation,
+        }
+    }
+
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_translation(self.translation)
+            * Mat4::from(self.rotation.normalize())
+            * Mat4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    // Scale and translation are blended linearly, the rotation is slerped so it
+    // keeps turning at a constant rate
+    pub fn interpolate(&self, other: &Srt, amount: f32) -> Self {
+        Self {
+            scale: self.scale.lerp(other.scale, amount),
+            rotation: self
+                .rotation
+                .normalize()
+                .slerp(other.rotation.normalize(), amount),
+            translation: self.translation.lerp(other.translation, amount),
+        }
+    }
+}
+
+// The transform of a moving instance, sampled at two or more keyframes spread
+// evenly over the time range [0, 1]. Times outside it are clamped.
+#[derive(Clone, Debug)]
+pub enum MotionTransform {
+    // Matrices are blended element-wise, which is cheap but makes rotating
+    // objects shrink between keyframes
+    Linear(Vec<Mat4>),
+    // Rotations stay rigid, at the cost of looser bounds
+    Srt(Vec<Srt>),
+}
+
+impl MotionTransform {
+    pub fn linear(keyframes: Vec<Mat4>) -> Self {
+        assert!(keyframes.len() >= 2, "Motion needs at least two keyframes");
+        MotionTransform::Linear(keyframes)
+    }
+
+    pub fn srt(keyframes: Vec<Srt>) -> Self {
+        assert!(keyframes.len() >= 2, "Motion needs at least two keyframes");
+        MotionTransform::Srt(keyframes)
+    }
+
+    pub fn keyframe_count(&self) -> usize {
+        match self {
+            MotionTransform::Linear(keyframes) => keyframes.len(),
+            MotionTransform::Srt(keyframes) => keyframes.len(),
+        }
+    }
+
+    // The segment `time` falls in and how far along it is
+    fn segment(&self, time: f32) -> (usize, f32) {
+        let segments = self.keyframe_count() - 1;
+        let position = time.clamp(0.0, 1.0) * segments as f32;
+        let segment = (position as usize).min(segments - 1);
+        (segment, position - segment as f32)
+    }
+
+    pub fn at(&self, time: f32) -> Mat4 {
+        let (segment, amount) = self.segment(time);
+        match self {
+            MotionTransform::Linear(keyframes) => {
+                keyframes[segment] * (1.0 - amount) + keyframes[segment + 1] * amount
+            }
+            MotionTransform::Srt(keyframes) => keyframes[segment]
+                .interpolate(&keyframes[segment + 1], amount)
+                .matrix(),
+        }
+    }
+
+    // Bounds of `aabb` over the whole time range
+    pub fn bounds(&self, aabb: &AABB) -> AABB {
+        let mut bounds = AABB::default();
+        match self {
+            // Every point moves along a straight line between keyframes
+            MotionTransform::Linear(keyframes) => {
+                for keyframe in keyframes {
+                    bounds.grow(&aabb.transformed(keyframe));
+                }
+            }
+            MotionTransform::Srt(keyframes) => {
+                for pair in keyframes.windows(2) {
+                    bounds.grow(&srt_segment_bounds(&pair[0], &pair[1], aabb));
+                }
+            }
+        }
+        bounds
+    }
+}
+
+// Corners of the box are sampled along the segment. Between samples a corner at
+// scaled position y(s), rotating at angular speed w, strays from the straight
+// line by at most (w^2 |y| + 2 w |y'|) / (8 n^2) for n steps, so the samples
+// are padded by that.
+fn srt_segment_bounds(a: &Srt, b: &Srt, aabb: &AABB) -> AABB {
+    let cos_half_angle = a.rotation.normalize().dot(b.rotation.normalize()).abs();
+    let angle = 2.0 * cos_half_angle.min(1.0).acos();
+
+    let mut bounds = AABB::default();
+    for step in 0..=SRT_BOUND_STEPS {
+        let amount = step as f32 / SRT_BOUND_STEPS as f32;
+        bounds.grow(&aabb.transformed(&a.interpolate(b, amount).matrix()));
+    }
+
+    let mut padding: f32 = 0.0;
+    for corner in 0..8 {
+        let corner = Vec3::new(
+            if corner & 1 == 0 {
+                aabb.min.x
+            } else {
+                aabb.max.x
+            },
+            if corner & 2 == 0 {
+                aabb.min.y
+            } else {
+                aabb.max.y
+            },
+            if corner & 4 == 0 {
+                aabb.min.z
+            } else {
+                aabb.max.z
+            },
+        );
+        let (y0, y1) = (
+            a.scale.mul_element_wise(corner),
+            b.scale.mul_element_wise(corner),
+        );
+        let radius = y0.magnitude().max(y1.magnitude());
+        let curvature = angle * angle * radius + 2.0 * angle * (y1 - y0).magnitude();
+        padding = padding.max(curvature / (8 * SRT_BOUND_STEPS * SRT_BOUND_STEPS) as f32);
+    }
+
+    // Slerp falls back to nlerp for small angles, which turns slightly unevenly
+    let padding = padding * 1.01;
+    bounds.min -= Vec3::new(padding, padding, padding);
+    bounds.max += Vec3::new(padding, padding, padding);
+    bounds
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{InnerSpace, Quaternion, Rad, Rotation3};
+
+    use super::{MotionTransform, Srt};
+    use crate::{
+        testing::{encloses, Rng},
+        types::{Mat4, Vec3, AABB},
+    };
+
+    // Samples the motion densely and checks that the box at every pose lies
+    // within the bounds, up to float rounding
+    fn assert_bounds_every_pose(motion: &MotionTransform, aabb: &AABB) {
+        let mut bounds = motion.bounds(aabb);
+        let slack = (bounds.max - bounds.min).magnitude() * 1e-5;
+        bounds.min -= Vec3::new(slack, slack, slack);
+        bounds.max += Vec3::new(slack, slack, slack);
+        for step in 0..=1000 {
+            let time = step as f32 / 1000.0;
+            assert!(encloses(&bounds, &aabb.transformed(&motion.at(time))));
+        }
+    }
+
+    #[test]
+    fn bounds_contain_every_pose() {
+        let mut rng = Rng::new(25);
+        for keyframe_count in [2, 3, 5] {
+            for _ in 0..20 {
+                let min = rng.vec3(-2.0, 2.0);
+                let aabb = AABB::new(min, min + rng.vec3(0.1, 3.0));
+
+                // Large rotations between keyframes, where the corners swing
+                // furthest from the straight lines the samples are joined by
+                let keyframes: Vec<Srt> = (0..keyframe_count)
+                    .map(|_| {
+                        let axis = rng.vec3(-1.0, 1.0).normalize();
+                        Srt::new(
+                            rng.vec3(0.2, 3.0),
+                            Quaternion::from_axis_angle(axis, Rad(rng.range(-3.1, 3.1))),
+                            rng.vec3(-10.0, 10.0),
+                        )
+                    })
+                    .collect();
+                let linear = keyframes.iter().map(Srt::matrix).collect::<Vec<Mat4>>();
+
+                assert_bounds_every_pose(&MotionTransform::srt(keyframes), &aabb);
+                assert_bounds_every_pose(&MotionTransform::linear(linear), &aabb);
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use crate::types::HitRecord;
+
+// Receives every hit found by a multi-hit traversal. Implemented by `HitBuffer`,
+// which never allocates, and `HitList`.
+pub trait HitCollector {
+    // Hits at or beyond this distance can't be stored, so the traversal skips
+    // everything farther away
+    fn t_max(&self) -> f32;
+
+    fn insert(&mut self, hit: HitRecord);
+}
+
+// The N nearest hits sorted by t, stored inline
+pub struct HitBuffer<const N: usize> {
+    hits: [HitRecord; N],
+    len: usize,
+}
+
+impl<const N: usize> HitBuffer<N> {
+    pub fn new() -> Self {
+        assert!(N > 0, "A hit buffer needs room for at least one hit");
+        Self {
+            hits: [HitRecord::new(); N],
+            len: 0,
+        }
+    }
+
+    pub fn hits(&self) -> &[HitRecord] {
+        &self.hits[..self.len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for HitBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> HitCollector for HitBuffer<N> {
+    fn t_max(&self) -> f32 {
+        if self.len < N {
+            f32::MAX
+        } else {
+            self.hits[N - 1].t
+        }
+    }
+
+    fn insert(&mut self, hit: HitRecord) {
+        if hit.t >= self.t_max() || contains(self.hits(), &hit) {
+            return;
+        }
+
+        let position = self.hits().partition_point(|other| other.t <= hit.t);
+        self.len = (self.len + 1).min(N);
+        self.hits[position..self.len].rotate_right(1);
+        self.hits[position] = hit;
+    }
+}
+
+// All hits, or the nearest `limit` ones, sorted by t
+pub struct HitList {
+    hits: Vec<HitRecord>,
+    limit: Option<usize>,
+}
+
+impl HitList {
+    pub fn all() -> Self {
+        Self {
+            hits: Vec::new(),
+            limit: None,
+        }
+    }
+
+    pub fn nearest(limit: usize) -> Self {
+        Self {
+            hits: Vec::with_capacity(limit),
+            limit: Some(limit),
+        }
+    }
+
+    pub fn hits(&self) -> &[HitRecord] {
+        &self.hits
+    }
+
+    pub fn into_hits(self) -> Vec<HitRecord> {
+        self.hits
+    }
+}
+
+impl HitCollector for HitList {
+    fn t_max(&self) -> f32 {
+        match self.limit {
+            Some(limit) if self.hits.len() >= limit => {
+                self.hits.last().map_or(f32::MIN, |hit| hit.t)
+            }
+            _ => f32::MAX,
+        }
+    }
+
+    fn insert(&mut self, hit: HitRecord) {
+        if hit.t >= self.t_max() || contains(&self.hits, &hit) {
+            return;
+        }
+
+        let position = self.hits.partition_point(|other| other.t <= hit.t);
+        self.hits.insert(position, hit);
+        if let Some(limit) = self.limit {
+            self.hits.truncate(limit);
+        }
+    }
+}
+
+// Primitives referenced by more than one leaf, like after spatial splits, are
+// only stored once
+fn contains(hits: &[HitRecord], hit: &HitRecord) -> bool {
+    hits.iter()
+        .any(|other| other.object_id == hit.object_id && other.primitive_id == hit.primitive_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::SquareMatrix;
+
+    use super::{HitBuffer, HitCollector, HitList};
+    use crate::{
+        bvh::{BuildStrategy, Bvh, BvhBuildOptions},
+        testing::{closest_hit, slivers, Rng},
+        types::{HitRecord, Mat4},
+    };
+
+    fn hit(t: f32, primitive_id: u32) -> HitRecord {
+        HitRecord {
+            t,
+            primitive_id,
+            ..HitRecord::new()
+        }
+    }
+
+    fn primitives(hits: &[HitRecord]) -> Vec<u32> {
+        hits.iter().map(|hit| hit.primitive_id).collect()
+    }
+
+    #[test]
+    fn collectors_keep_hits_sorted_and_unique() {
+        let mut buffer = HitBuffer::<3>::new();
+        let mut list = HitList::all();
+        let mut nearest = HitList::nearest(2);
+        for (t, primitive) in [(3.0, 3), (1.0, 1), (3.0, 3), (4.0, 4), (2.0, 2), (1.0, 1)] {
+            buffer.insert(hit(t, primitive));
+            list.insert(hit(t, primitive));
+            nearest.insert(hit(t, primitive));
+        }
+        assert_eq!(primitives(buffer.hits()), [1, 2, 3]);
+        assert_eq!(buffer.t_max(), 3.0);
+        assert_eq!(primitives(list.hits()), [1, 2, 3, 4]);
+        assert_eq!(list.t_max(), f32::MAX);
+        assert_eq!(primitives(nearest.hits()), [1, 2]);
+        assert_eq!(nearest.t_max(), 2.0);
+    }
+
+    #[test]
+    fn multi_hit_matches_brute_force() {
+        // Spatial splits reference primitives from several leaves
+        let (vertices, indices) = slivers(1000);
+        let options = BvhBuildOptions::new().with_strategy(BuildStrategy::SpatialSah {
+            max_duplication: 0.5,
+        });
+        let bvh = Bvh::new_with_options(&vertices, &indices, &options);
+        assert!(bvh.primitives().len() > indices.len() / 3);
+
+        let mut rng = Rng::new(47);
+        let mut hits = 0;
+        for _ in 0..300 {
+            let ray = rng.ray();
+            let mut expected: Vec<(f32, u32)> = indices
+                .chunks(3)
+                .enumerate()
+                .map(|(i, triangle)| (closest_hit(&vertices, triangle, &ray), i as u32))
+                .filter(|(t, _)| *t < f32::MAX)
+                .collect();
+            expected.sort_by(|a, b| a.0.total_cmp(&b.0));
+            hits += expected.len();
+
+            let all = bvh.all_hits(&ray, &Mat4::identity());
+            assert_eq!(all.len(), expected.len());
+            assert!(all.windows(2).all(|pair| pair[0].t <= pair[1].t));
+            for (hit, (t, _)) in all.iter().zip(&expected) {
+                assert!((hit.t - t).abs() < t * 1e-5);
+            }
+            let mut found = primitives(&all);
+            found.sort_unstable();
+            let mut expected_primitives: Vec<u32> = expected.iter().map(|(_, i)| *i).collect();
+            expected_primitives.sort_unstable();
+            assert_eq!(found, expected_primitives);
+
+            let mut buffer = HitBuffer::<4>::new();
+            bvh.traverse_all(&ray, &Mat4::identity(), &mut buffer);
+            assert_eq!(
+                primitives(buffer.hits()),
+                primitives(&all[..all.len().min(4)])
+            );
+        }
+        assert!(hits > 300);
+    }
+}
@@ -0,0 +1,672 @@
+use cgmath::InnerSpace;
+
+use crate::{
+    types::{Mat4, Ray, Vec3, Vertex, AABB},
+    wide_bvh::SimdLevel,
+};
+
+// Up to N rays stored per component so the box and triangle tests can run on
+// all of them at once. Lanes are processed in groups of four, so N has to be 4,
+// 8 or 16.
+#[derive(Clone, Copy)]
+pub struct RayPacket<const N: usize> {
+    origin_x: [f32; N],
+    origin_y: [f32; N],
+    origin_z: [f32; N],
+    direction_x: [f32; N],
+    direction_y: [f32; N],
+    direction_z: [f32; N],
+    inv_direction_x: [f32; N],
+    inv_direction_y: [f32; N],
+    inv_direction_z: [f32; N],
+    t_min: [f32; N],
+    t_max: [f32; N],
+    time: [f32; N],
+    // Bit i is set when lane i holds a ray
+    active: u32,
+    frustum_culling: bool,
+    simd: SimdLevel,
+}
+
+pub type RayPacket4 = RayPacket<4>;
+pub type RayPacket8 = RayPacket<8>;
+pub type RayPacket16 = RayPacket<16>;
+
+impl<const N: usize> RayPacket<N> {
+    pub fn new() -> Self {
+        assert!(N == 4 || N == 8 || N == 16, "Packets hold 4, 8 or 16 rays");
+        Self {
+            origin_x: [0.0; N],
+            origin_y: [0.0; N],
+            origin_z: [0.0; N],
+            direction_x: [1.0; N],
+            direction_y: [1.0; N],
+            direction_z: [1.0; N],
+            inv_direction_x: [1.0; N],
+            inv_direction_y: [1.0; N],
+            inv_direction_z: [1.0; N],
+            t_min: [0.0; N],
+            t_max: [0.0; N],
+            time: [0.0; N],
+            active: 0,
+            frustum_culling: false,
+            simd: SimdLevel::detect(),
+        }
+    }
+
+    // Fills the first lanes with `rays`, the remaining lanes stay inactive
+    pub fn from_rays(rays: &[Ray]) -> Self {
+        assert!(rays.len() <= N, "Too many rays for the packet");
+        let mut packet = Self::new();
+        for (lane, ray) in rays.iter().enumerate() {
+            packet.set(lane, ray);
+        }
+        packet
+    }
+
+    // Skips whole subtrees when no ray of the packet can hit them. Only used when
+    // the directions of all active rays have the same sign on every axis, like
+    // small tiles of camera rays away from the image axes.
+    pub fn with_frustum_culling(mut self, frustum_culling: bool) -> Self {
+        self.frustum_culling = frustum_culling;
+        self
+    }
+
+    // Forces the instruction set used for the box and triangle tests, as far as
+    // the cpu supports it
+    pub fn with_simd(mut self, simd: SimdLevel) -> Self {
+        self.simd = simd.supported();
+        self
+    }
+
+    pub fn set(&mut self, lane: usize, ray: &Ray) {
+        self.origin_x[lane] = ray.origin.x;
+        self.origin_y[lane] = ray.origin.y;
+        self.origin_z[lane] = ray.origin.z;
+        self.direction_x[lane] = ray.direction.x;
+        self.direction_y[lane] = ray.direction.y;
+        self.direction_z[lane] = ray.direction.z;
+        self.inv_direction_x[lane] = 1.0 / ray.direction.x;
+        self.inv_direction_y[lane] = 1.0 / ray.direction.y;
+        self.inv_direction_z[lane] = 1.0 / ray.direction.z;
+        self.t_min[lane] = ray.t_min;
+        self.t_max[lane] = ray.t_max;
+        self.time[lane] = ray.time;
+        self.active |= 1 << lane;
+    }
+
+    pub fn ray(&self, lane: usize) -> Ray {
+        Ray::new(
+            Vec3::new(
+                self.origin_x[lane],
+                self.origin_y[lane],
+                self.origin_z[lane],
+            ),
+            Vec3::new(
+                self.direction_x[lane],
+                self.direction_y[lane],
+                self.direction_z[lane],
+            ),
+        )
+        .with_interval(self.t_min[lane], self.t_max[lane])
+        .with_time(self.time[lane])
+    }
+
+    pub fn active(&self) -> u32 {
+        self.active
+    }
+
+    pub fn set_active(&mut self, active: u32) {
+        self.active = active;
+    }
+
+    pub fn frustum_culling(&self) -> bool {
+        self.frustum_culling
+    }
+
+    pub fn simd(&self) -> SimdLevel {
+        self.simd
+    }
+
+    pub(crate) fn t_max(&self) -> &[f32; N] {
+        &self.t_max
+    }
+
+    pub(crate) fn set_t_max(&mut self, lane: usize, t_max: f32) {
+        self.t_max[lane] = t_max;
+    }
+
+    // Transforms every active lane like `Ray::transformed`. Also returns the
+    // factor each lane's distances were scaled by.
+    pub(crate) fn transformed(&self, transform: &Mat4) -> (Self, [f32; N]) {
+        let mut packet = *self;
+        let mut scale = [1.0; N];
+        for lane in lanes(self.active) {
+            let ray = self.ray(lane);
+            packet.set(lane, &ray.transformed(transform));
+            scale[lane] = ray.transformed_scale(transform);
+        }
+        (packet, scale)
+    }
+
+    // Whether the active lanes are likely to reach box `a` before box `b`. Uses
+    // the direction of the first lane, like the single ray traversal sorting
+    // children by their entry distance.
+    pub(crate) fn in_order(&self, active: u32, a: &AABB, b: &AABB) -> bool {
+        let lane = active.trailing_zeros() as usize;
+        let direction = Vec3::new(
+            self.direction_x[lane],
+            self.direction_y[lane],
+            self.direction_z[lane],
+        );
+        ((b.min + b.max) - (a.min + a.max)).dot(direction) >= 0.0
+    }
+
+    // Bounds of the origins and inverse directions of the active lanes, when the
+    // directions agree in sign
+    pub(crate) fn frustum(&self) -> Option<PacketFrustum> {
+        if !self.frustum_culling || self.active == 0 {
+            return None;
+        }
+
+        let mut frustum = PacketFrustum {
+            origin_min: Vec3::new(f32::MAX, f32::MAX, f32::MAX),
+            origin_max: Vec3::new(f32::MIN, f32::MIN, f32::MIN),
+            inv_direction_min: Vec3::new(f32::MAX, f32::MAX, f32::MAX),
+            inv_direction_max: Vec3::new(f32::MIN, f32::MIN, f32::MIN),
+            negative: [false; 3],
+            t_min: f32::MAX,
+        };
+        let first = self.active.trailing_zeros() as usize;
+        let inv_directions = [
+            &self.inv_direction_x,
+            &self.inv_direction_y,
+            &self.inv_direction_z,
+        ];
+        frustum.negative = inv_directions.map(|inv_direction| inv_direction[first] < 0.0);
+
+        let origins = [&self.origin_x, &self.origin_y, &self.origin_z];
+        for lane in lanes(self.active) {
+            for axis in 0..3 {
+                let inv_direction = inv_directions[axis][lane];
+                if !inv_direction.is_finite() || (inv_direction < 0.0) != frustum.negative[axis] {
+                    return None;
+                }
+                frustum.origin_min[axis] = frustum.origin_min[axis].min(origins[axis][lane]);
+                frustum.origin_max[axis] = frustum.origin_max[axis].max(origins[axis][lane]);
+                frustum.inv_direction_min[axis] =
+                    frustum.inv_direction_min[axis].min(inv_direction);
+                frustum.inv_direction_max[axis] =
+                    frustum.inv_direction_max[axis].max(inv_direction);
+            }
+            frustum.t_min = frustum.t_min.min(self.t_min[lane]);
+        }
+        Some(frustum)
+    }
+}
+
+impl<const N: usize> Default for RayPacket<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Iterates over the set bits of a lane mask
+pub fn lanes(mask: u32) -> impl Iterator<Item = usize> {
+    let mut mask = mask;
+    std::iter::from_fn(move || {
+        if mask == 0 {
+            return None;
+        }
+        let lane = mask.trailing_zeros() as usize;
+        mask &= mask - 1;
+        Some(lane)
+    })
+}
+
+// Conservative bounds of a whole packet. Every ray's slab distances lie inside
+// the intervals computed from these, so a box missed by the intervals is missed
+// by every ray.
+pub(crate) struct PacketFrustum {
+    origin_min: Vec3,
+    origin_max: Vec3,
+    inv_direction_min: Vec3,
+    inv_direction_max: Vec3,
+    negative: [bool; 3],
+    t_min: f32,
+}
+
+impl PacketFrustum {
+    // `t_max` holds the current closest hit of every lane
+    pub(crate) fn misses<const N: usize>(
+        &self,
+        aabb: &AABB,
+        t_max: &[f32; N],
+        active: u32,
+    ) -> bool {
+        let mut near = self.t_min;
+        let mut far = lanes(active).fold(f32::MIN, |far, lane| far.max(t_max[lane]));
+        for axis in 0..3 {
+            let (entry, exit) = if self.negative[axis] {
+                (aabb.max[axis], aabb.min[axis])
+            } else {
+                (aabb.min[axis], aabb.max[axis])
+            };
+            let distances = |plane: f32| {
+                let a = plane - self.origin_max[axis];
+                let b = plane - self.origin_min[axis];
+                let c = self.inv_direction_min[axis];
+                let d = self.inv_direction_max[axis];
+                let products = [a * c, a * d, b * c, b * d];
+                let min = products.iter().fold(f32::MAX, |acc, p| acc.min(*p));
+                let max = products.iter().fold(f32::MIN, |acc, p| acc.max(*p));
+                (min, max)
+            };
+            near = near.max(distances(entry).0);
+            far = far.min(distances(exit).1);
+        }
+        near > far
+    }
+}
+
+// Closest hits found for each lane while tracing a packet. `t` starts at the
+// lane's `t_max` and only shrinks.
+pub struct PacketHits<const N: usize> {
+    pub t: [f32; N],
+    pub u: [f32; N],
+    pub v: [f32; N],
+    pub primitive_id: [u32; N],
+    // Lanes that found a hit
+    pub hit: u32,
+}
+
+impl<const N: usize> PacketHits<N> {
+    pub fn new<const M: usize>(packet: &RayPacket<M>) -> Self {
+        let mut t = [0.0; N];
+        t.copy_from_slice(packet.t_max());
+        Self {
+            t,
+            u: [0.0; N],
+            v: [0.0; N],
+            primitive_id: [0; N],
+            hit: 0,
+        }
+    }
+
+    pub fn record(&mut self, lane: usize, t: f32, u: f32, v: f32, primitive: u32) {
+        self.t[lane] = t;
+        self.u[lane] = u;
+        self.v[lane] = v;
+        self.primitive_id[lane] = primitive;
+        self.hit |= 1 << lane;
+    }
+}
+
+// Returns the active lanes whose interval, clipped to `t_max`, overlaps the box
+pub(crate) fn intersect_aabb_packet<const N: usize>(
+    aabb: &AABB,
+    packet: &RayPacket<N>,
+    t_max: &[f32; N],
+    active: u32,
+) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if packet.simd != SimdLevel::Scalar {
+            // Safety: packets only keep levels the cpu supports
+            return unsafe { x86::intersect_aabb_sse(aabb, packet, t_max) } & active;
+        }
+    }
+
+    let mut mask = 0;
+    for lane in lanes(active) {
+        let slab = |min: f32, max: f32, origin: f32, inv_direction: f32| {
+            let t1 = (min - origin) * inv_direction;
+            let t2 = (max - origin) * inv_direction;
+            (t1.min(t2), t1.max(t2))
+        };
+        let (x0, x1) = slab(
+            aabb.min.x,
+            aabb.max.x,
+            packet.origin_x[lane],
+            packet.inv_direction_x[lane],
+        );
+        let (y0, y1) = slab(
+            aabb.min.y,
+            aabb.max.y,
+            packet.origin_y[lane],
+            packet.inv_direction_y[lane],
+        );
+        let (z0, z1) = slab(
+            aabb.min.z,
+            aabb.max.z,
+            packet.origin_z[lane],
+            packet.inv_direction_z[lane],
+        );
+        let near = x0.max(y0).max(z0);
+        let far = x1.min(y1).min(z1);
+        if far >= near && near < t_max[lane] && far > packet.t_min[lane] {
+            mask |= 1 << lane;
+        }
+    }
+    mask
+}
+
+// Möller-Trumbore for every active lane against one triangle, with the same
+// tolerances as `intersect_triangle`. Lanes hit closer than `hits.t` are
+// recorded.
+pub(crate) fn intersect_triangle_packet<const N: usize>(
+    triangle: &[Vertex; 3],
+    primitive: u32,
+    packet: &RayPacket<N>,
+    active: u32,
+    hits: &mut PacketHits<N>,
+) {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if packet.simd != SimdLevel::Scalar {
+            // Safety: packets only keep levels the cpu supports
+            return unsafe {
+                x86::intersect_triangle_sse(triangle, primitive, packet, active, hits)
+            };
+        }
+    }
+
+    let [v0, v1, v2] = triangle;
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    for lane in lanes(active) {
+        let origin = Vec3::new(
+            packet.origin_x[lane],
+            packet.origin_y[lane],
+            packet.origin_z[lane],
+        );
+        let direction = Vec3::new(
+            packet.direction_x[lane],
+            packet.direction_y[lane],
+            packet.direction_z[lane],
+        );
+        let h = direction.cross(edge2);
+        let a = edge1.dot(h);
+        if a > -0.0001 && a < 0.0001 {
+            continue;
+        }
+
+        let f = 1.0 / a;
+        let s = origin - v0;
+        let u = f * s.dot(h);
+        if !(0.0..=1.0).contains(&u) {
+            continue;
+        }
+        let q = s.cross(edge1);
+        let v = f * direction.dot(q);
+        if v < 0.0 || u + v > 1.0 {
+            continue;
+        }
+
+        let t = f * edge2.dot(q);
+        if t > packet.t_min[lane].max(0.000001) && t < hits.t[lane] {
+            hits.record(lane, t, u, v, primitive);
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::{PacketHits, RayPacket};
+    use crate::types::{Vertex, AABB};
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn intersect_aabb_sse<const N: usize>(
+        aabb: &AABB,
+        packet: &RayPacket<N>,
+        t_max: &[f32; N],
+    ) -> u32 {
+        let min_x = _mm_set1_ps(aabb.min.x);
+        let min_y = _mm_set1_ps(aabb.min.y);
+        let min_z = _mm_set1_ps(aabb.min.z);
+        let max_x = _mm_set1_ps(aabb.max.x);
+        let max_y = _mm_set1_ps(aabb.max.y);
+        let max_z = _mm_set1_ps(aabb.max.z);
+
+        let mut mask = 0;
+        for k in (0..N).step_by(4) {
+            let load = |values: &[f32; N]| _mm_loadu_ps(values[k..].as_ptr());
+            let slab = |min: __m128, max: __m128, origin: &[f32; N], inv: &[f32; N]| {
+                let t1 = _mm_mul_ps(_mm_sub_ps(min, load(origin)), load(inv));
+                let t2 = _mm_mul_ps(_mm_sub_ps(max, load(origin)), load(inv));
+                (_mm_min_ps(t1, t2), _mm_max_ps(t1, t2))
+            };
+            let (x0, x1) = slab(min_x, max_x, &packet.origin_x, &packet.inv_direction_x);
+            let (y0, y1) = slab(min_y, max_y, &packet.origin_y, &packet.inv_direction_y);
+            let (z0, z1) = slab(min_z, max_z, &packet.origin_z, &packet.inv_direction_z);
+            let near = _mm_max_ps(_mm_max_ps(x0, y0), z0);
+            let far = _mm_min_ps(_mm_min_ps(x1, y1), z1);
+            let hit = _mm_and_ps(
+                _mm_cmpge_ps(far, near),
+                _mm_and_ps(
+                    _mm_cmplt_ps(near, load(t_max)),
+                    _mm_cmpgt_ps(far, load(&packet.t_min)),
+                ),
+            );
+            mask |= (_mm_movemask_ps(hit) as u32) << k;
+        }
+        mask
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn intersect_triangle_sse<const N: usize>(
+        triangle: &[Vertex; 3],
+        primitive: u32,
+        packet: &RayPacket<N>,
+        active: u32,
+        hits: &mut PacketHits<N>,
+    ) {
+        let [v0, v1, v2] = triangle;
+        let edge1 = v1 - v0;
+        let edge2 = v2 - v0;
+        let splat = |v: f32| _mm_set1_ps(v);
+        let (e1x, e1y, e1z) = (splat(edge1.x), splat(edge1.y), splat(edge1.z));
+        let (e2x, e2y, e2z) = (splat(edge2.x), splat(edge2.y), splat(edge2.z));
+        let zero = _mm_setzero_ps();
+        let one = splat(1.0);
+        let epsilon = splat(0.0001);
+
+        for k in (0..N).step_by(4) {
+            let lane_mask = (active >> k) & 0xf;
+            if lane_mask == 0 {
+                continue;
+            }
+
+            let load = |values: &[f32; N]| _mm_loadu_ps(values[k..].as_ptr());
+            let (dx, dy, dz) = (
+                load(&packet.direction_x),
+                load(&packet.direction_y),
+                load(&packet.direction_z),
+            );
+            let sub = _mm_sub_ps;
+            let mul = _mm_mul_ps;
+            let add = _mm_add_ps;
+
+            // h = direction x edge2
+            let hx = sub(mul(dy, e2z), mul(dz, e2y));
+            let hy = sub(mul(dz, e2x), mul(dx, e2z));
+            let hz = sub(mul(dx, e2y), mul(dy, e2x));
+            let a = add(add(mul(e1x, hx), mul(e1y, hy)), mul(e1z, hz));
+            let parallel = _mm_and_ps(
+                _mm_cmpgt_ps(a, _mm_sub_ps(zero, epsilon)),
+                _mm_cmplt_ps(a, epsilon),
+            );
+
+            let f = _mm_div_ps(one, a);
+            let sx = sub(load(&packet.origin_x), splat(v0.x));
+            let sy = sub(load(&packet.origin_y), splat(v0.y));
+            let sz = sub(load(&packet.origin_z), splat(v0.z));
+            let u = mul(f, add(add(mul(sx, hx), mul(sy, hy)), mul(sz, hz)));
+
+            // q = s x edge1
+            let qx = sub(mul(sy, e1z), mul(sz, e1y));
+            let qy = sub(mul(sz, e1x), mul(sx, e1z));
+            let qz = sub(mul(sx, e1y), mul(sy, e1x));
+            let v = mul(f, add(add(mul(dx, qx), mul(dy, qy)), mul(dz, qz)));
+            let t = mul(f, add(add(mul(e2x, qx), mul(e2y, qy)), mul(e2z, qz)));
+
+            let t_min = _mm_max_ps(load(&packet.t_min), splat(0.000001));
+            let hit = _mm_andnot_ps(
+                parallel,
+                _mm_and_ps(
+                    _mm_and_ps(_mm_cmpge_ps(u, zero), _mm_cmple_ps(u, one)),
+                    _mm_and_ps(
+                        _mm_and_ps(_mm_cmpge_ps(v, zero), _mm_cmple_ps(add(u, v), one)),
+                        _mm_and_ps(_mm_cmpgt_ps(t, t_min), _mm_cmplt_ps(t, load(&hits.t))),
+                    ),
+                ),
+            );
+            let hit_mask = _mm_movemask_ps(hit) as u32 & lane_mask;
+            if hit_mask == 0 {
+                continue;
+            }
+
+            let mut ts = [0.0; 4];
+            let mut us = [0.0; 4];
+            let mut vs = [0.0; 4];
+            _mm_storeu_ps(ts.as_mut_ptr(), t);
+            _mm_storeu_ps(us.as_mut_ptr(), u);
+            _mm_storeu_ps(vs.as_mut_ptr(), v);
+            for i in super::lanes(hit_mask) {
+                hits.record(k + i, ts[i], us[i], vs[i], primitive);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cgmath::{InnerSpace, Rad, SquareMatrix};
+
+    use super::RayPacket;
+    use crate::{
+        bvh::Bvh,
+        testing::{soup, Rng},
+        top_level_acceleration_structure::{Instance, TopLevelAccelerationStructure},
+        types::{HitRecord, Mat4, Ray, Vec3},
+        wide_bvh::SimdLevel,
+    };
+
+    // Alternates tiles of nearly parallel camera rays, which frustum culling can
+    // use, with unrelated rays, some of them with a limited interval
+    fn packets(count: usize, rng: &mut Rng) -> Vec<Vec<Ray>> {
+        (0..count)
+            .map(|k| {
+                let len = 1 + (rng.next() * 16.0) as usize;
+                if k % 2 == 0 {
+                    let origin = Vec3::new(rng.range(-1.0, 1.0), rng.range(-1.0, 1.0), -15.0);
+                    let target = rng.vec3(-2.0, 2.0);
+                    (0..len)
+                        .map(|lane| {
+                            let offset = Vec3::new((lane % 4) as f32, (lane / 4) as f32, 0.0);
+                            Ray::new(origin, (target + offset * 0.02 - origin).normalize())
+                        })
+                        .collect()
+                } else {
+                    (0..len)
+                        .map(|_| {
+                            let ray = rng.ray();
+                            if rng.next() < 0.3 {
+                                ray.with_interval(rng.range(0.0, 5.0), rng.range(10.0, 20.0))
+                            } else {
+                                ray
+                            }
+                        })
+                        .collect()
+                }
+            })
+            .collect()
+    }
+
+    fn assert_matches_single<const N: usize>(bvh: &Bvh, rays: &[Ray], transform: &Mat4) {
+        let rays = &rays[..rays.len().min(N)];
+        for simd in [SimdLevel::Scalar, SimdLevel::Sse, SimdLevel::Avx] {
+            for frustum_culling in [false, true] {
+                let packet = RayPacket::<N>::from_rays(rays)
+                    .with_frustum_culling(frustum_culling)
+                    .with_simd(simd);
+                // Levels the cpu lacks fall back instead of being executed
+                assert_eq!(packet.simd(), simd.min(SimdLevel::detect()));
+                let mut records = [HitRecord::new(); N];
+                let hit_lanes = bvh.traverse_packet(&packet, transform, &mut records);
+                for (lane, ray) in rays.iter().enumerate() {
+                    let mut expected = HitRecord::new();
+                    bvh.traverse(ray, transform, &mut expected);
+                    let hit = &records[lane];
+                    assert_eq!(hit_lanes & (1 << lane) != 0, expected.t < f32::MAX);
+                    if expected.t < f32::MAX {
+                        assert!((hit.t - expected.t).abs() < expected.t * 1e-5);
+                        assert_eq!(hit.primitive_id, expected.primitive_id);
+                    } else {
+                        assert_eq!(hit.t, f32::MAX);
+                    }
+                }
+                assert!(records[rays.len()..].iter().all(|hit| hit.t == f32::MAX));
+            }
+        }
+    }
+
+    #[test]
+    fn packets_match_single_rays() {
+        let (vertices, indices) = soup(2000, 33);
+        let bvh = Bvh::new(&vertices, &indices);
+        let mut compressed = Bvh::new(&vertices, &indices);
+        compressed.compress();
+        let transform = Mat4::from_translation(Vec3::new(0.5, -0.2, 1.0))
+            * Mat4::from_scale(1.5)
+            * Mat4::from_angle_y(Rad(0.7));
+        let mut rng = Rng::new(34);
+        for rays in packets(200, &mut rng) {
+            for bvh in [&bvh, &compressed] {
+                for transform in [Mat4::identity(), transform] {
+                    assert_matches_single::<4>(bvh, &rays, &transform);
+                    assert_matches_single::<8>(bvh, &rays, &transform);
+                    assert_matches_single::<16>(bvh, &rays, &transform);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn instance_packets_match_single_rays() {
+        let (vertices, indices) = soup(500, 35);
+        let blas = Arc::new(Bvh::new(&vertices, &indices));
+        let mut rng = Rng::new(36);
+        let instances: Vec<Instance> = (0..20)
+            .map(|id| {
+                let transform = Mat4::from_translation(rng.vec3(-3.0, 3.0))
+                    * Mat4::from_angle_x(Rad(rng.range(0.0, 3.0)))
+                    * Mat4::from_scale(rng.range(0.3, 0.6));
+                Instance::new(blas.clone(), id, transform)
+            })
+            .collect();
+        let tlas = TopLevelAccelerationStructure::new(&instances);
+        for rays in packets(200, &mut rng) {
+            let rays = &rays[..rays.len().min(8)];
+            for frustum_culling in [false, true] {
+                let packet = RayPacket::<8>::from_rays(rays).with_frustum_culling(frustum_culling);
+                let records = tlas.traverse_packet(&packet);
+                for (lane, ray) in rays.iter().enumerate() {
+                    let expected = tlas.traverse(ray);
+                    let hit = &records[lane];
+                    assert!(hit.t == expected.t || (hit.t - expected.t).abs() < expected.t * 1e-5);
+                    if expected.t < f32::MAX {
+                        assert_eq!(
+                            (hit.object_id, hit.primitive_id),
+                            (expected.object_id, expected.primitive_id)
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
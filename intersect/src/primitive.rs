@@ -0,0 +1,724 @@
+use std::f32::consts::PI;
+
+use cgmath::{InnerSpace, Matrix, SquareMatrix};
+
+use crate::{
+    intersect::{
+        gamma, intersect_triangle, intersect_triangle_watertight, CullMode, TriangleIntersection,
+    },
+    packet::{intersect_triangle_packet, lanes, PacketHits, RayPacket},
+    query::Region,
+    serialize::Storage,
+    types::{Mat4, Ray, Vec3, Vertex, AABB},
+};
+
+#[derive(Clone, Copy)]
+pub struct PrimitiveHit {
+    pub t: f32,
+    pub u: f32,
+    pub v: f32,
+}
+
+// Where a ray hit a primitive, in the space the primitive was built in. `error`
+// bounds the absolute error of `position` on every axis and `normal` is the
+// normalized geometric normal.
+#[derive(Clone, Copy)]
+pub struct SurfacePoint {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub error: Vec3,
+}
+
+impl SurfacePoint {
+    // The point at `t` along the ray. The bound assumes `t` is accurate to a few
+    // ulps, shapes that can recompute the point from their own data should.
+    pub fn along_ray(ray: &Ray, t: f32, normal: Vec3) -> Self {
+        let offset = ray.direction * t;
+        Self {
+            position: ray.origin + offset,
+            normal,
+            error: (ray.origin.map(f32::abs) + offset.map(f32::abs)) * gamma(5),
+        }
+    }
+
+    // Moves the point to the space `transform` maps to, including the rounding
+    // error of the transform itself
+    pub fn transformed(&self, transform: &Mat4) -> Self {
+        let mut error = Vec3::new(0.0, 0.0, 0.0);
+        for row in 0..3 {
+            let mut propagated = 0.0;
+            let mut rounding = transform[3][row].abs();
+            for column in 0..3 {
+                let m = transform[column][row];
+                propagated += m.abs() * self.error[column];
+                rounding += (m * self.position[column]).abs();
+            }
+            error[row] = (1.0 + gamma(3)) * propagated + gamma(3) * rounding;
+        }
+
+        let normal_transform = transform.invert().unwrap().transpose();
+        let normal = (normal_transform * self.normal.extend(0.0)).truncate();
+        Self {
+            position: (transform * self.position.extend(1.0)).truncate(),
+            normal: if normal.magnitude2() > 0.0 {
+                normal.normalize()
+            } else {
+                normal
+            },
+            error,
+        }
+    }
+}
+
+// A shape that can be stored in a `Bvh`. Rays are in the space the primitive was
+// built in and have a normalized direction. Hits outside the ray's interval have
+// to be ignored, including the near hit of a shape the interval starts inside.
+pub trait Primitive: Sync {
+    fn aabb(&self) -> AABB;
+
+    fn centroid(&self) -> Vec3 {
+        let aabb = self.aabb();
+        (aabb.min + aabb.max) * 0.5
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<PrimitiveHit>;
+
+    // Hit point and geometric normal of a hit returned by `intersect`. Shapes
+    // without a normal face the ray.
+    fn surface(&self, ray: &Ray, hit: &PrimitiveHit) -> SurfacePoint {
+        SurfacePoint::along_ray(ray, hit.t, -ray.direction)
+    }
+}
+
+// The primitives a `Bvh` is built over, addressed by their index. Implemented for
+// a `Vec` of any `Primitive`, and directly by `TriangleMesh` so triangles can
+// keep sharing vertices.
+pub trait PrimitiveSet: Sync {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn aabb(&self, primitive: usize) -> AABB;
+
+    fn centroid(&self, primitive: usize) -> Vec3;
+
+    fn intersect(&self, primitive: usize, ray: &Ray) -> Option<PrimitiveHit>;
+
+    // See `Primitive::surface`
+    fn surface(&self, _primitive: usize, ray: &Ray, hit: &PrimitiveHit) -> SurfacePoint {
+        SurfacePoint::along_ray(ray, hit.t, -ray.direction)
+    }
+
+    // Sides rays may not hit, only sets whose primitives have sides cull anything
+    fn cull_mode(&self) -> CullMode {
+        CullMode::None
+    }
+
+    // `intersect` with `cull_mode` instead of the set's own, so instances can
+    // override it
+    fn intersect_culled(
+        &self,
+        primitive: usize,
+        ray: &Ray,
+        _cull_mode: CullMode,
+    ) -> Option<PrimitiveHit> {
+        self.intersect(primitive, ray)
+    }
+
+    // Tests one primitive against the `active` lanes of a packet and records hits
+    // closer than the lane's current one. Tests the lanes one by one unless the
+    // set has a faster way.
+    fn intersect_packet<const N: usize>(
+        &self,
+        primitive: usize,
+        packet: &RayPacket<N>,
+        active: u32,
+        cull_mode: CullMode,
+        hits: &mut PacketHits<N>,
+    ) {
+        for lane in lanes(active) {
+            let ray = packet.ray(lane);
+            let ray = ray.with_interval(ray.t_min, hits.t[lane]);
+            if let Some(hit) = self.intersect_culled(primitive, &ray, cull_mode) {
+                hits.record(lane, hit.t, hit.u, hit.v, primitive as _);
+            }
+        }
+    }
+
+    // Whether the primitive overlaps `region`, tested with its bounding box unless
+    // the set knows its exact shape
+    fn overlaps<R: Region>(&self, primitive: usize, region: &R) -> bool {
+        region.overlaps_aabb(&self.aabb(primitive))
+    }
+
+    // Bounds of the parts of a primitive on either side of an axis aligned plane,
+    // used by spatial splits. Splitting the bounding box is always conservative,
+    // shapes that can do better should clip themselves.
+    fn split(&self, primitive: usize, axis: usize, position: f32) -> (AABB, AABB) {
+        let mut left = self.aabb(primitive);
+        let mut right = left;
+        left.max[axis] = left.max[axis].min(position);
+        right.min[axis] = right.min[axis].max(position);
+        (left, right)
+    }
+}
+
+impl<P: Primitive> PrimitiveSet for Vec<P> {
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn aabb(&self, primitive: usize) -> AABB {
+        self[primitive].aabb()
+    }
+
+    fn centroid(&self, primitive: usize) -> Vec3 {
+        self[primitive].centroid()
+    }
+
+    fn intersect(&self, primitive: usize, ray: &Ray) -> Option<PrimitiveHit> {
+        self[primitive].intersect(ray)
+    }
+
+    fn surface(&self, primitive: usize, ray: &Ray, hit: &PrimitiveHit) -> SurfacePoint {
+        self[primitive].surface(ray, hit)
+    }
+}
+
+// Indexed triangles, every three indices form a triangle
+pub struct TriangleMesh {
+    vertices: Storage<Vertex>,
+    indices: Storage<u32>,
+    intersection: TriangleIntersection,
+    cull_mode: CullMode,
+}
+
+impl TriangleMesh {
+    pub fn new(vertices: &[Vertex], indices: &[u32]) -> Self {
+        Self::from_storage(vertices.to_vec().into(), indices.to_vec().into())
+    }
+
+    pub(crate) fn from_storage(vertices: Storage<Vertex>, indices: Storage<u32>) -> Self {
+        Self {
+            vertices,
+            indices,
+            intersection: TriangleIntersection::default(),
+            cull_mode: CullMode::default(),
+        }
+    }
+
+    pub fn with_intersection(mut self, intersection: TriangleIntersection) -> Self {
+        self.intersection = intersection;
+        self
+    }
+
+    pub fn intersection(&self) -> TriangleIntersection {
+        self.intersection
+    }
+
+    pub fn set_intersection(&mut self, intersection: TriangleIntersection) {
+        self.intersection = intersection;
+    }
+
+    pub fn with_cull_mode(mut self, cull_mode: CullMode) -> Self {
+        self.cull_mode = cull_mode;
+        self
+    }
+
+    pub fn set_cull_mode(&mut self, cull_mode: CullMode) {
+        self.cull_mode = cull_mode;
+    }
+
+    pub fn vertices(&self) -> &[Vertex] {
+        &self.vertices
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    pub(crate) fn vertices_mut(&mut self) -> &mut Vec<Vertex> {
+        self.vertices.to_mut()
+    }
+
+    pub(crate) fn vertices_mapped(&self) -> bool {
+        self.vertices.is_mapped()
+    }
+
+    #[inline]
+    pub fn triangle(&self, primitive: usize) -> [Vertex; 3] {
+        let first = primitive * 3;
+        [
+            self.vertices[self.indices[first] as usize],
+            self.vertices[self.indices[first + 1] as usize],
+            self.vertices[self.indices[first + 2] as usize],
+        ]
+    }
+}
+
+impl PrimitiveSet for TriangleMesh {
+    fn len(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    fn aabb(&self, primitive: usize) -> AABB {
+        let mut aabb = AABB::default();
+        self.triangle(primitive)
+            .iter()
+            .for_each(|v| aabb.grow_with_position(v));
+        aabb
+    }
+
+    fn centroid(&self, primitive: usize) -> Vec3 {
+        let [v0, v1, v2] = self.triangle(primitive);
+        (v0 + v1 + v2) / 3.0
+    }
+
+    #[inline]
+    fn intersect(&self, primitive: usize, ray: &Ray) -> Option<PrimitiveHit> {
+        self.intersect_culled(primitive, ray, self.cull_mode)
+    }
+
+    fn cull_mode(&self) -> CullMode {
+        self.cull_mode
+    }
+
+    #[inline]
+    fn intersect_culled(
+        &self,
+        primitive: usize,
+        ray: &Ray,
+        cull_mode: CullMode,
+    ) -> Option<PrimitiveHit> {
+        let [v0, v1, v2] = self.triangle(primitive);
+        if cull_mode.culls((v1 - v0).cross(v2 - v0), ray.direction) {
+            return None;
+        }
+
+        let mut hit = PrimitiveHit {
+            t: 0.0,
+            u: 0.0,
+            v: 0.0,
+        };
+        let (t, u, v) = (&mut hit.t, &mut hit.u, &mut hit.v);
+        match self.intersection {
+            TriangleIntersection::MollerTrumbore => intersect_triangle(ray, &v0, &v1, &v2, t, u, v),
+            TriangleIntersection::Watertight => {
+                intersect_triangle_watertight(ray, &v0, &v1, &v2, t, u, v)
+            }
+        }
+        .then_some(hit)
+    }
+
+    // Interpolates the vertices instead of stepping along the ray, which keeps
+    // the error bound small however far away the ray started. The normal
+    // follows the winding, so it faces rays that hit the front.
+    fn surface(&self, primitive: usize, _ray: &Ray, hit: &PrimitiveHit) -> SurfacePoint {
+        let [v0, v1, v2] = self.triangle(primitive);
+        let weighted = [v0 * (1.0 - hit.u - hit.v), v1 * hit.u, v2 * hit.v];
+        let normal = (v1 - v0).cross(v2 - v0);
+        SurfacePoint {
+            position: weighted[0] + weighted[1] + weighted[2],
+            normal: if normal.magnitude2() > 0.0 {
+                normal.normalize()
+            } else {
+                normal
+            },
+            error: weighted
+                .iter()
+                .fold(Vec3::new(0.0, 0.0, 0.0), |error, v| error + v.map(f32::abs))
+                * gamma(7),
+        }
+    }
+
+    #[inline]
+    fn intersect_packet<const N: usize>(
+        &self,
+        primitive: usize,
+        packet: &RayPacket<N>,
+        active: u32,
+        cull_mode: CullMode,
+        hits: &mut PacketHits<N>,
+    ) {
+        let triangle = self.triangle(primitive);
+        // Culled lanes are dropped up front so the SIMD test doesn't need to know
+        let active = if cull_mode == CullMode::None {
+            active
+        } else {
+            let [v0, v1, v2] = triangle;
+            let normal = (v1 - v0).cross(v2 - v0);
+            lanes(active)
+                .filter(|lane| !cull_mode.culls(normal, packet.ray(*lane).direction))
+                .fold(0, |mask, lane| mask | 1 << lane)
+        };
+
+        match self.intersection {
+            TriangleIntersection::MollerTrumbore => {
+                intersect_triangle_packet(&triangle, primitive as _, packet, active, hits)
+            }
+            // Only Möller-Trumbore has a SIMD version
+            TriangleIntersection::Watertight => {
+                for lane in lanes(active) {
+                    let ray = packet.ray(lane);
+                    let ray = ray.with_interval(ray.t_min, hits.t[lane]);
+                    if let Some(hit) = self.intersect_culled(primitive, &ray, CullMode::None) {
+                        hits.record(lane, hit.t, hit.u, hit.v, primitive as _);
+                    }
+                }
+            }
+        }
+    }
+
+    fn overlaps<R: Region>(&self, primitive: usize, region: &R) -> bool {
+        region.overlaps_triangle(&self.triangle(primitive))
+    }
+
+    // Clips the triangle against the plane, so the halves are often much smaller
+    // than the halves of its bounding box
+    fn split(&self, primitive: usize, axis: usize, position: f32) -> (AABB, AABB) {
+        let triangle = self.triangle(primitive);
+        let mut left = AABB::default();
+        let mut right = AABB::default();
+        for i in 0..3 {
+            let v0 = triangle[i];
+            let v1 = triangle[(i + 1) % 3];
+            let p0 = v0[axis];
+            let p1 = v1[axis];
+            if p0 <= position {
+                left.grow_with_position(&v0);
+            }
+            if p0 >= position {
+                right.grow_with_position(&v0);
+            }
+            if (p0 < position && p1 > position) || (p0 > position && p1 < position) {
+                let t = ((position - p0) / (p1 - p0)).clamp(0.0, 1.0);
+                let p = v0 + (v1 - v0) * t;
+                left.grow_with_position(&p);
+                right.grow_with_position(&p);
+            }
+        }
+
+        left.max[axis] = position;
+        right.min[axis] = position;
+        (left, right)
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Sphere {
+    pub center: Vec3,
+    pub radius: f32,
+}
+
+impl Primitive for Sphere {
+    fn aabb(&self) -> AABB {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        AABB::new(self.center - r, self.center + r)
+    }
+
+    fn centroid(&self) -> Vec3 {
+        self.center
+    }
+
+    // u and v are the longitude and latitude of the hit point, scaled to [0, 1]
+    fn intersect(&self, ray: &Ray) -> Option<PrimitiveHit> {
+        let oc = ray.origin - self.center;
+        let b = oc.dot(ray.direction);
+        let c = oc.dot(oc) - self.radius * self.radius;
+        let discriminant = b * b - c;
+        if discriminant < 0.0 {
+            return None;
+        }
+
+        let root = discriminant.sqrt();
+        let t = [-b - root, -b + root]
+            .into_iter()
+            .find(|t| in_interval(ray, *t))?;
+
+        let n = (ray.origin + ray.direction * t - self.center) / self.radius;
+        Some(PrimitiveHit {
+            t,
+            u: 0.5 + n.z.atan2(n.x) / (2.0 * PI),
+            v: 0.5 - n.y.clamp(-1.0, 1.0).asin() / PI,
+        })
+    }
+
+    // Projects the point back onto the sphere, so its error doesn't grow with
+    // the distance the ray traveled
+    fn surface(&self, ray: &Ray, hit: &PrimitiveHit) -> SurfacePoint {
+        let normal = (ray.origin + ray.direction * hit.t - self.center).normalize();
+        let offset = normal * self.radius;
+        SurfacePoint {
+            position: self.center + offset,
+            normal,
+            error: offset.map(f32::abs) * gamma(5) + self.center.map(f32::abs) * gamma(1),
+        }
+    }
+}
+
+// A cylinder with hemispherical caps around the segment from `a` to `b`
+#[derive(Clone, Copy)]
+pub struct Capsule {
+    pub a: Vec3,
+    pub b: Vec3,
+    pub radius: f32,
+}
+
+impl Primitive for Capsule {
+    fn aabb(&self) -> AABB {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        let mut aabb = AABB::new(self.a - r, self.a + r);
+        aabb.grow(&AABB::new(self.b - r, self.b + r));
+        aabb
+    }
+
+    fn centroid(&self) -> Vec3 {
+        (self.a + self.b) * 0.5
+    }
+
+    // u is the position of the hit along the segment from `a` to `b`, v is unused
+    fn intersect(&self, ray: &Ray) -> Option<PrimitiveHit> {
+        let ba = self.b - self.a;
+        let baba = ba.dot(ba);
+        // Position along the axis, scaled by its squared length
+        let axial = |t: f32| (ray.origin + ray.direction * t - self.a).dot(ba);
+
+        // The capsule is convex, so every candidate that lies on the part of the
+        // surface it belongs to is a real crossing and the closest one wins
+        let mut closest = f32::MAX;
+        let mut candidate = |t: f32, valid: bool| {
+            if valid && in_interval(ray, t) && t < closest {
+                closest = t;
+            }
+        };
+
+        let oa = ray.origin - self.a;
+        let bard = ba.dot(ray.direction);
+        let baoa = ba.dot(oa);
+        let a = baba - bard * bard;
+        let b = baba * ray.direction.dot(oa) - baoa * bard;
+        let c = baba * oa.dot(oa) - baoa * baoa - self.radius * self.radius * baba;
+        let h = b * b - a * c;
+        if h >= 0.0 && a > 0.0 {
+            for t in [(-b - h.sqrt()) / a, (-b + h.sqrt()) / a] {
+                let y = axial(t);
+                candidate(t, y > 0.0 && y < baba);
+            }
+        }
+
+        for (center, cap) in [(self.a, 0.0), (self.b, baba)] {
+            let oc = ray.origin - center;
+            let b = oc.dot(ray.direction);
+            let h = b * b - oc.dot(oc) + self.radius * self.radius;
+            if h >= 0.0 {
+                for t in [-b - h.sqrt(), -b + h.sqrt()] {
+                    let y = axial(t);
+                    candidate(t, if cap == 0.0 { y <= 0.0 } else { y >= baba });
+                }
+            }
+        }
+
+        (closest < f32::MAX).then(|| PrimitiveHit {
+            t: closest,
+            u: if baba > 0.0 {
+                (axial(closest) / baba).clamp(0.0, 1.0)
+            } else {
+                0.0
+            },
+            v: 0.0,
+        })
+    }
+
+    // Projects the point back onto the capsule, see `Sphere::surface`
+    fn surface(&self, ray: &Ray, hit: &PrimitiveHit) -> SurfacePoint {
+        let ba = self.b - self.a;
+        let axis = self.a + ba * hit.u;
+        let normal = (ray.origin + ray.direction * hit.t - axis).normalize();
+        let offset = normal * self.radius;
+        SurfacePoint {
+            position: axis + offset,
+            normal,
+            error: (axis.map(f32::abs) + offset.map(f32::abs)) * gamma(7),
+        }
+    }
+}
+
+impl Primitive for AABB {
+    fn aabb(&self) -> AABB {
+        *self
+    }
+
+    // Hits the box where the ray enters it, or where it leaves it when the ray
+    // starts inside
+    fn intersect(&self, ray: &Ray) -> Option<PrimitiveHit> {
+        let inv_direction = ray.direction.map(|x| 1.0 / x);
+        let mut near = f32::MIN;
+        let mut far = f32::MAX;
+        for axis in 0..3 {
+            let t1 = (self.min[axis] - ray.origin[axis]) * inv_direction[axis];
+            let t2 = (self.max[axis] - ray.origin[axis]) * inv_direction[axis];
+            near = near.max(t1.min(t2));
+            far = far.min(t1.max(t2));
+        }
+        if near > far {
+            return None;
+        }
+
+        let t = [near, far].into_iter().find(|t| in_interval(ray, *t))?;
+        Some(PrimitiveHit { t, u: 0.0, v: 0.0 })
+    }
+
+    // Snaps the point to the face it lies on, which is exact along the normal
+    fn surface(&self, ray: &Ray, hit: &PrimitiveHit) -> SurfacePoint {
+        let mut surface = SurfacePoint::along_ray(ray, hit.t, Vec3::new(0.0, 0.0, 0.0));
+        let position = surface.position;
+        let (axis, sign, bound) = (0..3)
+            .flat_map(|axis| [(axis, -1.0, self.min[axis]), (axis, 1.0, self.max[axis])])
+            .min_by(|(a, _, bound_a), (b, _, bound_b)| {
+                (position[*a] - bound_a)
+                    .abs()
+                    .total_cmp(&(position[*b] - bound_b).abs())
+            })
+            .unwrap();
+
+        surface.position[axis] = bound;
+        surface.error[axis] = 0.0;
+        surface.normal[axis] = sign;
+        surface
+    }
+}
+
+// Whether a hit at `t` counts for the ray. Hits right at the origin are skipped
+// like in `intersect_triangle`.
+fn in_interval(ray: &Ray, t: f32) -> bool {
+    t > ray.t_min.max(0.000001) && t < ray.t_max
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::SquareMatrix;
+
+    use super::{Capsule, Primitive, Sphere};
+    use crate::{
+        bvh::{BuildStrategy, Bvh, BvhBuildOptions, MortonCode},
+        testing::Rng,
+        types::{HitRecord, Mat4, Ray, Vec3, AABB},
+    };
+
+    fn ray(origin: Vec3, direction: Vec3) -> Ray {
+        Ray::new(origin, direction).transformed(&Mat4::identity())
+    }
+
+    // Distance and normal of the hit, None for a miss
+    fn hit<P: Primitive>(primitive: &P, ray: &Ray) -> Option<(f32, Vec3)> {
+        let hit = primitive.intersect(ray)?;
+        Some((hit.t, primitive.surface(ray, &hit).normal))
+    }
+
+    #[test]
+    fn primitives_hit_where_expected() {
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        let x = Vec3::new(1.0, 0.0, 0.0);
+        let front = Vec3::new(0.0, 0.0, -5.0);
+
+        let sphere = Sphere {
+            center: Vec3::new(0.0, 0.0, 0.0),
+            radius: 1.0,
+        };
+        assert_eq!(hit(&sphere, &ray(front, z)), Some((4.0, -z)));
+        // From inside only the far side is hit
+        assert_eq!(
+            hit(&sphere, &ray(Vec3::new(0.0, 0.0, 0.0), z)),
+            Some((1.0, z))
+        );
+        assert_eq!(hit(&sphere, &ray(front, x)), None);
+        assert!(hit(&sphere, &ray(front, z).with_interval(0.0, 3.9)).is_none());
+
+        let capsule = Capsule {
+            a: Vec3::new(-1.0, 0.0, 0.0),
+            b: Vec3::new(1.0, 0.0, 0.0),
+            radius: 0.5,
+        };
+        assert_eq!(hit(&capsule, &ray(front, z)), Some((4.5, -z)));
+        assert_eq!(
+            hit(&capsule, &ray(Vec3::new(-5.0, 0.0, 0.0), x)),
+            Some((3.5, -x))
+        );
+        assert_eq!(hit(&capsule, &ray(Vec3::new(0.0, 1.0, -5.0), z)), None);
+
+        let aabb = AABB::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0));
+        assert_eq!(hit(&aabb, &ray(front, z)), Some((4.0, -z)));
+        assert_eq!(
+            hit(&aabb, &ray(Vec3::new(0.0, 0.0, 0.0), x)),
+            Some((1.0, x))
+        );
+        assert_eq!(hit(&aabb, &ray(Vec3::new(0.0, 2.0, -5.0), z)), None);
+    }
+
+    // Builds a tree over `primitives` with every strategy and checks it finds the
+    // same closest hits as testing all of them
+    fn assert_tree_matches<P: Primitive + Clone>(primitives: Vec<P>) {
+        let strategies = [
+            BuildStrategy::BinnedSah,
+            BuildStrategy::SpatialSah {
+                max_duplication: 0.3,
+            },
+            BuildStrategy::Linear {
+                morton_code: MortonCode::Bits30,
+            },
+            BuildStrategy::Ploc { search_radius: 8 },
+        ];
+        for strategy in strategies {
+            let options = BvhBuildOptions::new().with_strategy(strategy);
+            let bvh = Bvh::from_primitives(primitives.clone(), &options);
+            let mut rng = Rng::new(24);
+            for _ in 0..500 {
+                let ray = rng.ray();
+                let expected = primitives
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, p)| {
+                        p.intersect(&ray.transformed(&Mat4::identity()))
+                            .map(|h| (h.t, i))
+                    })
+                    .min_by(|a, b| a.0.total_cmp(&b.0));
+                let mut hit = HitRecord::new();
+                bvh.traverse(&ray, &Mat4::identity(), &mut hit);
+                match expected {
+                    Some((t, i)) => {
+                        assert!((hit.t - t).abs() <= t * 1e-5);
+                        assert_eq!(hit.primitive_id, i as u32);
+                    }
+                    None => assert_eq!(hit.t, f32::MAX),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn primitive_trees_match_brute_force() {
+        let mut rng = Rng::new(25);
+        let spheres: Vec<Sphere> = (0..1000)
+            .map(|_| Sphere {
+                center: rng.vec3(-2.0, 2.0),
+                radius: rng.range(0.01, 0.2),
+            })
+            .collect();
+        let capsules: Vec<Capsule> = (0..1000)
+            .map(|_| {
+                let a = rng.vec3(-2.0, 2.0);
+                Capsule {
+                    a,
+                    b: a + rng.vec3(-0.5, 0.5),
+                    radius: rng.range(0.01, 0.1),
+                }
+            })
+            .collect();
+        let boxes: Vec<AABB> = spheres.iter().map(|sphere| sphere.aabb()).collect();
+        assert_tree_matches(spheres);
+        assert_tree_matches(capsules);
+        assert_tree_matches(boxes);
+    }
+}
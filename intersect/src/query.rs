@@ -0,0 +1,279 @@
+use cgmath::{InnerSpace, Matrix};
+
+use crate::{
+    intersect::{closest_point_on_triangle, overlap_triangle_aabb},
+    primitive::Sphere,
+    types::{Mat4, Vec3, Vec4, Vertex, AABB},
+};
+
+// A volume the acceleration structures can be searched with. Both tests may
+// report overlaps that aren't there, but never miss one.
+pub trait Region {
+    fn overlaps_aabb(&self, aabb: &AABB) -> bool;
+
+    fn overlaps_triangle(&self, triangle: &[Vertex; 3]) -> bool {
+        let mut aabb = AABB::default();
+        triangle.iter().for_each(|v| aabb.grow_with_position(v));
+        self.overlaps_aabb(&aabb)
+    }
+}
+
+impl Region for AABB {
+    fn overlaps_aabb(&self, aabb: &AABB) -> bool {
+        (0..3).all(|axis| self.min[axis] <= aabb.max[axis] && self.max[axis] >= aabb.min[axis])
+    }
+
+    fn overlaps_triangle(&self, [v0, v1, v2]: &[Vertex; 3]) -> bool {
+        overlap_triangle_aabb(v0, v1, v2, self)
+    }
+}
+
+impl Region for Sphere {
+    fn overlaps_aabb(&self, aabb: &AABB) -> bool {
+        aabb.distance_squared(&self.center) <= self.radius * self.radius
+    }
+
+    fn overlaps_triangle(&self, [v0, v1, v2]: &[Vertex; 3]) -> bool {
+        let (p, _, _) = closest_point_on_triangle(&self.center, v0, v1, v2);
+        (p - self.center).magnitude2() <= self.radius * self.radius
+    }
+}
+
+// Convex volume bounded by six planes. A point p is inside a plane (n, d) when
+// n.p + d >= 0.
+#[derive(Clone, Copy)]
+pub struct Frustum {
+    pub planes: [Vec4; 6],
+}
+
+impl Frustum {
+    pub fn new(planes: [Vec4; 6]) -> Self {
+        Self { planes }
+    }
+
+    // Extracts the planes of a view projection matrix. Exact for clip space
+    // depth in [-1, 1] and slightly too large for depth in [0, 1].
+    pub fn from_matrix(view_projection: &Mat4) -> Self {
+        let row = |i| view_projection.row(i);
+        Self::new([
+            row(3) + row(0),
+            row(3) - row(0),
+            row(3) + row(1),
+            row(3) - row(1),
+            row(3) + row(2),
+            row(3) - row(2),
+        ])
+    }
+
+    fn distance(plane: &Vec4, p: &Vec3) -> f32 {
+        plane.truncate().dot(*p) + plane.w
+    }
+}
+
+impl Region for Frustum {
+    // Tests the corner of the box farthest along each plane normal, so boxes
+    // near the frustum's edges can be reported although they are outside
+    fn overlaps_aabb(&self, aabb: &AABB) -> bool {
+        self.planes.iter().all(|plane| {
+            let corner = Vec3::new(
+                if plane.x >= 0.0 {
+                    aabb.max.x
+                } else {
+                    aabb.min.x
+                },
+                if plane.y >= 0.0 {
+                    aabb.max.y
+                } else {
+                    aabb.min.y
+                },
+                if plane.z >= 0.0 {
+                    aabb.max.z
+                } else {
+                    aabb.min.z
+                },
+            );
+            Self::distance(plane, &corner) >= 0.0
+        })
+    }
+
+    fn overlaps_triangle(&self, triangle: &[Vertex; 3]) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| triangle.iter().any(|v| Self::distance(plane, v) >= 0.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ops::ControlFlow, sync::Arc};
+
+    use cgmath::{perspective, Deg, EuclideanSpace, Point3, Rad, SquareMatrix};
+
+    use super::{Frustum, Region};
+    use crate::{
+        bvh::{BuildStrategy, Bvh, BvhBuildOptions},
+        primitive::Sphere,
+        testing::{slivers, soup, Rng},
+        top_level_acceleration_structure::{Instance, TopLevelAccelerationStructure},
+        types::{Mat4, Vec3, Vertex, AABB},
+    };
+
+    fn brute_force<R: Region>(vertices: &[Vertex], region: &R) -> Vec<u32> {
+        (0..vertices.len() / 3)
+            .filter(|i| {
+                region.overlaps_triangle(&[
+                    vertices[3 * i],
+                    vertices[3 * i + 1],
+                    vertices[3 * i + 2],
+                ])
+            })
+            .map(|i| i as u32)
+            .collect()
+    }
+
+    #[test]
+    fn spatial_splits_report_primitives_once() {
+        let (vertices, indices) = slivers(3000);
+        let options = BvhBuildOptions::new().with_strategy(BuildStrategy::SpatialSah {
+            max_duplication: 0.5,
+        });
+        let bvh = Bvh::new_with_options(&vertices, &indices, &options);
+        assert!(bvh.primitives().len() > indices.len() / 3);
+
+        let mut rng = Rng::new(7);
+        for _ in 0..50 {
+            let center = rng.vec3(-2.0, 2.0);
+            let extent = rng.vec3(0.0, 0.5);
+            let aabb = AABB::new(center - extent, center + extent);
+            let mut found = bvh.overlapping(&aabb);
+            found.sort_unstable();
+            assert_eq!(found, brute_force(&vertices, &aabb));
+
+            let sphere = Sphere {
+                center,
+                radius: rng.range(0.0, 0.6),
+            };
+            let mut found = bvh.overlapping(&sphere);
+            found.sort_unstable();
+            assert_eq!(found, brute_force(&vertices, &sphere));
+        }
+    }
+
+    // A box, a sphere and a camera frustum somewhere around the [-2, 2] cube
+    fn regions(rng: &mut Rng) -> (AABB, Sphere, Frustum) {
+        let center = rng.vec3(-2.0, 2.0);
+        let extent = rng.vec3(0.0, 0.5);
+        let sphere = Sphere {
+            center,
+            radius: rng.range(0.0, 0.6),
+        };
+        let eye = Point3::new(rng.range(-1.0, 1.0), rng.range(-1.0, 1.0), -6.0);
+        let view = Mat4::look_at_rh(eye, Point3::from_vec(center), Vec3::unit_y());
+        let projection = perspective(Deg(rng.range(5.0, 30.0)), 1.0, 1.0, 7.0);
+        let frustum = Frustum::from_matrix(&(projection * view));
+        (AABB::new(center - extent, center + extent), sphere, frustum)
+    }
+
+    fn sorted(mut primitives: Vec<u32>) -> Vec<u32> {
+        primitives.sort_unstable();
+        primitives
+    }
+
+    fn query_all<R: Region>(bvh: &Bvh, region: &R, transform: &Mat4) -> Vec<u32> {
+        let mut primitives = Vec::new();
+        bvh.query_transformed(region, transform, |primitive| {
+            primitives.push(primitive);
+            ControlFlow::Continue(())
+        });
+        sorted(primitives)
+    }
+
+    // Queries the tree in place and moved to `world` by `transform`, returns the
+    // number of primitives found in place
+    fn assert_matches_brute_force<R: Region>(
+        bvh: &Bvh,
+        region: &R,
+        vertices: &[Vertex],
+        world: &[Vertex],
+        transform: &Mat4,
+    ) -> usize {
+        let expected = brute_force(vertices, region);
+        assert_eq!(sorted(bvh.overlapping(region)), expected);
+        assert_eq!(query_all(bvh, region, &Mat4::identity()), expected);
+        assert_eq!(
+            query_all(bvh, region, transform),
+            brute_force(world, region)
+        );
+        expected.len()
+    }
+
+    #[test]
+    fn region_queries_match_brute_force() {
+        let (vertices, indices) = soup(2000, 41);
+        let bvh = Bvh::new(&vertices, &indices);
+        let transform = Mat4::from_translation(Vec3::new(0.5, 0.0, 0.5))
+            * Mat4::from_angle_y(Rad(0.6))
+            * Mat4::from_scale(1.2);
+        let world: Vec<Vertex> = vertices
+            .iter()
+            .map(|v| (transform * v.extend(1.0)).truncate())
+            .collect();
+
+        let mut rng = Rng::new(42);
+        let mut found = [0; 3];
+        for _ in 0..100 {
+            let (aabb, sphere, frustum) = regions(&mut rng);
+            found[0] += assert_matches_brute_force(&bvh, &aabb, &vertices, &world, &transform);
+            found[1] += assert_matches_brute_force(&bvh, &sphere, &vertices, &world, &transform);
+            found[2] += assert_matches_brute_force(&bvh, &frustum, &vertices, &world, &transform);
+        }
+        assert!(found.iter().all(|found| *found > 0));
+    }
+
+    #[test]
+    fn instance_queries_match_brute_force() {
+        let (vertices, indices) = soup(300, 43);
+        let blas = Arc::new(Bvh::new(&vertices, &indices));
+        let mut rng = Rng::new(44);
+        let transforms: Vec<Mat4> = (0..8)
+            .map(|_| Mat4::from_translation(rng.vec3(-2.0, 2.0)) * Mat4::from_scale(0.5))
+            .collect();
+        let instances: Vec<Instance> = (0..8)
+            .map(|id| Instance::new(blas.clone(), id, transforms[id as usize]))
+            .collect();
+        let tlas = TopLevelAccelerationStructure::new(&instances);
+
+        for _ in 0..50 {
+            let (aabb, _, _) = regions(&mut rng);
+            let mut expected = Vec::new();
+            for (id, transform) in transforms.iter().enumerate() {
+                let world: Vec<Vertex> = vertices
+                    .iter()
+                    .map(|v| (transform * v.extend(1.0)).truncate())
+                    .collect();
+                expected.extend(
+                    brute_force(&world, &aabb)
+                        .into_iter()
+                        .map(|p| (id as u32, p)),
+                );
+            }
+            expected.sort_unstable();
+
+            let mut found: Vec<(u32, u32)> = tlas
+                .overlapping_primitives(&aabb, 0.0)
+                .into_iter()
+                .map(|(i, p)| (tlas.instances()[i as usize].id(), p))
+                .collect();
+            found.sort_unstable();
+            assert_eq!(found, expected);
+
+            // Breaking out of the visitor ends the query
+            let mut visited = 0;
+            tlas.query_primitives(&aabb, 0.0, |_, _| {
+                visited += 1;
+                ControlFlow::Break(())
+            });
+            assert_eq!(visited, expected.len().min(1));
+        }
+    }
+}
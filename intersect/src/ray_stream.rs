@@ -0,0 +1,267 @@
+use rayon::prelude::*;
+
+use crate::{
+    builder::morton::radix_sort,
+    bvh::{Bvh, MortonCode},
+    packet::RayPacket16,
+    primitive::PrimitiveSet,
+    top_level_acceleration_structure::TopLevelAccelerationStructure,
+    types::{HitRecord, Mat4, Ray, AABB},
+};
+
+// How rays are ordered before tracing. Rays with nearby keys start close to each
+// other and travel in similar directions, so they visit the same nodes.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RaySortKey {
+    // Cell of a uniform grid over the origins, then the octant of the direction.
+    // At most 1024 cells per axis.
+    OriginOctant { cells_per_axis: u32 },
+    // Morton code of the origin followed by a Morton code of the direction
+    Morton,
+}
+
+impl RaySortKey {
+    fn bits(&self) -> u32 {
+        match self {
+            RaySortKey::OriginOctant { .. } => 33,
+            RaySortKey::Morton => 60,
+        }
+    }
+
+    // `origin` is relative to the bounds of all origins, scaled to [0, 1]
+    fn key(&self, origin: [f32; 3], ray: &Ray) -> u64 {
+        match self {
+            RaySortKey::OriginOctant { cells_per_axis } => {
+                let scale = *cells_per_axis.clamp(&1, &1024) as f32 / 1024.0;
+                let [x, y, z] = origin.map(|v| (v * scale).min(scale - f32::EPSILON));
+                let octant = (ray.direction.x < 0.0) as u64
+                    | ((ray.direction.y < 0.0) as u64) << 1
+                    | ((ray.direction.z < 0.0) as u64) << 2;
+                MortonCode::Bits30.encode(x, y, z) << 3 | octant
+            }
+            RaySortKey::Morton => {
+                let [x, y, z] = origin;
+                let direction = ray.direction.map(|d| d * 0.5 + 0.5);
+                MortonCode::Bits30.encode(x, y, z) << 30
+                    | MortonCode::Bits30.encode(direction.x, direction.y, direction.z)
+            }
+        }
+    }
+}
+
+// Traces large batches of rays, like a whole bounce of a path tracer, in an
+// order that keeps neighbouring rays coherent. The rays are sorted by
+// `sort_key`, split into groups of `group_size` that are traced in parallel as
+// packets, and the results are returned in the order the rays were given.
+#[derive(Clone, Copy)]
+pub struct RayStream {
+    pub sort_key: RaySortKey,
+    pub group_size: usize,
+}
+
+impl RayStream {
+    pub fn new() -> Self {
+        Self {
+            sort_key: RaySortKey::OriginOctant { cells_per_axis: 32 },
+            group_size: 256,
+        }
+    }
+
+    pub fn with_sort_key(mut self, sort_key: RaySortKey) -> Self {
+        self.sort_key = sort_key;
+        self
+    }
+
+    pub fn with_group_size(mut self, group_size: usize) -> Self {
+        self.group_size = group_size;
+        self
+    }
+
+    // Indices of `rays` in the order they are traced
+    pub fn sort(&self, rays: &[Ray]) -> Vec<u32> {
+        let bounds = rays
+            .par_iter()
+            .fold(AABB::default, |mut aabb, ray| {
+                aabb.grow_with_position(&ray.origin);
+                aabb
+            })
+            .reduce(AABB::default, |mut lhs, rhs| {
+                lhs.grow(&rhs);
+                lhs
+            });
+
+        let extent = bounds.extent();
+        let inv_extent = extent.map(|e| if e > 0.0 { 1.0 / e } else { 0.0 });
+        let mut keys: Vec<(u64, u32)> = rays
+            .par_iter()
+            .enumerate()
+            .map(|(i, ray)| {
+                let p = ray.origin - bounds.min;
+                let origin = [p.x * inv_extent.x, p.y * inv_extent.y, p.z * inv_extent.z];
+                (self.sort_key.key(origin, ray), i as u32)
+            })
+            .collect();
+
+        radix_sort(&mut keys, self.sort_key.bits());
+        keys.into_iter().map(|(_, i)| i).collect()
+    }
+
+    // Closest hit of every ray, see `TopLevelAccelerationStructure::traverse`
+    pub fn traverse(
+        &self,
+        acceleration_structure: &TopLevelAccelerationStructure,
+        rays: &[Ray],
+    ) -> Vec<HitRecord> {
+        self.trace(rays, HitRecord::new(), |packet, records| {
+            let hits = acceleration_structure.traverse_packet(packet);
+            records.copy_from_slice(&hits[..records.len()]);
+        })
+    }
+
+    // Closest hit of every ray against a single transformed Bvh, see
+    // `Bvh::traverse`
+    pub fn traverse_bvh<G: PrimitiveSet>(
+        &self,
+        bvh: &Bvh<G>,
+        transform: &Mat4,
+        rays: &[Ray],
+    ) -> Vec<HitRecord> {
+        self.trace(rays, HitRecord::new(), |packet, records| {
+            let mut hits = [HitRecord::new(); 16];
+            bvh.traverse_packet(packet, transform, &mut hits);
+            records.copy_from_slice(&hits[..records.len()]);
+        })
+    }
+
+    // Whether anything blocks each ray before its `t_max`, see
+    // `TopLevelAccelerationStructure::occluded`
+    pub fn occluded(
+        &self,
+        acceleration_structure: &TopLevelAccelerationStructure,
+        rays: &[Ray],
+        t_max: &[f32],
+    ) -> Vec<bool> {
+        assert_eq!(rays.len(), t_max.len(), "Every ray needs a t_max");
+        let rays: Vec<Ray> = rays
+            .iter()
+            .zip(t_max)
+            .map(|(ray, t_max)| ray.with_interval(ray.t_min, ray.t_max.min(*t_max)))
+            .collect();
+        self.trace(&rays, false, |packet, occluded| {
+            for (lane, occluded) in occluded.iter_mut().enumerate() {
+                let ray = packet.ray(lane);
+                *occluded = acceleration_structure.occluded(&ray, ray.t_max);
+            }
+        })
+    }
+
+    // Sorts the rays, hands every group to `trace` as packets of up to 16 rays
+    // and scatters what it writes back to the original order
+    fn trace<T, F>(&self, rays: &[Ray], empty: T, trace: F) -> Vec<T>
+    where
+        T: Copy + Send + Sync,
+        F: Fn(&RayPacket16, &mut [T]) + Sync,
+    {
+        let order = self.sort(rays);
+        let mut sorted = vec![empty; rays.len()];
+        sorted
+            .par_chunks_mut(self.group_size.max(1))
+            .zip(order.par_chunks(self.group_size.max(1)))
+            .for_each(|(results, group)| {
+                for (results, indices) in results.chunks_mut(16).zip(group.chunks(16)) {
+                    let mut packet = RayPacket16::new().with_frustum_culling(true);
+                    for (lane, i) in indices.iter().enumerate() {
+                        packet.set(lane, &rays[*i as usize]);
+                    }
+                    trace(&packet, results);
+                }
+            });
+
+        let mut results = vec![empty; rays.len()];
+        for (i, result) in order.into_iter().zip(sorted) {
+            results[i as usize] = result;
+        }
+        results
+    }
+}
+
+impl Default for RayStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cgmath::{InnerSpace, SquareMatrix};
+
+    use super::{RaySortKey, RayStream};
+    use crate::{
+        bvh::Bvh,
+        testing::{soup, Rng},
+        top_level_acceleration_structure::{Instance, TopLevelAccelerationStructure},
+        types::{HitRecord, Mat4, Ray},
+    };
+
+    #[test]
+    fn streams_keep_the_original_order() {
+        let (vertices, indices) = soup(500, 37);
+        let bvh = Arc::new(Bvh::new(&vertices, &indices));
+        let mut rng = Rng::new(38);
+        let instances: Vec<Instance> = (0..10)
+            .map(|id| Instance::new(bvh.clone(), id, Mat4::from_translation(rng.vec3(-4.0, 4.0))))
+            .collect();
+        let tlas = TopLevelAccelerationStructure::new(&instances);
+
+        // Incoherent rays, like a bounce of a path tracer
+        let rays: Vec<Ray> = (0..3000)
+            .map(|_| Ray::new(rng.vec3(-6.0, 6.0), rng.vec3(-1.0, 1.0).normalize()))
+            .collect();
+        let t_max: Vec<f32> = rays.iter().map(|_| rng.range(0.0, 10.0)).collect();
+        let expected: Vec<HitRecord> = rays.iter().map(|ray| tlas.traverse(ray)).collect();
+        assert!(expected.iter().any(|hit| hit.t < f32::MAX));
+
+        let keys = [
+            RaySortKey::OriginOctant { cells_per_axis: 32 },
+            RaySortKey::OriginOctant { cells_per_axis: 1 },
+            RaySortKey::Morton,
+        ];
+        for sort_key in keys {
+            for group_size in [1, 100, 256] {
+                let stream = RayStream::new()
+                    .with_sort_key(sort_key)
+                    .with_group_size(group_size);
+                let mut order = stream.sort(&rays);
+                order.sort_unstable();
+                assert!(order.iter().enumerate().all(|(i, ray)| i as u32 == *ray));
+
+                let hits = stream.traverse(&tlas, &rays);
+                for ((hit, expected), ray) in hits.iter().zip(&expected).zip(&rays) {
+                    assert!(hit.t == expected.t || (hit.t - expected.t).abs() < expected.t * 1e-5);
+                    if expected.t < f32::MAX {
+                        assert_eq!(hit.ray.origin, ray.origin);
+                        assert_eq!(
+                            (hit.object_id, hit.primitive_id),
+                            (expected.object_id, expected.primitive_id)
+                        );
+                    }
+                }
+
+                let occluded = stream.occluded(&tlas, &rays, &t_max);
+                for ((ray, t_max), occluded) in rays.iter().zip(&t_max).zip(occluded) {
+                    assert_eq!(occluded, tlas.occluded(ray, *t_max));
+                }
+            }
+        }
+
+        let hits = RayStream::new().traverse_bvh(&bvh, &Mat4::identity(), &rays);
+        for (hit, ray) in hits.iter().zip(&rays) {
+            let mut expected = HitRecord::new();
+            bvh.traverse(ray, &Mat4::identity(), &mut expected);
+            assert!(hit.t == expected.t || (hit.t - expected.t).abs() < expected.t * 1e-5);
+        }
+        assert!(RayStream::new().traverse(&tlas, &[]).is_empty());
+    }
+}
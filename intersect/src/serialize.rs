@@ -0,0 +1,846 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    marker::PhantomData,
+    ops::Deref,
+    path::Path,
+    sync::Arc,
+};
+
+use cgmath::Quaternion;
+use memmap2::Mmap;
+
+use crate::{
+    bvh::{BuildStrategy, Bvh, BvhBuildOptions, MortonCode, Node, MAX_DEPTH},
+    intersect::CullMode,
+    motion::{MotionTransform, Srt},
+    primitive::TriangleMesh,
+    types::{Mat4, Vec3, Vertex},
+};
+
+// Version 2 stores the leaf primitives as triangle indices instead of offsets into
+// the index buffer. Version 3 adds the cull mode of every Bvh and instance.
+// Version 4 adds the keyframes of moving instances.
+pub const FORMAT_VERSION: u32 = 4;
+pub const BVH_MAGIC: [u8; 8] = *b"ISECTBVH";
+pub const TLAS_MAGIC: [u8; 8] = *b"ISECTTLS";
+
+// Written in native byte order, reads back byte swapped on a machine with the
+// other endianness
+const ENDIANNESS: u32 = 0x01020304;
+// Arrays start at multiples of this so they can be used in place once mapped
+const ALIGNMENT: usize = 16;
+
+#[derive(Debug)]
+pub enum SerializationError {
+    Io(io::Error),
+    // Not a file of the expected kind
+    InvalidMagic,
+    // Written on a machine with a different byte order
+    EndiannessMismatch,
+    // Written by a different version of this crate
+    UnsupportedVersion { found: u32, expected: u32 },
+    // The contents don't match the stored checksum
+    ChecksumMismatch,
+    // The file ends before the data it describes
+    Truncated,
+    // The checksum matches but the contents don't describe a valid tree
+    Corrupt(&'static str),
+    // The file was built from different geometry than the caller expects
+    Stale,
+}
+
+impl fmt::Display for SerializationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SerializationError::Io(error) => write!(f, "io error: {}", error),
+            SerializationError::InvalidMagic => {
+                write!(f, "not a serialized acceleration structure")
+            }
+            SerializationError::EndiannessMismatch => {
+                write!(
+                    f,
+                    "file was written on a machine with a different byte order"
+                )
+            }
+            SerializationError::UnsupportedVersion { found, expected } => write!(
+                f,
+                "file has format version {}, expected version {}",
+                found, expected
+            ),
+            SerializationError::ChecksumMismatch => write!(f, "checksum mismatch, file is corrupt"),
+            SerializationError::Truncated => write!(f, "file is truncated"),
+            SerializationError::Corrupt(reason) => write!(f, "file is corrupt: {}", reason),
+            SerializationError::Stale => {
+                write!(f, "file was built from different geometry and is stale")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SerializationError {}
+
+impl From<io::Error> for SerializationError {
+    fn from(error: io::Error) -> Self {
+        SerializationError::Io(error)
+    }
+}
+
+// Types that are read straight out of mapped files and written out as raw bytes.
+// Private to the crate and only implemented here, for types without padding for
+// which every bit pattern is a valid value.
+pub(crate) trait PlainData: Copy {}
+
+impl PlainData for u8 {}
+impl PlainData for u32 {}
+impl PlainData for Vertex {}
+impl PlainData for Node {}
+impl PlainData for FileHeader {}
+impl PlainData for BvhHeader {}
+impl PlainData for TlasHeader {}
+impl PlainData for SerializedInstance {}
+impl PlainData for SerializedKeyframe {}
+
+// Array data that is either owned or points into a memory mapped file. Mapped
+// data is copied the first time it's modified.
+pub(crate) enum Storage<T: PlainData> {
+    Owned(Vec<T>),
+    Mapped {
+        map: Arc<Mmap>,
+        offset: usize,
+        len: usize,
+        _marker: PhantomData<T>,
+    },
+}
+
+impl<T: PlainData> Storage<T> {
+    pub fn to_mut(&mut self) -> &mut Vec<T> {
+        if let Storage::Mapped { .. } = self {
+            *self = Storage::Owned(self.to_vec());
+        }
+        match self {
+            Storage::Owned(data) => data,
+            Storage::Mapped { .. } => unreachable!(),
+        }
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        matches!(self, Storage::Mapped { .. })
+    }
+}
+
+impl<T: PlainData> Deref for Storage<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Storage::Owned(data) => data,
+            // Safety: only `Reader::array` creates mapped storage, after checking the
+            // range and alignment, and any bytes are a valid `PlainData` value
+            Storage::Mapped {
+                map, offset, len, ..
+            } => unsafe { std::slice::from_raw_parts(map.as_ptr().add(*offset) as *const T, *len) },
+        }
+    }
+}
+
+impl<T: PlainData> From<Vec<T>> for Storage<T> {
+    fn from(data: Vec<T>) -> Self {
+        Storage::Owned(data)
+    }
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    crc32_update(0xffffffff, bytes) ^ 0xffffffff
+}
+
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                c = if c & 1 != 0 {
+                    0xedb88320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+                bit += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    };
+
+    for byte in bytes {
+        crc = TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+pub(crate) fn as_bytes<T: PlainData>(data: &[T]) -> &[u8] {
+    // Safety: `PlainData` types have no padding bytes
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) }
+}
+
+// Every file starts with this header, followed by the payload it checksums.
+// A Bvh payload is a `BvhHeader` followed by the nodes, primitives, vertices and
+// indices. A TLAS payload is a `TlasHeader` followed by the nodes, the instances,
+// the keyframes of moving instances and a complete Bvh file for every distinct
+// Bvh.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct FileHeader {
+    magic: [u8; 8],
+    endianness: u32,
+    version: u32,
+    checksum: u32,
+    _reserved: u32,
+    payload_size: u64,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct BvhHeader {
+    strategy: u32,
+    strategy_parameter: u32,
+    bin_count: u32,
+    max_leaf_size: u32,
+    traversal_cost: f32,
+    max_depth: u32,
+    build_cost: f32,
+    cull_mode: u32,
+    node_count: u64,
+    primitive_count: u64,
+    vertex_count: u64,
+    index_count: u64,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct TlasHeader {
+    node_count: u64,
+    instance_count: u64,
+    blas_count: u64,
+    keyframe_count: u64,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub(crate) struct SerializedInstance {
+    pub blas: u32,
+    pub id: u32,
+    pub transform: Mat4,
+    pub cull_mode: u32,
+    // 0 for instances that don't move, see `encode_motion`
+    pub motion: u32,
+    pub first_keyframe: u32,
+    pub keyframe_count: u32,
+}
+
+// A matrix, or an SRT transform packed into the first three rows
+pub(crate) type SerializedKeyframe = [[f32; 4]; 4];
+
+// Collects the payload of a file so the checksum can be written up front
+#[derive(Default)]
+pub(crate) struct Writer {
+    payload: Vec<u8>,
+}
+
+impl Writer {
+    pub fn position(&self) -> usize {
+        std::mem::size_of::<FileHeader>() + self.payload.len()
+    }
+
+    pub fn align(&mut self) {
+        while !self.position().is_multiple_of(ALIGNMENT) {
+            self.payload.push(0);
+        }
+    }
+
+    pub fn write<T: PlainData>(&mut self, data: &[T]) {
+        self.align();
+        self.payload.extend_from_slice(as_bytes(data));
+    }
+
+    pub fn finish(self, magic: [u8; 8], writer: &mut impl Write) -> io::Result<()> {
+        let header = FileHeader {
+            magic,
+            endianness: ENDIANNESS,
+            version: FORMAT_VERSION,
+            checksum: crc32(&self.payload),
+            _reserved: 0,
+            payload_size: self.payload.len() as u64,
+        };
+        writer.write_all(as_bytes(&[header]))?;
+        writer.write_all(&self.payload)?;
+        writer.flush()
+    }
+
+    pub fn into_file(self, magic: [u8; 8], path: &Path) -> Result<(), SerializationError> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.finish(magic, &mut file)?;
+        Ok(())
+    }
+}
+
+// Reads a file written by `Writer` from a mapped file, starting at `base`
+pub(crate) struct Reader {
+    map: Arc<Mmap>,
+    base: usize,
+    end: usize,
+    cursor: usize,
+}
+
+impl Reader {
+    pub fn open(path: &Path) -> Result<Arc<Mmap>, SerializationError> {
+        let file = File::open(path)?;
+        // Safety: the file must not be modified while mapped, like any memory map
+        Ok(Arc::new(unsafe { Mmap::map(&file)? }))
+    }
+
+    pub fn new(
+        map: Arc<Mmap>,
+        base: usize,
+        magic: [u8; 8],
+        verify_checksum: bool,
+    ) -> Result<Self, SerializationError> {
+        let header_size = std::mem::size_of::<FileHeader>();
+        if map.len() < base + header_size {
+            return Err(SerializationError::Truncated);
+        }
+
+        let mut reader = Self {
+            end: map.len(),
+            map,
+            base,
+            cursor: base,
+        };
+        let header: FileHeader = reader.read()?;
+        if header.magic != magic {
+            return Err(SerializationError::InvalidMagic);
+        }
+        if header.endianness != ENDIANNESS {
+            return Err(if header.endianness == ENDIANNESS.swap_bytes() {
+                SerializationError::EndiannessMismatch
+            } else {
+                SerializationError::Corrupt("invalid byte order marker")
+            });
+        }
+        if header.version != FORMAT_VERSION {
+            return Err(SerializationError::UnsupportedVersion {
+                found: header.version,
+                expected: FORMAT_VERSION,
+            });
+        }
+
+        let payload_start = base + header_size;
+        let payload_end = payload_start
+            .checked_add(header.payload_size as usize)
+            .filter(|end| *end <= reader.map.len())
+            .ok_or(SerializationError::Truncated)?;
+        if verify_checksum && crc32(&reader.map[payload_start..payload_end]) != header.checksum {
+            return Err(SerializationError::ChecksumMismatch);
+        }
+
+        reader.end = payload_end;
+        Ok(reader)
+    }
+
+    fn align(&mut self) {
+        let offset = self.cursor - self.base;
+        self.cursor += (ALIGNMENT - offset % ALIGNMENT) % ALIGNMENT;
+    }
+
+    pub fn array<T: PlainData>(&mut self, len: u64) -> Result<Storage<T>, SerializationError> {
+        self.align();
+        let len = len as usize;
+        let size = len
+            .checked_mul(std::mem::size_of::<T>())
+            .ok_or(SerializationError::Truncated)?;
+        let offset = self.cursor;
+        if offset.checked_add(size).is_none_or(|end| end > self.end) {
+            return Err(SerializationError::Truncated);
+        }
+        if !(self.map.as_ptr() as usize + offset).is_multiple_of(std::mem::align_of::<T>()) {
+            return Err(SerializationError::Corrupt("misaligned array"));
+        }
+
+        self.cursor += size;
+        Ok(Storage::Mapped {
+            map: self.map.clone(),
+            offset,
+            len,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn read<T: PlainData>(&mut self) -> Result<T, SerializationError> {
+        Ok(self.array::<T>(1)?[0])
+    }
+
+    pub fn map(&self) -> &Arc<Mmap> {
+        &self.map
+    }
+}
+
+fn encode_strategy(strategy: BuildStrategy) -> (u32, u32) {
+    match strategy {
+        BuildStrategy::BinnedSah => (0, 0),
+        BuildStrategy::SpatialSah { max_duplication } => (1, max_duplication.to_bits()),
+        BuildStrategy::Linear { morton_code } => (2, morton_code.bits()),
+        BuildStrategy::Ploc { search_radius } => (3, search_radius as u32),
+    }
+}
+
+fn decode_strategy(strategy: u32, parameter: u32) -> Result<BuildStrategy, SerializationError> {
+    match (strategy, parameter) {
+        (0, _) => Ok(BuildStrategy::BinnedSah),
+        (1, max_duplication) => Ok(BuildStrategy::SpatialSah {
+            max_duplication: f32::from_bits(max_duplication),
+        }),
+        (2, 30) => Ok(BuildStrategy::Linear {
+            morton_code: MortonCode::Bits30,
+        }),
+        (2, 63) => Ok(BuildStrategy::Linear {
+            morton_code: MortonCode::Bits63,
+        }),
+        (3, search_radius) => Ok(BuildStrategy::Ploc {
+            search_radius: search_radius as usize,
+        }),
+        _ => Err(SerializationError::Corrupt("unknown build strategy")),
+    }
+}
+
+pub(crate) fn encode_cull_mode(cull_mode: CullMode) -> u32 {
+    match cull_mode {
+        CullMode::None => 0,
+        CullMode::Back => 1,
+        CullMode::Front => 2,
+    }
+}
+
+pub(crate) fn decode_cull_mode(cull_mode: u32) -> Result<CullMode, SerializationError> {
+    match cull_mode {
+        0 => Ok(CullMode::None),
+        1 => Ok(CullMode::Back),
+        2 => Ok(CullMode::Front),
+        _ => Err(SerializationError::Corrupt("unknown cull mode")),
+    }
+}
+
+// Appends the keyframes of `motion` and returns its kind
+pub(crate) fn encode_motion(
+    motion: &MotionTransform,
+    keyframes: &mut Vec<SerializedKeyframe>,
+) -> u32 {
+    match motion {
+        MotionTransform::Linear(matrices) => {
+            keyframes.extend(
+                matrices
+                    .iter()
+                    .map(|matrix| -> SerializedKeyframe { (*matrix).into() }),
+            );
+            1
+        }
+        MotionTransform::Srt(srts) => {
+            keyframes.extend(srts.iter().map(|srt| {
+                let (scale, rotation, translation) = (srt.scale, srt.rotation, srt.translation);
+                [
+                    [scale.x, scale.y, scale.z, 0.0],
+                    [rotation.s, rotation.v.x, rotation.v.y, rotation.v.z],
+                    [translation.x, translation.y, translation.z, 0.0],
+                    [0.0; 4],
+                ]
+            }));
+            2
+        }
+    }
+}
+
+pub(crate) fn decode_motion(
+    motion: u32,
+    keyframes: &[SerializedKeyframe],
+) -> Result<MotionTransform, SerializationError> {
+    match motion {
+        1 => Ok(MotionTransform::linear(
+            keyframes
+                .iter()
+                .map(|keyframe| Mat4::from(*keyframe))
+                .collect(),
+        )),
+        2 => Ok(MotionTransform::srt(
+            keyframes
+                .iter()
+                .map(|[scale, rotation, translation, _]| Srt {
+                    scale: Vec3::new(scale[0], scale[1], scale[2]),
+                    rotation: Quaternion::new(rotation[0], rotation[1], rotation[2], rotation[3]),
+                    translation: Vec3::new(translation[0], translation[1], translation[2]),
+                })
+                .collect(),
+        )),
+        _ => Err(SerializationError::Corrupt("unknown motion")),
+    }
+}
+
+pub(crate) fn write_bvh(bvh: &Bvh) -> Writer {
+    let options = bvh.options();
+    let (strategy, strategy_parameter) = encode_strategy(options.strategy);
+    // Compressed trees are stored with their decoded nodes
+    let nodes = bvh.nodes();
+    let header = BvhHeader {
+        strategy,
+        strategy_parameter,
+        bin_count: options.bin_count as u32,
+        max_leaf_size: options.max_leaf_size as u32,
+        traversal_cost: options.traversal_cost,
+        max_depth: options.max_depth as u32,
+        build_cost: bvh.build_cost(),
+        cull_mode: encode_cull_mode(bvh.cull_mode()),
+        node_count: nodes.len() as u64,
+        primitive_count: bvh.primitives().len() as u64,
+        vertex_count: bvh.vertices().len() as u64,
+        index_count: bvh.indices().len() as u64,
+    };
+
+    let mut writer = Writer::default();
+    writer.write(&[header]);
+    writer.write(&nodes);
+    writer.write(bvh.primitives());
+    writer.write(bvh.vertices());
+    writer.write(bvh.indices());
+    writer
+}
+
+pub(crate) fn read_bvh(reader: &mut Reader) -> Result<Bvh, SerializationError> {
+    let header: BvhHeader = reader.read()?;
+    let options = BvhBuildOptions {
+        strategy: decode_strategy(header.strategy, header.strategy_parameter)?,
+        bin_count: header.bin_count as usize,
+        max_leaf_size: header.max_leaf_size as usize,
+        traversal_cost: header.traversal_cost,
+        max_depth: header.max_depth as usize,
+    };
+    let nodes: Storage<Node> = reader.array(header.node_count)?;
+    let primitives: Storage<u32> = reader.array(header.primitive_count)?;
+    let vertices: Storage<Vertex> = reader.array(header.vertex_count)?;
+    let indices: Storage<u32> = reader.array(header.index_count)?;
+
+    validate_nodes(&nodes, primitives.len())?;
+    if primitives.iter().any(|p| *p as usize >= indices.len() / 3) {
+        return Err(SerializationError::Corrupt(
+            "triangle outside the index buffer",
+        ));
+    }
+    if indices.iter().any(|i| *i as usize >= vertices.len()) {
+        return Err(SerializationError::Corrupt(
+            "index outside the vertex buffer",
+        ));
+    }
+
+    Ok(Bvh::from_parts(
+        TriangleMesh::from_storage(vertices, indices)
+            .with_cull_mode(decode_cull_mode(header.cull_mode)?),
+        primitives,
+        nodes,
+        options,
+        header.build_cost,
+    ))
+}
+
+// Makes sure traversal stays inside the arrays. Children have to be stored after
+// their parent, which also rules out cycles, and the tree has to fit the fixed
+// size traversal stacks.
+pub(crate) fn validate_nodes(
+    nodes: &[Node],
+    primitive_count: usize,
+) -> Result<(), SerializationError> {
+    if nodes.is_empty() {
+        return Err(SerializationError::Corrupt("no root node"));
+    }
+
+    let mut depths = vec![0; nodes.len()];
+    for (idx, node) in nodes.iter().enumerate() {
+        let first = node.first_primitive as usize;
+        let leaf = node.primitive_count > 0 || nodes.len() == 1;
+        let valid = if leaf {
+            first + node.primitive_count as usize <= primitive_count
+        } else {
+            first > idx && first + 1 < nodes.len()
+        };
+        if !valid {
+            return Err(SerializationError::Corrupt("node references out of range"));
+        }
+
+        if depths[idx] > MAX_DEPTH {
+            return Err(SerializationError::Corrupt("tree too deep"));
+        }
+        if !leaf {
+            let depth = depths[idx] + 1;
+            depths[first] = depths[first].max(depth);
+            depths[first + 1] = depths[first + 1].max(depth);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn write_tlas(
+    nodes: &[Node],
+    instances: &[SerializedInstance],
+    keyframes: &[SerializedKeyframe],
+    blases: &[&Bvh],
+) -> Writer {
+    let header = TlasHeader {
+        node_count: nodes.len() as u64,
+        instance_count: instances.len() as u64,
+        blas_count: blases.len() as u64,
+        keyframe_count: keyframes.len() as u64,
+    };
+
+    let mut writer = Writer::default();
+    writer.write(&[header]);
+    writer.write(nodes);
+    writer.write(instances);
+    writer.write(keyframes);
+    // Each blas is embedded as a complete file so it can be read in place
+    for blas in blases {
+        let mut image = Vec::new();
+        write_bvh(blas)
+            .finish(BVH_MAGIC, &mut image)
+            .expect("Writing to memory can't fail");
+        writer.write(&image);
+    }
+    writer
+}
+
+pub(crate) struct TlasParts {
+    pub nodes: Vec<Node>,
+    pub instances: Vec<SerializedInstance>,
+    pub keyframes: Vec<SerializedKeyframe>,
+    pub blases: Vec<Bvh>,
+}
+
+pub(crate) fn read_tlas(reader: &mut Reader) -> Result<TlasParts, SerializationError> {
+    let header: TlasHeader = reader.read()?;
+    let nodes: Storage<Node> = reader.array(header.node_count)?;
+    let instances: Storage<SerializedInstance> = reader.array(header.instance_count)?;
+    let keyframes: Storage<SerializedKeyframe> = reader.array(header.keyframe_count)?;
+    validate_nodes(&nodes, instances.len())?;
+    if instances.iter().any(|i| {
+        i.motion != 0
+            && (i.keyframe_count < 2
+                || i.first_keyframe as usize + i.keyframe_count as usize > keyframes.len())
+    }) {
+        return Err(SerializationError::Corrupt(
+            "instance references missing keyframes",
+        ));
+    }
+
+    let mut blases = Vec::new();
+    for _ in 0..header.blas_count {
+        reader.align();
+        // The outer checksum already covers the embedded files
+        let mut blas_reader = Reader::new(reader.map().clone(), reader.cursor, BVH_MAGIC, false)?;
+        blases.push(read_bvh(&mut blas_reader)?);
+        reader.cursor = blas_reader.end;
+    }
+
+    if instances.iter().any(|i| i.blas as usize >= blases.len()) {
+        return Err(SerializationError::Corrupt(
+            "instance references a missing blas",
+        ));
+    }
+
+    Ok(TlasParts {
+        nodes: nodes.to_vec(),
+        instances: instances.to_vec(),
+        keyframes: keyframes.to_vec(),
+        blases,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use cgmath::{Quaternion, SquareMatrix};
+
+    use super::{validate_nodes, SerializationError, FORMAT_VERSION};
+    use crate::{
+        bvh::{BuildStrategy, Bvh, BvhBuildOptions, Node, MAX_DEPTH},
+        intersect::CullMode,
+        motion::{MotionTransform, Srt},
+        testing::{soup, Rng},
+        top_level_acceleration_structure::{Instance, TopLevelAccelerationStructure},
+        types::{HitRecord, Mat4, Vec3},
+    };
+
+    // Unique per test and process, so tests running in parallel don't share files
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("intersect_{}_{}", std::process::id(), name))
+    }
+
+    // A tree whose left spine reaches `depth`, every right child is a leaf
+    fn spine(depth: usize) -> Vec<Node> {
+        let mut nodes = vec![Node::default()];
+        for level in 0..depth {
+            let parent = if level == 0 { 0 } else { nodes.len() - 2 };
+            nodes[parent].first_primitive = nodes.len() as u32;
+            nodes[parent].primitive_count = 0;
+            let leaf = Node {
+                primitive_count: 1,
+                ..Node::default()
+            };
+            nodes.extend([leaf, leaf]);
+        }
+        nodes
+    }
+
+    #[test]
+    fn rejects_trees_deeper_than_the_traversal_stack() {
+        assert!(validate_nodes(&spine(MAX_DEPTH), 1).is_ok());
+        assert!(matches!(
+            validate_nodes(&spine(MAX_DEPTH + 1), 1),
+            Err(SerializationError::Corrupt("tree too deep"))
+        ));
+    }
+
+    #[test]
+    fn trees_round_trip() {
+        let (vertices, indices) = soup(2000, 19);
+        let options = BvhBuildOptions::new().with_strategy(BuildStrategy::SpatialSah {
+            max_duplication: 0.2,
+        });
+        let mut bvh = Bvh::new_with_options(&vertices, &indices, &options);
+        bvh.set_cull_mode(CullMode::Back);
+        let path = temp_path("round_trip.bvh");
+        bvh.save(&path).unwrap();
+
+        let mut loaded = Bvh::load(&path).unwrap();
+        assert!(loaded.is_mapped());
+        assert!(loaded.options().strategy == options.strategy);
+        assert!(loaded.cull_mode() == CullMode::Back);
+        assert_eq!(loaded.primitives(), bvh.primitives());
+        let mut rng = Rng::new(20);
+        for _ in 0..1000 {
+            let ray = rng.ray();
+            let (mut expected, mut hit) = (HitRecord::new(), HitRecord::new());
+            bvh.traverse(&ray, &Mat4::identity(), &mut expected);
+            loaded.traverse(&ray, &Mat4::identity(), &mut hit);
+            assert_eq!(
+                (hit.t, hit.u, hit.v, hit.primitive_id),
+                (expected.t, expected.u, expected.v, expected.primitive_id)
+            );
+        }
+
+        // Changing the tree copies it out of the mapped file
+        loaded.refit(&vertices);
+        assert!(!loaded.is_mapped());
+
+        assert!(Bvh::load_matching(&path, &vertices, &indices).is_ok());
+        let mut moved = vertices.clone();
+        moved[0].x += 0.1;
+        assert!(matches!(
+            Bvh::load_matching(&path, &moved, &indices),
+            Err(SerializationError::Stale)
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn instances_round_trip() {
+        let (vertices, indices) = soup(500, 21);
+        let blas = Arc::new(Bvh::new(&vertices, &indices));
+        let motion = MotionTransform::srt(vec![
+            Srt::new(
+                Vec3::new(1.0, 1.0, 1.0),
+                Quaternion::new(1.0, 0.0, 0.0, 0.0),
+                Vec3::new(-1.0, 0.0, 0.0),
+            ),
+            Srt::new(
+                Vec3::new(1.0, 1.5, 1.0),
+                Quaternion::new(0.7, 0.7, 0.0, 0.0),
+                Vec3::new(1.0, 0.0, 0.0),
+            ),
+        ]);
+        let tlas = TopLevelAccelerationStructure::new(&[
+            Instance::new(blas.clone(), 0, Mat4::from_scale(0.5)),
+            Instance::new(blas.clone(), 1, Mat4::identity()).with_cull_mode(CullMode::Front),
+            Instance::new(blas, 2, Mat4::identity()).with_motion(motion),
+        ]);
+        let path = temp_path("round_trip.tlas");
+        tlas.save(&path).unwrap();
+
+        let loaded = TopLevelAccelerationStructure::load(&path).unwrap();
+        // Trees shared between instances are stored and loaded once
+        let instances = loaded.instances();
+        assert!(Arc::ptr_eq(&instances[0].blas, &instances[1].blas));
+        assert!(Arc::ptr_eq(&instances[0].blas, &instances[2].blas));
+        let mut rng = Rng::new(22);
+        for _ in 0..1000 {
+            let ray = rng.ray().with_time(rng.next());
+            let expected = tlas.traverse(&ray);
+            let hit = loaded.traverse(&ray);
+            assert_eq!(
+                (hit.t, hit.object_id, hit.primitive_id),
+                (expected.t, expected.object_id, expected.primitive_id)
+            );
+        }
+
+        // Neither kind of file loads as the other
+        assert!(matches!(
+            Bvh::load(&path),
+            Err(SerializationError::InvalidMagic)
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn damaged_files_are_rejected() {
+        let (vertices, indices) = soup(200, 23);
+        let path = temp_path("damaged.bvh");
+        Bvh::new(&vertices, &indices).save(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+
+        let load = |bytes: &[u8]| {
+            std::fs::write(&path, bytes).unwrap();
+            Bvh::load(&path).err()
+        };
+        let damaged = |offset: usize, value: &[u8]| {
+            let mut damaged = bytes.clone();
+            damaged[offset..offset + value.len()].copy_from_slice(value);
+            load(&damaged)
+        };
+        assert!(matches!(
+            damaged(0, b"X"),
+            Some(SerializationError::InvalidMagic)
+        ));
+        assert!(matches!(
+            damaged(8, &0x01020304u32.swap_bytes().to_ne_bytes()),
+            Some(SerializationError::EndiannessMismatch)
+        ));
+        assert!(matches!(
+            damaged(12, &(FORMAT_VERSION - 1).to_ne_bytes()),
+            Some(SerializationError::UnsupportedVersion { found, expected })
+                if found == FORMAT_VERSION - 1 && expected == FORMAT_VERSION
+        ));
+        assert!(matches!(
+            damaged(bytes.len() - 1, &[!bytes[bytes.len() - 1]]),
+            Some(SerializationError::ChecksumMismatch)
+        ));
+        assert!(matches!(
+            load(&bytes[..bytes.len() - 1]),
+            Some(SerializationError::Truncated)
+        ));
+        assert!(matches!(
+            load(&bytes[..10]),
+            Some(SerializationError::Truncated)
+        ));
+        assert!(load(&bytes).is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
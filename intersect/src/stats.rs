@@ -0,0 +1,438 @@
+use std::{collections::HashSet, fmt};
+
+use cgmath::{InnerSpace, Zero};
+use rayon::prelude::*;
+
+use crate::{
+    bvh::Node,
+    types::{Vec3, Vertex, AABB},
+};
+
+pub struct BvhStats {
+    // Expected cost of tracing a ray, relative to a single primitive intersection
+    pub sah_cost: f32,
+    pub node_count: usize,
+    pub leaf_count: usize,
+    // Primitives referenced by the leaves, larger than the primitive count when a
+    // builder duplicates references
+    pub primitive_references: usize,
+    pub max_depth: usize,
+    // Number of leaves at each depth, the root is at depth 0
+    pub depth_histogram: Vec<usize>,
+    // Number of leaves holding each primitive count
+    pub leaf_size_histogram: Vec<usize>,
+    pub average_primitives_per_leaf: f32,
+    pub max_primitives_per_leaf: usize,
+    // Bytes used by the nodes, the primitive references and any cpu node layout
+    pub memory: usize,
+    // End-point overlap, the cost weighted area of primitive surface that lies
+    // inside nodes that don't reference it. Only computed on request since it
+    // clips every primitive against all nodes it overlaps.
+    pub epo: Option<f32>,
+}
+
+impl BvhStats {
+    // Gathers everything except EPO from the nodes reachable from the root
+    pub(crate) fn from_nodes(nodes: &[Node], traversal_cost: f32, memory: usize) -> Self {
+        let mut stats = Self {
+            sah_cost: 0.0,
+            node_count: 0,
+            leaf_count: 0,
+            primitive_references: 0,
+            max_depth: 0,
+            depth_histogram: Vec::new(),
+            leaf_size_histogram: Vec::new(),
+            average_primitives_per_leaf: 0.0,
+            max_primitives_per_leaf: 0,
+            memory,
+            epo: None,
+        };
+
+        let mut cost = 0.0;
+        let mut stack = vec![(0, 0)];
+        while let Some((idx, depth)) = stack.pop() {
+            let node = &nodes[idx];
+            stats.node_count += 1;
+            if node.primitive_count > 0 || nodes.len() == 1 {
+                let count = node.primitive_count as usize;
+                cost += count as f32 * node.aabb.area();
+                stats.leaf_count += 1;
+                stats.primitive_references += count;
+                stats.max_depth = stats.max_depth.max(depth);
+                stats.max_primitives_per_leaf = stats.max_primitives_per_leaf.max(count);
+                increment(&mut stats.depth_histogram, depth);
+                increment(&mut stats.leaf_size_histogram, count);
+            } else {
+                cost += traversal_cost * node.aabb.area();
+                let left_child_index = node.first_primitive as usize;
+                stack.push((left_child_index + 1, depth + 1));
+                stack.push((left_child_index, depth + 1));
+            }
+        }
+
+        let root_area = nodes[0].aabb.area();
+        if root_area > 0.0 {
+            stats.sah_cost = cost / root_area;
+        }
+        stats.average_primitives_per_leaf =
+            stats.primitive_references as f32 / stats.leaf_count as f32;
+        stats
+    }
+
+    pub fn to_json(&self) -> String {
+        let histogram = |values: &[usize]| {
+            values
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        format!(
+            "{{\"sah_cost\":{},\"node_count\":{},\"leaf_count\":{},\"primitive_references\":{},\
+             \"max_depth\":{},\"depth_histogram\":[{}],\"leaf_size_histogram\":[{}],\
+             \"average_primitives_per_leaf\":{},\"max_primitives_per_leaf\":{},\"memory\":{},\
+             \"epo\":{}}}",
+            json_number(self.sah_cost),
+            self.node_count,
+            self.leaf_count,
+            self.primitive_references,
+            self.max_depth,
+            histogram(&self.depth_histogram),
+            histogram(&self.leaf_size_histogram),
+            json_number(self.average_primitives_per_leaf),
+            self.max_primitives_per_leaf,
+            self.memory,
+            self.epo.map_or("null".to_string(), json_number),
+        )
+    }
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "SAH cost: {:.3}", self.sah_cost)?;
+        writeln!(f, "Nodes: {} ({} leaves)", self.node_count, self.leaf_count)?;
+        writeln!(f, "Primitive references: {}", self.primitive_references)?;
+        writeln!(
+            f,
+            "Primitives per leaf: {:.2} average, {} max",
+            self.average_primitives_per_leaf, self.max_primitives_per_leaf
+        )?;
+        writeln!(f, "Max depth: {}", self.max_depth)?;
+        writeln!(f, "Memory: {} bytes", self.memory)?;
+        match self.epo {
+            Some(epo) => writeln!(f, "EPO: {:.4}", epo)?,
+            None => writeln!(f, "EPO: not computed")?,
+        }
+        writeln!(f, "Leaves per depth:")?;
+        for (depth, count) in self.depth_histogram.iter().enumerate() {
+            writeln!(f, "  {:3}: {}", depth, count)?;
+        }
+        writeln!(f, "Leaves per primitive count:")?;
+        for (size, count) in self.leaf_size_histogram.iter().enumerate() {
+            writeln!(f, "  {:3}: {}", size, count)?;
+        }
+        Ok(())
+    }
+}
+
+fn increment(histogram: &mut Vec<usize>, bucket: usize) {
+    if histogram.len() <= bucket {
+        histogram.resize(bucket + 1, 0);
+    }
+    histogram[bucket] += 1;
+}
+
+// JSON has no representation for infinity or NaN
+fn json_number(value: f32) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+// Computes the end-point overlap of a tree. `references` maps the primitive slots
+// of the leaves to primitives and `clipped_area` returns the surface area of a
+// primitive inside a box.
+pub(crate) fn end_point_overlap<F>(
+    nodes: &[Node],
+    references: &[u32],
+    primitive_count: usize,
+    traversal_cost: f32,
+    clipped_area: F,
+) -> f32
+where
+    F: Fn(usize, &AABB) -> f32 + Sync,
+{
+    let mut parents = vec![usize::MAX; nodes.len()];
+    let mut slots = vec![Vec::new(); primitive_count];
+    let mut stack = vec![0];
+    while let Some(idx) = stack.pop() {
+        let node = &nodes[idx];
+        if node.primitive_count > 0 || nodes.len() == 1 {
+            let first = node.first_primitive as usize;
+            let last = first + node.primitive_count as usize;
+            for primitive in &references[first..last] {
+                slots[*primitive as usize].push(idx);
+            }
+        } else {
+            let left_child_index = node.first_primitive as usize;
+            for child in [left_child_index, left_child_index + 1] {
+                parents[child] = idx;
+                stack.push(child);
+            }
+        }
+    }
+
+    let root = nodes[0].aabb;
+    let (overlap, total) = slots
+        .par_iter()
+        .enumerate()
+        .map(|(primitive, leaves)| {
+            // Nodes whose subtree references the primitive don't count as overlap
+            let mut owners = HashSet::new();
+            for leaf in leaves {
+                let mut idx = *leaf;
+                while idx != usize::MAX && owners.insert(idx) {
+                    idx = parents[idx];
+                }
+            }
+
+            let mut overlap = 0.0;
+            let mut stack = vec![0];
+            while let Some(idx) = stack.pop() {
+                let node = &nodes[idx];
+                let area = clipped_area(primitive, &node.aabb);
+                if area <= 0.0 {
+                    // Children are contained in their parent, so they can't overlap either
+                    continue;
+                }
+
+                let is_leaf = node.primitive_count > 0 || nodes.len() == 1;
+                if !owners.contains(&idx) {
+                    let cost = if is_leaf {
+                        node.primitive_count as f32
+                    } else {
+                        traversal_cost
+                    };
+                    overlap += cost * area;
+                }
+                if !is_leaf {
+                    let left_child_index = node.first_primitive as usize;
+                    stack.push(left_child_index);
+                    stack.push(left_child_index + 1);
+                }
+            }
+
+            (overlap, clipped_area(primitive, &root))
+        })
+        .reduce(|| (0.0, 0.0), |lhs, rhs| (lhs.0 + rhs.0, lhs.1 + rhs.1));
+
+    if total > 0.0 {
+        overlap / total
+    } else {
+        0.0
+    }
+}
+
+// Area of the part of a triangle inside `aabb`
+pub(crate) fn clipped_triangle_area(triangle: &[Vertex; 3], aabb: &AABB) -> f32 {
+    let mut polygon = triangle.to_vec();
+    for axis in 0..3 {
+        polygon = clip_polygon(&polygon, axis, aabb.min[axis], 1.0);
+        polygon = clip_polygon(&polygon, axis, aabb.max[axis], -1.0);
+        if polygon.len() < 3 {
+            return 0.0;
+        }
+    }
+
+    let mut normal = Vec3::zero();
+    for i in 1..polygon.len() - 1 {
+        normal += (polygon[i] - polygon[0]).cross(polygon[i + 1] - polygon[0]);
+    }
+    normal.magnitude() * 0.5
+}
+
+// Keeps the part of a convex polygon on the side of the plane that `side` points to
+fn clip_polygon(polygon: &[Vec3], axis: usize, position: f32, side: f32) -> Vec<Vec3> {
+    let inside = |p: &Vec3| (p[axis] - position) * side >= 0.0;
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+    for (i, a) in polygon.iter().enumerate() {
+        let b = &polygon[(i + 1) % polygon.len()];
+        if inside(a) {
+            clipped.push(*a);
+        }
+        if inside(a) != inside(b) {
+            let t = (position - a[axis]) / (b[axis] - a[axis]);
+            clipped.push(a + (b - a) * t);
+        }
+    }
+    clipped
+}
+
+// Surface area of the part of the faces of `aabb` inside `clip`
+pub(crate) fn clipped_box_area(aabb: &AABB, clip: &AABB) -> f32 {
+    let overlap = |axis: usize| {
+        (aabb.max[axis].min(clip.max[axis]) - aabb.min[axis].max(clip.min[axis])).max(0.0)
+    };
+    let mut area = 0.0;
+    for axis in 0..3 {
+        let face = overlap((axis + 1) % 3) * overlap((axis + 2) % 3);
+        for position in [aabb.min[axis], aabb.max[axis]] {
+            if position >= clip.min[axis] && position <= clip.max[axis] {
+                area += face;
+            }
+        }
+    }
+    area
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::BvhStats;
+    use crate::{
+        bvh::{Bvh, Node},
+        testing::soup,
+        top_level_acceleration_structure::{Instance, TopLevelAccelerationStructure},
+        types::{Mat4, Vec3, Vertex, AABB},
+    };
+
+    fn node(min: Vec3, max: Vec3, first_primitive: u32, primitive_count: u32) -> Node {
+        Node {
+            aabb: AABB::new(min, max),
+            first_primitive,
+            primitive_count,
+        }
+    }
+
+    // Small triangles along the diagonal, far enough apart that no node can
+    // overlap a triangle it doesn't reference
+    fn staircase(count: usize) -> (Vec<Vertex>, Vec<u32>) {
+        let mut vertices = Vec::new();
+        for i in 0..count {
+            let corner = Vec3::new(3.0, 3.0, 3.0) * i as f32;
+            vertices.extend([
+                corner,
+                corner + Vec3::new(1.0, 0.0, 0.0),
+                corner + Vec3::new(0.0, 1.0, 0.5),
+            ]);
+        }
+        let indices = (0..vertices.len() as u32).collect();
+        (vertices, indices)
+    }
+
+    #[test]
+    fn counts_match_the_tree() {
+        // A leaf with 2 primitives next to an interior node holding leaves with 1
+        // and 3 primitives
+        let nodes = [
+            node(Vec3::new(0.0, 0.0, 0.0), Vec3::new(4.0, 2.0, 1.0), 1, 0),
+            node(Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 1.0), 0, 2),
+            node(Vec3::new(2.0, 0.0, 0.0), Vec3::new(4.0, 2.0, 1.0), 3, 0),
+            node(Vec3::new(2.0, 0.0, 0.0), Vec3::new(3.0, 1.0, 1.0), 2, 1),
+            node(Vec3::new(3.0, 1.0, 0.0), Vec3::new(4.0, 2.0, 0.5), 3, 3),
+        ];
+        let stats = BvhStats::from_nodes(&nodes, 0.5, 1234);
+        assert_eq!(stats.node_count, 5);
+        assert_eq!(stats.leaf_count, 3);
+        assert_eq!(stats.primitive_references, 6);
+        assert_eq!(stats.max_depth, 2);
+        assert_eq!(stats.depth_histogram, vec![0, 1, 2]);
+        assert_eq!(stats.leaf_size_histogram, vec![0, 1, 1, 1]);
+        assert_eq!(stats.average_primitives_per_leaf, 2.0);
+        assert_eq!(stats.max_primitives_per_leaf, 3);
+        assert_eq!(stats.memory, 1234);
+        assert_eq!(stats.epo, None);
+
+        let area = |idx: usize| nodes[idx].aabb.area();
+        let expected =
+            (0.5 * area(0) + 2.0 * area(1) + 0.5 * area(2) + area(3) + 3.0 * area(4)) / area(0);
+        assert!((stats.sah_cost - expected).abs() < expected * 1e-6);
+    }
+
+    #[test]
+    fn built_trees_are_counted_completely() {
+        let (vertices, indices) = soup(500, 9);
+        let bvh = Bvh::new(&vertices, &indices);
+        let stats = bvh.stats();
+        assert_eq!(
+            stats.depth_histogram.iter().sum::<usize>(),
+            stats.leaf_count
+        );
+        assert_eq!(
+            stats.leaf_size_histogram.iter().sum::<usize>(),
+            stats.leaf_count
+        );
+        assert_eq!(stats.node_count, 2 * stats.leaf_count - 1);
+        // Binned builds reference every triangle exactly once
+        assert_eq!(stats.primitive_references, indices.len() / 3);
+        assert_eq!(stats.max_depth, stats.depth_histogram.len() - 1);
+        assert!((stats.sah_cost - bvh.sah_cost()).abs() < stats.sah_cost * 1e-5);
+    }
+
+    #[test]
+    fn epo_measures_overlap() {
+        let (vertices, indices) = staircase(16);
+        let disjoint = Bvh::new(&vertices, &indices);
+        assert_eq!(disjoint.stats_with_epo().epo, Some(0.0));
+        let (vertices, indices) = soup(300, 10);
+        let overlapping = Bvh::new(&vertices, &indices);
+        assert!(overlapping.stats_with_epo().epo.unwrap() > 0.0);
+
+        let blas = Arc::new(disjoint);
+        let apart: Vec<Instance> = (0..8)
+            .map(|i| {
+                let offset = Vec3::new(100.0 * i as f32, 0.0, 0.0);
+                Instance::new(blas.clone(), i, Mat4::from_translation(offset))
+            })
+            .collect();
+        let tlas = TopLevelAccelerationStructure::new(&apart);
+        assert_eq!(tlas.stats_with_epo().epo, Some(0.0));
+
+        // Nested in each other
+        let stacked: Vec<Instance> = (0..8)
+            .map(|i| Instance::new(blas.clone(), i, Mat4::from_scale(1.0 + i as f32)))
+            .collect();
+        let tlas = TopLevelAccelerationStructure::new(&stacked);
+        assert!(tlas.stats_with_epo().epo.unwrap() > 0.0);
+        assert_eq!(tlas.stats().epo, None);
+        assert_eq!(tlas.stats().leaf_count, tlas.stats().primitive_references);
+    }
+
+    #[test]
+    fn reports_are_formatted() {
+        let (vertices, indices) = soup(50, 11);
+        let mut stats = Bvh::new(&vertices, &indices).stats();
+        let json = stats.to_json();
+        assert!(json.starts_with('{') && json.ends_with('}'));
+        assert!(json.contains(&format!("\"node_count\":{}", stats.node_count)));
+        assert!(json.contains("\"epo\":null"));
+
+        // JSON has no infinity or NaN
+        stats.sah_cost = f32::INFINITY;
+        stats.epo = Some(f32::NAN);
+        let json = stats.to_json();
+        assert!(json.contains("\"sah_cost\":null"));
+        assert!(json.contains("\"epo\":null"));
+        stats.epo = Some(0.25);
+        assert!(stats.to_json().contains("\"epo\":0.25"));
+
+        let text = stats.to_string();
+        for field in [
+            "SAH cost:",
+            &format!("Nodes: {} ({} leaves)", stats.node_count, stats.leaf_count),
+            &format!("Primitive references: {}", stats.primitive_references),
+            &format!("Max depth: {}", stats.max_depth),
+            &format!("Memory: {} bytes", stats.memory),
+            "EPO: 0.2500",
+            "Leaves per depth:",
+            "Leaves per primitive count:",
+        ] {
+            assert!(text.contains(field), "{} missing from {}", field, text);
+        }
+        stats.epo = None;
+        assert!(stats.to_string().contains("EPO: not computed"));
+    }
+}
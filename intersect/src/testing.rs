@@ -0,0 +1,137 @@
+// Deterministic scenes shared by the unit tests
+
+use cgmath::{InnerSpace, SquareMatrix};
+
+use crate::{
+    bvh::{Bvh, MAX_DEPTH},
+    intersect::intersect_triangle,
+    query::Region,
+    types::{HitRecord, Mat4, Ray, Vec3, Vertex, AABB},
+};
+
+// Xorshift generator, so tests don't depend on a random number crate
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    // Uniform in [0, 1)
+    pub fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next()
+    }
+
+    pub fn vec3(&mut self, min: f32, max: f32) -> Vec3 {
+        Vec3::new(
+            self.range(min, max),
+            self.range(min, max),
+            self.range(min, max),
+        )
+    }
+
+    // Ray from in front of the scenes below towards a point inside them
+    pub fn ray(&mut self) -> Ray {
+        let origin = Vec3::new(self.range(-5.0, 5.0), self.range(-5.0, 5.0), -10.0);
+        let target = self.vec3(-2.0, 2.0);
+        Ray::new(origin, (target - origin).normalize())
+    }
+}
+
+// Triangles of up to 0.6 across scattered over the [-2, 2] cube
+pub fn soup(count: usize, seed: u64) -> (Vec<Vertex>, Vec<u32>) {
+    let mut rng = Rng::new(seed);
+    let mut vertices = Vec::with_capacity(3 * count);
+    for _ in 0..count {
+        let center = rng.vec3(-2.0, 2.0);
+        vertices.extend([0; 3].map(|_| center + rng.vec3(-0.3, 0.3)));
+    }
+    let indices = (0..vertices.len() as u32).collect();
+    (vertices, indices)
+}
+
+// Distance to the closest triangle along the ray, found by testing all of them
+pub fn closest_hit(vertices: &[Vertex], indices: &[u32], ray: &Ray) -> f32 {
+    let ray = ray.transformed(&Mat4::identity());
+    let mut closest = f32::MAX;
+    for triangle in indices.chunks(3) {
+        let [v0, v1, v2] = [0, 1, 2].map(|i| vertices[triangle[i] as usize]);
+        let (mut t, mut u, mut v) = (0.0, 0.0, 0.0);
+        if intersect_triangle(&ray, &v0, &v1, &v2, &mut t, &mut u, &mut v) && t < closest {
+            closest = t;
+        }
+    }
+    closest
+}
+
+// Long thin triangles crossing the [-2, 2] cube in random directions. Their
+// bounds overlap a lot, so the spatial split builder duplicates references.
+pub fn slivers(count: usize) -> (Vec<Vertex>, Vec<u32>) {
+    let mut rng = Rng::new(99);
+    let mut vertices = Vec::with_capacity(3 * count);
+    for _ in 0..count {
+        let center = rng.vec3(-2.0, 2.0);
+        let direction = rng.vec3(-0.5, 0.5).normalize() * 3.0;
+        let width = rng.vec3(0.0, 0.05);
+        vertices.extend([center - direction, center + direction, center + width]);
+    }
+    let indices = (0..vertices.len() as u32).collect();
+    (vertices, indices)
+}
+
+pub fn encloses(outer: &AABB, inner: &AABB) -> bool {
+    (0..3).all(|axis| outer.min[axis] <= inner.min[axis] && outer.max[axis] >= inner.max[axis])
+}
+
+// Checks that every node encloses its children, leaves overlap the triangles
+// they reference, every triangle is referenced and the tree fits the traversal
+// stack. Then checks that rays find the same closest hits as the default
+// binned SAH build.
+pub fn assert_matches_sah_build(bvh: &Bvh, vertices: &[Vertex], indices: &[u32]) {
+    let nodes = bvh.nodes();
+    let mut referenced = vec![false; indices.len() / 3];
+    let mut stack = vec![(0, 0)];
+    while let Some((idx, depth)) = stack.pop() {
+        assert!(depth <= MAX_DEPTH);
+        let node = &nodes[idx];
+        if node.primitive_count > 0 || nodes.len() == 1 {
+            let first = node.first_primitive as usize;
+            for &primitive in &bvh.primitives()[first..first + node.primitive_count as usize] {
+                let mut aabb = AABB::default();
+                for i in 0..3 {
+                    aabb.grow_with_position(
+                        &vertices[indices[3 * primitive as usize + i] as usize],
+                    );
+                }
+                assert!(node.aabb.overlaps_aabb(&aabb));
+                referenced[primitive as usize] = true;
+            }
+        } else {
+            let left = node.first_primitive as usize;
+            for child in [left, left + 1] {
+                assert!(child > idx);
+                assert!(encloses(&node.aabb, &nodes[child].aabb));
+                stack.push((child, depth + 1));
+            }
+        }
+    }
+    assert!(referenced.iter().all(|&referenced| referenced));
+
+    let reference = Bvh::new(vertices, indices);
+    let mut rng = Rng::new(17);
+    for _ in 0..500 {
+        let ray = rng.ray();
+        let (mut hit, mut expected) = (HitRecord::new(), HitRecord::new());
+        bvh.traverse(&ray, &Mat4::identity(), &mut hit);
+        reference.traverse(&ray, &Mat4::identity(), &mut expected);
+        assert_eq!(hit.t, expected.t);
+        assert_eq!(hit.primitive_id, expected.primitive_id);
+    }
+}
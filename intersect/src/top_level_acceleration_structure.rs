@@ -1,11 +1,22 @@
-use std::rc::Rc;
+use std::{ops::ControlFlow, path::Path, sync::Arc};
 
 use cgmath::Matrix4;
+use rayon::prelude::*;
 
 use crate::{
-    bvh::Bvh,
-    intersect::intersect_aabb,
-    types::{HitRecord, Ray, AABB},
+    bvh::{Bvh, InstanceTraversal, Node, MAX_DEPTH},
+    filter::HitFilter,
+    intersect::{intersect_aabb, CullMode},
+    motion::MotionTransform,
+    multi_hit::{HitCollector, HitList},
+    packet::{intersect_aabb_packet, lanes, RayPacket},
+    query::Region,
+    serialize::{
+        decode_cull_mode, decode_motion, encode_cull_mode, encode_motion, read_tlas, write_tlas,
+        Reader, SerializationError, SerializedInstance, TLAS_MAGIC,
+    },
+    stats::{clipped_box_area, end_point_overlap, BvhStats},
+    types::{ClosestPoint, HitRecord, Ray, Vec3, AABB},
 };
 
 pub struct TlasNode {
@@ -32,17 +43,89 @@ impl Default for TlasNode {
 
 #[derive(Clone)]
 pub struct Instance {
-    pub blas: Rc<Bvh>,
+    pub blas: Arc<Bvh>,
     _id: u32,
     transform: Matrix4<f32>,
+    motion: Option<MotionTransform>,
+    cull_mode: Option<CullMode>,
 }
 
 impl Instance {
-    pub fn new(blas: Rc<Bvh>, id: u32, transform: Matrix4<f32>) -> Self {
+    pub fn new(blas: Arc<Bvh>, id: u32, transform: Matrix4<f32>) -> Self {
         Self {
             blas,
             _id: id,
             transform,
+            motion: None,
+            cull_mode: None,
+        }
+    }
+
+    // Also stops the instance from moving
+    pub fn with_transform(mut self, transform: Matrix4<f32>) -> Self {
+        self.transform = transform;
+        self.motion = None;
+        self
+    }
+
+    // Makes the instance move, rays are intersected with the transform at their
+    // time. `transform` becomes the one at time 0.
+    pub fn with_motion(mut self, motion: MotionTransform) -> Self {
+        self.transform = motion.at(0.0);
+        self.motion = Some(motion);
+        self
+    }
+
+    // Overrides the cull mode of the Bvh for this instance only
+    pub fn with_cull_mode(mut self, cull_mode: CullMode) -> Self {
+        self.cull_mode = Some(cull_mode);
+        self
+    }
+
+    pub fn id(&self) -> u32 {
+        self._id
+    }
+
+    // Queries without a time, like closest points and overlaps, see moving
+    // instances at time 0
+    pub fn transform(&self) -> &Matrix4<f32> {
+        &self.transform
+    }
+
+    pub fn motion(&self) -> Option<&MotionTransform> {
+        self.motion.as_ref()
+    }
+
+    pub fn transform_at(&self, time: f32) -> Matrix4<f32> {
+        match &self.motion {
+            Some(motion) => motion.at(time),
+            None => self.transform,
+        }
+    }
+
+    // World space bounds, enclosing the whole motion of moving instances
+    pub fn aabb(&self) -> AABB {
+        match &self.motion {
+            Some(motion) => motion.bounds(self.blas.aabb()),
+            None => self.blas.aabb().transformed(&self.transform),
+        }
+    }
+
+    // World space bounds where the instance is at `time`
+    pub fn aabb_at(&self, time: f32) -> AABB {
+        self.blas.aabb().transformed(&self.transform_at(time))
+    }
+
+    // The override if there is one, otherwise the cull mode of the Bvh
+    pub fn cull_mode(&self) -> CullMode {
+        self.cull_mode.unwrap_or_else(|| self.blas.cull_mode())
+    }
+
+    pub(crate) fn traversal<'a>(&self, filter: Option<&'a dyn HitFilter>) -> InstanceTraversal<'a> {
+        InstanceTraversal {
+            cull_mode: self.cull_mode(),
+            instance_id: self._id,
+            filter,
         }
     }
 }
@@ -65,7 +148,12 @@ impl TopLevelAccelerationStructure {
         self.nodes[idx].aabb = aabb;
     }
 
-    fn subdivide(&mut self, idx: usize, boxes: &mut [AABB]) {
+    // Stops at `MAX_DEPTH` so the tree fits the fixed size traversal stacks
+    fn subdivide(&mut self, idx: usize, depth: usize, boxes: &mut [AABB]) {
+        if depth >= MAX_DEPTH {
+            return;
+        }
+
         let node = &self.nodes[idx];
 
         let extent = node.aabb.extent();
@@ -103,16 +191,17 @@ impl TopLevelAccelerationStructure {
 
         self.update_bounds(left_child_index, boxes);
         self.update_bounds(right_child_index, boxes);
-        self.subdivide(left_child_index, boxes);
-        self.subdivide(right_child_index, boxes);
+        self.subdivide(left_child_index, depth + 1, boxes);
+        self.subdivide(right_child_index, depth + 1, boxes);
     }
 
     pub fn new(instances: &[Instance]) -> Self {
+        // A binary tree over n instances has at most 2n - 1 nodes
         let mut nodes = Vec::new();
+        nodes.resize_with((2 * instances.len()).max(2) - 1, TlasNode::new);
         let mut boxes = Vec::new();
         for instance in instances {
-            nodes.push(TlasNode::new());
-            boxes.push(instance.blas.aabb().transformed(&instance.transform))
+            boxes.push(instance.aabb())
         }
 
         nodes[0].primitive_count = instances.len() as u32;
@@ -123,9 +212,9 @@ impl TopLevelAccelerationStructure {
         };
         this.update_bounds(0, &mut boxes);
         if this.nodes()[0].primitive_count > 2 {
-            this.subdivide(0, &mut boxes);
+            this.subdivide(0, 0, &mut boxes);
         }
-        this.subdivide(0, &mut boxes);
+        this.subdivide(0, 0, &mut boxes);
         this
     }
 
@@ -137,16 +226,57 @@ impl TopLevelAccelerationStructure {
         &self.nodes
     }
 
+    fn binary_nodes(&self) -> Vec<Node> {
+        self.nodes
+            .iter()
+            .map(|node| Node {
+                aabb: node.aabb,
+                first_primitive: node.first_primitive,
+                primitive_count: node.primitive_count,
+            })
+            .collect()
+    }
+
+    // Statistics for the top level tree only, the instanced Bvhs have their own
+    pub fn stats(&self) -> BvhStats {
+        let memory = std::mem::size_of::<TlasNode>() * self.used_nodes
+            + std::mem::size_of_val(self.instances.as_slice());
+        BvhStats::from_nodes(&self.binary_nodes(), 1.0, memory)
+    }
+
+    // Also computes the end-point overlap of the instance bounds
+    pub fn stats_with_epo(&self) -> BvhStats {
+        let boxes: Vec<AABB> = self.instances.iter().map(Instance::aabb).collect();
+        let references: Vec<u32> = (0..self.instances.len() as u32).collect();
+        let epo = end_point_overlap(
+            &self.binary_nodes(),
+            &references,
+            self.instances.len(),
+            1.0,
+            |instance, aabb| clipped_box_area(&boxes[instance], aabb),
+        );
+        BvhStats {
+            epo: Some(epo),
+            ..self.stats()
+        }
+    }
+
     pub fn traverse(&self, ray: &Ray) -> HitRecord {
-        self.traverse_stack(ray)
+        self.traverse_stack(ray, None)
+    }
+
+    // `traverse` that only records hits accepted by `filter`. The filter sees
+    // the id the instance was created with.
+    pub fn traverse_filtered(&self, ray: &Ray, filter: &dyn HitFilter) -> HitRecord {
+        self.traverse_stack(ray, Some(filter))
     }
 
-    fn traverse_stack(&self, ray: &Ray) -> HitRecord {
+    fn traverse_stack(&self, ray: &Ray, filter: Option<&dyn HitFilter>) -> HitRecord {
         let mut node_idx = 0;
         let mut stack_ptr = 0;
         let mut stack = [0; 64];
         let mut record = HitRecord::new();
-        let mut d = f32::MAX;
+        let mut d = ray.t_max;
         loop {
             let node = &self.nodes[node_idx];
             if self.nodes[node_idx].primitive_count > 0 {
@@ -155,12 +285,17 @@ impl TopLevelAccelerationStructure {
 
                 for i in first..last {
                     let instance = &self.instances[i];
-                    let transform = &instance.transform;
-                    instance.blas.traverse(ray, transform, &mut record);
+                    let transform = instance.transform_at(ray.time);
+                    // Only hits closer than the current one are recorded
+                    let ray = ray.with_interval(ray.t_min, d);
+                    let traversal = instance.traversal(filter);
+                    instance
+                        .blas
+                        .traverse_instance(&ray, &transform, &traversal, &mut record);
                     if record.t < d {
                         record.object_id = i as _;
                         d = record.t;
-                        record.obj_to_world = *transform;
+                        record.obj_to_world = transform;
                     }
                 }
 
@@ -177,17 +312,8 @@ impl TopLevelAccelerationStructure {
             let mut right_child_idx = left_child_idx + 1;
             let left_child = &self.nodes[left_child_idx];
             let right_child = &self.nodes[right_child_idx];
-            let mut left_distance = intersect_aabb(&left_child.aabb, ray, f32::MAX);
-            let mut right_distance = intersect_aabb(&right_child.aabb, ray, f32::MAX);
-            if left_distance > d || right_distance > d {
-                if stack_ptr == 0 {
-                    break;
-                } else {
-                    stack_ptr -= 1;
-                    node_idx = stack[stack_ptr];
-                    continue;
-                }
-            }
+            let mut left_distance = intersect_aabb(&left_child.aabb, ray, d);
+            let mut right_distance = intersect_aabb(&right_child.aabb, ray, d);
             if left_distance > right_distance {
                 std::mem::swap(&mut left_child_idx, &mut right_child_idx);
                 std::mem::swap(&mut left_distance, &mut right_distance);
@@ -211,9 +337,860 @@ impl TopLevelAccelerationStructure {
         record
     }
 
+    // `traverse` for a whole packet, see `Bvh::traverse_packet`. Inactive lanes
+    // keep an empty record.
+    pub fn traverse_packet<const N: usize>(&self, packet: &RayPacket<N>) -> [HitRecord; N] {
+        self.traverse_packet_with(packet, None)
+    }
+
+    // `traverse_packet` that only records hits accepted by `filter`
+    pub fn traverse_packet_filtered<const N: usize>(
+        &self,
+        packet: &RayPacket<N>,
+        filter: &dyn HitFilter,
+    ) -> [HitRecord; N] {
+        self.traverse_packet_with(packet, Some(filter))
+    }
+
+    fn traverse_packet_with<const N: usize>(
+        &self,
+        packet: &RayPacket<N>,
+        filter: Option<&dyn HitFilter>,
+    ) -> [HitRecord; N] {
+        let mut records = [HitRecord::new(); N];
+        // Every instance only looks for hits closer than the lane's current one
+        let mut closest = *packet;
+        let frustum = packet.frustum();
+
+        let mut stack_ptr = 1;
+        let mut stack = [(0, packet.active()); 64];
+        while stack_ptr > 0 {
+            stack_ptr -= 1;
+            let (node_idx, active) = stack[stack_ptr];
+            let node = &self.nodes[node_idx];
+            if frustum
+                .as_ref()
+                .is_some_and(|frustum| frustum.misses(&node.aabb, closest.t_max(), active))
+            {
+                continue;
+            }
+            let active = intersect_aabb_packet(&node.aabb, packet, closest.t_max(), active);
+            if active == 0 {
+                continue;
+            }
+
+            if node.primitive_count > 0 {
+                let first = node.first_primitive as usize;
+                let last = first + node.primitive_count as usize;
+                for i in first..last {
+                    let instance = &self.instances[i];
+                    closest.set_active(active);
+                    let traversal = instance.traversal(filter);
+                    let hit = match &instance.motion {
+                        None => instance.blas.traverse_packet_instance(
+                            &closest,
+                            &instance.transform,
+                            &traversal,
+                            &mut records,
+                        ),
+                        // Every lane has its own time and so its own transform
+                        Some(motion) => lanes(active)
+                            .filter(|&lane| {
+                                let ray = closest.ray(lane);
+                                let record = &mut records[lane];
+                                let before = record.t;
+                                let transform = motion.at(ray.time);
+                                instance
+                                    .blas
+                                    .traverse_instance(&ray, &transform, &traversal, record);
+                                record.t < before
+                            })
+                            .fold(0, |mask, lane| mask | 1 << lane),
+                    };
+                    for lane in lanes(hit) {
+                        closest.set_t_max(lane, records[lane].t);
+                        records[lane].object_id = i as _;
+                        records[lane].obj_to_world = instance.transform_at(packet.ray(lane).time);
+                    }
+                }
+            } else {
+                let left_child_idx = node.first_primitive as usize;
+                let right_child_idx = left_child_idx + 1;
+                let (near, far) = if packet.in_order(
+                    active,
+                    &self.nodes[left_child_idx].aabb,
+                    &self.nodes[right_child_idx].aabb,
+                ) {
+                    (left_child_idx, right_child_idx)
+                } else {
+                    (right_child_idx, left_child_idx)
+                };
+                stack[stack_ptr] = (far, active);
+                stack[stack_ptr + 1] = (near, active);
+                stack_ptr += 2;
+            }
+        }
+
+        records
+    }
+
+    // Passes every hit along the ray to `collector`, see `Bvh::traverse_all`.
+    // `object_id` is the index of the instance like for `traverse`.
+    pub fn traverse_all<C: HitCollector>(&self, ray: &Ray, collector: &mut C) {
+        self.traverse_all_with(ray, None, collector);
+    }
+
+    // `traverse_all` that only passes on hits accepted by `filter`. The filter
+    // sees the id the instance was created with.
+    pub fn traverse_all_filtered<C: HitCollector>(
+        &self,
+        ray: &Ray,
+        filter: &dyn HitFilter,
+        collector: &mut C,
+    ) {
+        self.traverse_all_with(ray, Some(filter), collector);
+    }
+
+    fn traverse_all_with<C: HitCollector>(
+        &self,
+        ray: &Ray,
+        filter: Option<&dyn HitFilter>,
+        collector: &mut C,
+    ) {
+        let mut stack_ptr = 1;
+        let mut stack = [0; 64];
+        while stack_ptr > 0 {
+            stack_ptr -= 1;
+            let node = &self.nodes[stack[stack_ptr]];
+            let d = collector.t_max();
+            if intersect_aabb(&node.aabb, ray, d) == f32::MAX {
+                continue;
+            }
+
+            if node.primitive_count > 0 {
+                let first = node.first_primitive as usize;
+                let last = first + node.primitive_count as usize;
+                for i in first..last {
+                    let instance = &self.instances[i];
+                    let ray = ray.with_interval(ray.t_min, ray.t_max.min(collector.t_max()));
+                    let mut instance_hits = InstanceHits {
+                        collector: &mut *collector,
+                        object_id: i as _,
+                    };
+                    instance.blas.traverse_all_instance(
+                        &ray,
+                        &instance.transform_at(ray.time),
+                        &instance.traversal(filter),
+                        &mut instance_hits,
+                    );
+                }
+            } else {
+                let left_child_index = node.first_primitive as usize;
+                stack[stack_ptr] = left_child_index + 1;
+                stack[stack_ptr + 1] = left_child_index;
+                stack_ptr += 2;
+            }
+        }
+    }
+
+    // Every hit along the ray, sorted by t
+    pub fn all_hits(&self, ray: &Ray) -> Vec<HitRecord> {
+        let mut hits = HitList::all();
+        self.traverse_all(ray, &mut hits);
+        hits.into_hits()
+    }
+
+    // The `count` nearest hits along the ray, sorted by t
+    pub fn nearest_hits(&self, ray: &Ray, count: usize) -> Vec<HitRecord> {
+        let mut hits = HitList::nearest(count);
+        self.traverse_all(ray, &mut hits);
+        hits.into_hits()
+    }
+
+    // Nearest point on any instance closer than `max_distance` to `point`, see
+    // `Bvh::closest_point`. `object_id` is the index of the instance. Moving
+    // instances are placed where they are at `time`, like for `Ray::time`.
+    pub fn closest_point(&self, point: Vec3, max_distance: f32, time: f32) -> Option<ClosestPoint> {
+        let mut closest: Option<ClosestPoint> = None;
+        let mut best = max_distance;
+        let mut stack_ptr = 1;
+        let mut stack = [(0, self.nodes[0].aabb.distance_squared(&point)); 64];
+        while stack_ptr > 0 {
+            stack_ptr -= 1;
+            let (node_idx, node_distance) = stack[stack_ptr];
+            if node_distance >= best * best {
+                continue;
+            }
+
+            let node = &self.nodes[node_idx];
+            if node.primitive_count > 0 {
+                let first = node.first_primitive as usize;
+                let last = first + node.primitive_count as usize;
+                for i in first..last {
+                    let instance = &self.instances[i];
+                    if let Some(mut hit) = instance.blas.closest_point_transformed(
+                        point,
+                        &instance.transform_at(time),
+                        best,
+                    ) {
+                        hit.object_id = i as _;
+                        best = hit.distance;
+                        closest = Some(hit);
+                    }
+                }
+            } else {
+                let left_child_idx = node.first_primitive as usize;
+                let right_child_idx = left_child_idx + 1;
+                let left = (
+                    left_child_idx,
+                    self.nodes[left_child_idx].aabb.distance_squared(&point),
+                );
+                let right = (
+                    right_child_idx,
+                    self.nodes[right_child_idx].aabb.distance_squared(&point),
+                );
+                let (near, far) = if left.1 <= right.1 {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                stack[stack_ptr] = far;
+                stack[stack_ptr + 1] = near;
+                stack_ptr += 2;
+            }
+        }
+        closest
+    }
+
+    // Calls `visitor` with the index of every instance whose world bounds
+    // overlap `region` at `time`, without allocating. Returning
+    // `ControlFlow::Break` from the visitor ends the query.
+    pub fn query_instances<R, F>(&self, region: &R, time: f32, mut visitor: F)
+    where
+        R: Region,
+        F: FnMut(u32) -> ControlFlow<()>,
+    {
+        let _ = self.visit_instances(region, |i, instance| {
+            if region.overlaps_aabb(&instance.aabb_at(time)) {
+                visitor(i)
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+    }
+
+    // Calls `visitor` with the instance index and triangle of every triangle
+    // overlapping `region` at `time`
+    pub fn query_primitives<R, F>(&self, region: &R, time: f32, mut visitor: F)
+    where
+        R: Region,
+        F: FnMut(u32, u32) -> ControlFlow<()>,
+    {
+        let _ = self.visit_instances(region, |i, instance| {
+            instance
+                .blas
+                .visit_transformed(region, &instance.transform_at(time), |primitive| {
+                    visitor(i, primitive)
+                })
+        });
+    }
+
+    // Every instance overlapping `region` at `time`
+    pub fn overlapping_instances<R: Region>(&self, region: &R, time: f32) -> Vec<u32> {
+        let mut instances = Vec::new();
+        self.query_instances(region, time, |instance| {
+            instances.push(instance);
+            ControlFlow::Continue(())
+        });
+        instances
+    }
+
+    // Every (instance, triangle) pair overlapping `region` at `time`
+    pub fn overlapping_primitives<R: Region>(&self, region: &R, time: f32) -> Vec<(u32, u32)> {
+        let mut primitives = Vec::new();
+        self.query_primitives(region, time, |instance, primitive| {
+            primitives.push((instance, primitive));
+            ControlFlow::Continue(())
+        });
+        primitives
+    }
+
+    fn visit_instances<R, F>(&self, region: &R, mut leaf: F) -> ControlFlow<()>
+    where
+        R: Region,
+        F: FnMut(u32, &Instance) -> ControlFlow<()>,
+    {
+        let mut stack_ptr = 1;
+        let mut stack = [0; 64];
+        while stack_ptr > 0 {
+            stack_ptr -= 1;
+            let node = &self.nodes[stack[stack_ptr]];
+            if !region.overlaps_aabb(&node.aabb) {
+                continue;
+            }
+
+            if node.primitive_count > 0 {
+                let first = node.first_primitive as usize;
+                let last = first + node.primitive_count as usize;
+                for i in first..last {
+                    leaf(i as _, &self.instances[i])?;
+                }
+            } else {
+                let left_child_index = node.first_primitive as usize;
+                stack[stack_ptr] = left_child_index + 1;
+                stack[stack_ptr + 1] = left_child_index;
+                stack_ptr += 2;
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    // Broad phase: calls `visitor` with every pair of instances whose world
+    // bounds overlap at `time`, each pair once with the lower index first. The
+    // tree is walked against itself, without allocating.
+    pub fn query_instance_pairs<F>(&self, time: f32, mut visitor: F)
+    where
+        F: FnMut(u32, u32) -> ControlFlow<()>,
+    {
+        let _ = self.visit_instance_pairs(time, &mut visitor);
+    }
+
+    // Every pair of instances with overlapping world bounds at `time`
+    pub fn overlapping_instance_pairs(&self, time: f32) -> Vec<(u32, u32)> {
+        let mut pairs = Vec::new();
+        self.query_instance_pairs(time, |a, b| {
+            pairs.push((a, b));
+            ControlFlow::Continue(())
+        });
+        pairs
+    }
+
+    // Narrow phase on top of `query_instance_pairs`: calls `visitor` with the
+    // instances and triangles of every intersecting triangle pair. With
+    // `first_contact` only the first pair found for each instance pair is
+    // reported.
+    pub fn query_collisions<F>(&self, time: f32, first_contact: bool, mut visitor: F)
+    where
+        F: FnMut(u32, u32, u32, u32) -> ControlFlow<()>,
+    {
+        self.query_instance_pairs(time, |a, b| {
+            let instance_a = &self.instances[a as usize];
+            let instance_b = &self.instances[b as usize];
+            let mut result = ControlFlow::Continue(());
+            let _ = instance_a.blas.visit_intersecting(
+                &instance_a.transform_at(time),
+                &instance_b.blas,
+                &instance_b.transform_at(time),
+                |primitive_a, primitive_b| {
+                    result = visitor(a, primitive_a, b, primitive_b);
+                    if first_contact {
+                        ControlFlow::Break(())
+                    } else {
+                        result
+                    }
+                },
+            );
+            result
+        });
+    }
+
+    // Every pair of instances that interpenetrate or touch at `time`
+    pub fn colliding_instance_pairs(&self, time: f32) -> Vec<(u32, u32)> {
+        let mut pairs = Vec::new();
+        self.query_collisions(time, true, |a, _, b, _| {
+            pairs.push((a, b));
+            ControlFlow::Continue(())
+        });
+        pairs
+    }
+
+    // The nodes enclose the whole motion of every instance, so only the final
+    // test between two instances depends on `time`
+    fn visit_instance_pairs<F>(&self, time: f32, visitor: &mut F) -> ControlFlow<()>
+    where
+        F: FnMut(u32, u32) -> ControlFlow<()>,
+    {
+        let world_aabb = |i: usize| self.instances[i].aabb_at(time);
+
+        let mut stack_ptr = 1;
+        let mut stack = [(0, 0); 128];
+        while stack_ptr > 0 {
+            stack_ptr -= 1;
+            let (a, b) = stack[stack_ptr];
+            let node_a = &self.nodes[a];
+            let node_b = &self.nodes[b];
+            if a != b && !node_a.aabb.overlaps_aabb(&node_b.aabb) {
+                continue;
+            }
+
+            match (node_a.primitive_count > 0, node_b.primitive_count > 0) {
+                (true, true) => {
+                    let first_a = node_a.first_primitive as usize;
+                    let first_b = node_b.first_primitive as usize;
+                    for i in first_a..first_a + node_a.primitive_count as usize {
+                        // Within a single leaf every pair is visited once
+                        let start = if a == b { i + 1 } else { first_b };
+                        for j in start..first_b + node_b.primitive_count as usize {
+                            if world_aabb(i).overlaps_aabb(&world_aabb(j)) {
+                                visitor(i.min(j) as _, i.max(j) as _)?;
+                            }
+                        }
+                    }
+                }
+                (false, _) if a == b => {
+                    let left = node_a.first_primitive as usize;
+                    stack[stack_ptr] = (left, left + 1);
+                    stack[stack_ptr + 1] = (left + 1, left + 1);
+                    stack[stack_ptr + 2] = (left, left);
+                    stack_ptr += 3;
+                }
+                (false, leaf_b) if leaf_b || node_a.aabb.area() >= node_b.aabb.area() => {
+                    let left = node_a.first_primitive as usize;
+                    stack[stack_ptr] = (left + 1, b);
+                    stack[stack_ptr + 1] = (left, b);
+                    stack_ptr += 2;
+                }
+                _ => {
+                    let left = node_b.first_primitive as usize;
+                    stack[stack_ptr] = (a, left + 1);
+                    stack[stack_ptr + 1] = (a, left);
+                    stack_ptr += 2;
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    // True when any instance blocks the ray before `t_max`, see `Bvh::occluded`
+    pub fn occluded(&self, ray: &Ray, t_max: f32) -> bool {
+        self.occluded_with(ray, t_max, None)
+    }
+
+    // `occluded` that ignores hits rejected by `filter`, so rays pass through
+    // cutouts
+    pub fn occluded_filtered(&self, ray: &Ray, t_max: f32, filter: &dyn HitFilter) -> bool {
+        self.occluded_with(ray, t_max, Some(filter))
+    }
+
+    fn occluded_with(&self, ray: &Ray, t_max: f32, filter: Option<&dyn HitFilter>) -> bool {
+        let mut stack_ptr = 1;
+        let mut stack = [0; 64];
+        while stack_ptr > 0 {
+            stack_ptr -= 1;
+            let node = &self.nodes[stack[stack_ptr]];
+            if intersect_aabb(&node.aabb, ray, t_max) == f32::MAX {
+                continue;
+            }
+
+            if node.primitive_count > 0 {
+                let first = node.first_primitive as usize;
+                let last = first + node.primitive_count as usize;
+                if self.instances[first..last].iter().any(|instance| {
+                    let traversal = instance.traversal(filter);
+                    let transform = instance.transform_at(ray.time);
+                    instance
+                        .blas
+                        .occluded_instance(ray, &transform, &traversal, t_max)
+                }) {
+                    return true;
+                }
+            } else {
+                let left_child_index = node.first_primitive as usize;
+                stack[stack_ptr] = left_child_index + 1;
+                stack[stack_ptr + 1] = left_child_index;
+                stack_ptr += 2;
+            }
+        }
+        false
+    }
+
+    // `occluded` for many rays at once, traced in parallel
+    pub fn occluded_batch(&self, rays: &[Ray], t_max: &[f32]) -> Vec<bool> {
+        assert_eq!(rays.len(), t_max.len(), "Every ray needs a t_max");
+        rays.par_iter()
+            .zip(t_max)
+            .map(|(ray, t_max)| self.occluded(ray, *t_max))
+            .collect()
+    }
+
+    // Writes the tree, the instances and every distinct Bvh they reference to a
+    // single file. Instances sharing a Bvh keep sharing it after loading.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SerializationError> {
+        let mut blases: Vec<&Arc<Bvh>> = Vec::new();
+        let mut keyframes = Vec::new();
+        let instances: Vec<SerializedInstance> = self
+            .instances
+            .iter()
+            .map(|instance| {
+                let blas = match blases.iter().position(|b| Arc::ptr_eq(b, &instance.blas)) {
+                    Some(blas) => blas,
+                    None => {
+                        blases.push(&instance.blas);
+                        blases.len() - 1
+                    }
+                };
+                let first_keyframe = keyframes.len() as u32;
+                let motion = instance
+                    .motion
+                    .as_ref()
+                    .map_or(0, |motion| encode_motion(motion, &mut keyframes));
+                SerializedInstance {
+                    blas: blas as u32,
+                    id: instance._id,
+                    transform: instance.transform,
+                    // 0 keeps the cull mode of the Bvh
+                    cull_mode: instance
+                        .cull_mode
+                        .map_or(0, |mode| encode_cull_mode(mode) + 1),
+                    motion,
+                    first_keyframe,
+                    keyframe_count: keyframes.len() as u32 - first_keyframe,
+                }
+            })
+            .collect();
+
+        let blases: Vec<&Bvh> = blases.into_iter().map(|blas| blas.as_ref()).collect();
+        let nodes = &self.binary_nodes()[..self.used_nodes];
+        write_tlas(nodes, &instances, &keyframes, &blases).into_file(TLAS_MAGIC, path.as_ref())
+    }
+
+    // Memory maps a file written by `save`. The Bvhs use their arrays in place.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SerializationError> {
+        let map = Reader::open(path.as_ref())?;
+        let parts = read_tlas(&mut Reader::new(map, 0, TLAS_MAGIC, true)?)?;
+        let blases: Vec<Arc<Bvh>> = parts.blases.into_iter().map(Arc::new).collect();
+        let instances = parts
+            .instances
+            .iter()
+            .map(|serialized| {
+                let mut instance = Instance::new(
+                    blases[serialized.blas as usize].clone(),
+                    serialized.id,
+                    serialized.transform,
+                );
+                if serialized.motion != 0 {
+                    let first = serialized.first_keyframe as usize;
+                    let keyframes = &parts.keyframes[first..][..serialized.keyframe_count as usize];
+                    instance = instance.with_motion(decode_motion(serialized.motion, keyframes)?);
+                }
+                match serialized.cull_mode {
+                    0 => Ok(instance),
+                    mode => Ok(instance.with_cull_mode(decode_cull_mode(mode - 1)?)),
+                }
+            })
+            .collect::<Result<_, SerializationError>>()?;
+        let nodes: Vec<TlasNode> = parts
+            .nodes
+            .iter()
+            .map(|node| TlasNode {
+                aabb: node.aabb,
+                first_primitive: node.first_primitive,
+                primitive_count: node.primitive_count,
+            })
+            .collect();
+
+        Ok(Self {
+            used_nodes: nodes.len(),
+            nodes,
+            instances,
+        })
+    }
+
     pub fn instances(&self) -> &[Instance] {
         &self.instances
     }
 }
 
-unsafe impl Sync for TopLevelAccelerationStructure {}
+// Tags the hits of one instance with its index before storing them
+struct InstanceHits<'a, C: HitCollector> {
+    collector: &'a mut C,
+    object_id: u32,
+}
+
+impl<C: HitCollector> HitCollector for InstanceHits<'_, C> {
+    fn t_max(&self) -> f32 {
+        self.collector.t_max()
+    }
+
+    fn insert(&mut self, mut hit: HitRecord) {
+        hit.object_id = self.object_id;
+        self.collector.insert(hit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ops::ControlFlow, sync::Arc};
+
+    use cgmath::{InnerSpace, SquareMatrix};
+
+    use super::{Instance, TopLevelAccelerationStructure};
+    use crate::{
+        bvh::Bvh,
+        filter::CandidateHit,
+        intersect::{intersect_triangle, intersect_triangles, CullMode},
+        motion::MotionTransform,
+        multi_hit::HitList,
+        query::Region,
+        testing::{soup, Rng},
+        types::{Mat4, Ray, Vec3, Vertex, AABB},
+    };
+
+    // One instance resting at the origin and one sliding from x = -10 to x = 10
+    fn sliding() -> TopLevelAccelerationStructure {
+        let (vertices, indices) = soup(50, 3);
+        let blas = Arc::new(Bvh::new(&vertices, &indices));
+        let motion = MotionTransform::linear(vec![
+            Mat4::from_translation(Vec3::new(-10.0, 0.0, 0.0)),
+            Mat4::from_translation(Vec3::new(10.0, 0.0, 0.0)),
+        ]);
+        TopLevelAccelerationStructure::new(&[
+            Instance::new(blas.clone(), 0, Mat4::identity()),
+            Instance::new(blas, 1, Mat4::identity()).with_motion(motion),
+        ])
+    }
+
+    #[test]
+    fn queries_place_moving_instances_at_the_given_time() {
+        let tlas = sliding();
+        // Building reorders the instances, so results are compared by id
+        let id = |i: u32| tlas.instances()[i as usize].id();
+        let ids = |pairs: Vec<(u32, u32)>| {
+            pairs
+                .into_iter()
+                .map(|(a, b)| (id(a).min(id(b)), id(a).max(id(b))))
+                .collect::<Vec<_>>()
+        };
+        let end = AABB::new(Vec3::new(8.0, -3.0, -3.0), Vec3::new(12.0, 3.0, 3.0));
+        assert!(tlas.overlapping_instances(&end, 0.0).is_empty());
+        assert_eq!(
+            tlas.overlapping_instances(&end, 1.0)
+                .into_iter()
+                .map(id)
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+        assert!(tlas.overlapping_primitives(&end, 0.0).is_empty());
+        assert!(!tlas.overlapping_primitives(&end, 1.0).is_empty());
+
+        let point = Vec3::new(10.0, 0.0, 0.0);
+        let at_end = tlas.closest_point(point, f32::MAX, 1.0).unwrap();
+        assert_eq!(id(at_end.object_id), 1);
+        assert!(at_end.distance < 4.0);
+        let at_start = tlas.closest_point(point, f32::MAX, 0.0).unwrap();
+        assert_eq!(id(at_start.object_id), 0);
+
+        // Halfway the moving instance passes through the resting one
+        assert!(tlas.overlapping_instance_pairs(0.0).is_empty());
+        assert_eq!(ids(tlas.overlapping_instance_pairs(0.5)), vec![(0, 1)]);
+        assert!(tlas.colliding_instance_pairs(0.0).is_empty());
+        assert_eq!(ids(tlas.colliding_instance_pairs(0.5)), vec![(0, 1)]);
+    }
+
+    #[test]
+    fn multi_hit_honours_filters() {
+        let (vertices, indices) = soup(200, 5);
+        let blas = Arc::new(Bvh::new(&vertices, &indices));
+        let tlas = TopLevelAccelerationStructure::new(&[
+            Instance::new(blas.clone(), 0, Mat4::identity()),
+            Instance::new(blas, 1, Mat4::from_translation(Vec3::new(0.5, 0.0, 0.0))),
+        ]);
+        let filter =
+            |hit: &CandidateHit| hit.instance_id == 0 && hit.primitive_id.is_multiple_of(2);
+
+        let mut rng = Rng::new(11);
+        let mut hits = 0;
+        for _ in 0..200 {
+            let ray = rng.ray();
+            let mut filtered = HitList::all();
+            tlas.traverse_all_filtered(&ray, &filter, &mut filtered);
+            let filtered = filtered.into_hits();
+
+            let expected: Vec<_> = tlas
+                .all_hits(&ray)
+                .into_iter()
+                .filter(|hit| {
+                    tlas.instances()[hit.object_id as usize].id() == 0
+                        && hit.primitive_id.is_multiple_of(2)
+                })
+                .collect();
+            assert_eq!(filtered.len(), expected.len());
+            hits += filtered.len();
+            for (hit, expected) in filtered.iter().zip(&expected) {
+                assert_eq!(hit.t, expected.t);
+                assert_eq!(hit.primitive_id, expected.primitive_id);
+            }
+        }
+        assert!(hits > 0);
+    }
+
+    #[test]
+    fn occlusion_matches_the_closest_hit() {
+        let tlas = sliding();
+        let mut rng = Rng::new(30);
+        let mut rays = Vec::new();
+        let mut t_max = Vec::new();
+        for _ in 0..1000 {
+            let ray = rng.ray().with_time(rng.next());
+            let hit = tlas.traverse(&ray);
+            let limit = if hit.t < f32::MAX {
+                hit.t * [0.99, 1.01][(rng.next() * 2.0) as usize]
+            } else {
+                rng.range(0.0, 30.0)
+            };
+            assert_eq!(tlas.occluded(&ray, limit), hit.t < limit);
+            rays.push(ray);
+            t_max.push(limit);
+        }
+
+        let batch = tlas.occluded_batch(&rays, &t_max);
+        for ((ray, t_max), occluded) in rays.iter().zip(&t_max).zip(batch) {
+            assert_eq!(occluded, tlas.occluded(ray, *t_max));
+        }
+    }
+
+    #[test]
+    fn cull_modes_match_brute_force() {
+        let (vertices, indices) = soup(300, 23);
+        // Closest hit on a single instance, skipping triangles facing the
+        // ray the way `mode` culls, in object space
+        let brute_force = |ray: &Ray, transform: &Mat4, mode: CullMode| {
+            let ray = ray.transformed(&transform.invert().unwrap());
+            let mut closest = (f32::MAX, 0);
+            for (primitive_id, triangle) in indices.chunks(3).enumerate() {
+                let [v0, v1, v2] = [0, 1, 2].map(|i| vertices[triangle[i] as usize]);
+                if mode.culls((v1 - v0).cross(v2 - v0), ray.direction) {
+                    continue;
+                }
+                let (mut t, mut u, mut v) = (0.0, 0.0, 0.0);
+                if intersect_triangle(&ray, &v0, &v1, &v2, &mut t, &mut u, &mut v) && t < closest.0
+                {
+                    closest = (t, primitive_id as u32);
+                }
+            }
+            closest
+        };
+
+        let shared = Arc::new(Bvh::new(&vertices, &indices));
+        let mut back = Bvh::new(&vertices, &indices);
+        back.set_cull_mode(CullMode::Back);
+        let back = Arc::new(back);
+        // Mirroring flips the winding in world space, the culled side is still
+        // decided in object space
+        let mirror = Mat4::from_nonuniform_scale(-1.0, 1.0, 1.0);
+        let instances = [
+            (
+                Instance::new(shared.clone(), 0, Mat4::identity()),
+                CullMode::None,
+            ),
+            (
+                Instance::new(back.clone(), 1, Mat4::identity()),
+                CullMode::Back,
+            ),
+            (
+                Instance::new(back, 2, mirror).with_cull_mode(CullMode::Front),
+                CullMode::Front,
+            ),
+            (
+                Instance::new(shared, 3, mirror).with_cull_mode(CullMode::Back),
+                CullMode::Back,
+            ),
+        ];
+
+        let mut rng = Rng::new(24);
+        let mut culled = 0;
+        for (instance, mode) in instances {
+            assert_eq!(instance.cull_mode(), mode);
+            let transform = *instance.transform();
+            let tlas = TopLevelAccelerationStructure::new(&[instance]);
+            for _ in 0..500 {
+                // From all around, so rays reach both sides of the triangles
+                let origin = rng.vec3(-1.0, 1.0).normalize() * 15.0;
+                let ray = Ray::new(origin, (rng.vec3(-2.0, 2.0) - origin).normalize());
+                let (t, primitive_id) = brute_force(&ray, &transform, mode);
+                if t != brute_force(&ray, &transform, CullMode::None).0 {
+                    culled += 1;
+                }
+
+                let hit = tlas.traverse(&ray);
+                assert!(hit.t == t || (hit.t - t).abs() < t * 1e-5);
+                if t < f32::MAX {
+                    assert_eq!(hit.primitive_id, primitive_id);
+                }
+                assert_eq!(tlas.occluded(&ray, f32::MAX), t < f32::MAX);
+            }
+        }
+        assert!(culled > 100);
+    }
+
+    #[test]
+    fn collisions_match_brute_force() {
+        let (vertices, indices) = soup(100, 45);
+        let blas = Arc::new(Bvh::new(&vertices, &indices));
+        let mut rng = Rng::new(46);
+        let instances: Vec<Instance> = (0..20)
+            .map(|id| {
+                let transform = Mat4::from_translation(rng.vec3(-4.0, 4.0)) * Mat4::from_scale(0.5);
+                Instance::new(blas.clone(), id, transform)
+            })
+            .collect();
+        let tlas = TopLevelAccelerationStructure::new(&instances);
+        let instances = tlas.instances();
+        let world: Vec<Vec<[Vertex; 3]>> = instances
+            .iter()
+            .map(|instance| {
+                vertices
+                    .chunks(3)
+                    .map(|triangle| {
+                        [0, 1, 2]
+                            .map(|i| (instance.transform() * triangle[i].extend(1.0)).truncate())
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let mut broad = Vec::new();
+        let mut narrow = Vec::new();
+        for a in 0..instances.len() {
+            for b in a + 1..instances.len() {
+                if !instances[a].aabb().overlaps_aabb(&instances[b].aabb()) {
+                    continue;
+                }
+                broad.push((a as u32, b as u32));
+                for (primitive_a, triangle_a) in world[a].iter().enumerate() {
+                    for (primitive_b, triangle_b) in world[b].iter().enumerate() {
+                        if intersect_triangles(triangle_a, triangle_b) {
+                            narrow.push((
+                                a as u32,
+                                primitive_a as u32,
+                                b as u32,
+                                primitive_b as u32,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        assert!(!narrow.is_empty());
+        narrow.sort_unstable();
+
+        let mut pairs = tlas.overlapping_instance_pairs(0.0);
+        pairs.sort_unstable();
+        assert_eq!(pairs, broad);
+
+        let mut collisions = Vec::new();
+        tlas.query_collisions(0.0, false, |a, primitive_a, b, primitive_b| {
+            collisions.push((a, primitive_a, b, primitive_b));
+            ControlFlow::Continue(())
+        });
+        collisions.sort_unstable();
+        assert_eq!(collisions, narrow);
+
+        let mut colliding = tlas.colliding_instance_pairs(0.0);
+        colliding.sort_unstable();
+        let mut expected: Vec<(u32, u32)> = narrow.iter().map(|&(a, _, b, _)| (a, b)).collect();
+        expected.sort_unstable();
+        expected.dedup();
+        assert_eq!(colliding, expected);
+    }
+}
@@ -1,5 +1,7 @@
 use cgmath::{InnerSpace, Matrix4, SquareMatrix, Vector2, Vector3, Vector4};
 
+use crate::intersect::offset_ray_origin;
+
 pub type Vec2 = Vector2<f32>;
 pub type Vec3 = Vector3<f32>;
 pub type Vec4 = Vector4<f32>;
@@ -108,13 +110,26 @@ impl AABB {
         self.extent() * 0.5
     }
 
+    // Squared distance from `p` to the closest point of the box, zero inside
+    pub fn distance_squared(&self, p: &Vec3) -> f32 {
+        let d = max(&(self.min - p), &(p - self.max)).map(|d| d.max(0.0));
+        d.dot(d)
+    }
+
+    // Bounds of all eight transformed corners, so rotated boxes stay enclosed
     pub fn transformed(&self, transform: &Mat4) -> Self {
-        let min = (transform * Vec4::new(self.min.x, self.min.y, self.min.z, 1.0)).truncate();
-        let max = (transform * Vec4::new(self.max.x, self.max.y, self.max.z, 1.0)).truncate();
-        Self {
-            min: Vector3::new(min.x, min.y, min.z),
-            max: Vector3::new(max.x, max.y, max.z),
+        let mut aabb = Self::default();
+        for corner in 0..8 {
+            let [x, y, z] = [0, 1, 2].map(|axis| {
+                if corner & (1 << axis) == 0 {
+                    self.min[axis]
+                } else {
+                    self.max[axis]
+                }
+            });
+            aabb.grow_with_position(&(transform * Vec4::new(x, y, z, 1.0)).truncate());
         }
+        aabb
     }
 }
 
@@ -133,6 +148,12 @@ pub struct Ray {
     pub origin: Origin,
     pub direction: Direction,
     pub color: HdrColor,
+    // Only hits with t_min < t < t_max count
+    pub t_min: f32,
+    pub t_max: f32,
+    // Point in the shutter interval [0, 1] the ray is traced at, moving
+    // instances are intersected with their transform at this time
+    pub time: f32,
 }
 
 impl Default for Ray {
@@ -141,6 +162,9 @@ impl Default for Ray {
             origin: Origin::new(0.0, 0.0, 0.0),
             direction: Direction::new(1.0, 1.0, 1.0),
             color: HdrColor::new(1.0, 1.0, 1.0, 1.0),
+            t_min: 0.0,
+            t_max: f32::MAX,
+            time: 0.0,
         }
     }
 }
@@ -151,15 +175,37 @@ impl Ray {
             origin,
             direction,
             color: HdrColor::new(1.0, 1.0, 1.0, 1.0),
+            t_min: 0.0,
+            t_max: f32::MAX,
+            time: 0.0,
         }
     }
 
+    pub fn with_time(mut self, time: f32) -> Self {
+        self.time = time;
+        self
+    }
+
+    pub fn with_interval(mut self, t_min: f32, t_max: f32) -> Self {
+        self.t_min = t_min;
+        self.t_max = t_max;
+        self
+    }
+
+    // The direction is normalized again, so the interval is scaled to still cover
+    // the same segment. `f32::MAX` stays unbounded.
     pub fn transformed(&self, transform: &Mat4) -> Self {
         let o =
             (transform * Vec4::new(self.origin.x, self.origin.y, self.origin.z, 1.0)).truncate();
         let d = (transform * Vec4::new(self.direction.x, self.direction.y, self.direction.z, 0.0))
-            .truncate()
-            .normalize();
+            .truncate();
+        let scale = d.magnitude();
+        let d = d / scale;
+        let t_max = if self.t_max == f32::MAX {
+            f32::MAX
+        } else {
+            self.t_max * scale
+        };
         Self::new(
             Vector3 {
                 x: o.x,
@@ -172,6 +218,16 @@ impl Ray {
                 z: d.z,
             },
         )
+        .with_interval(self.t_min * scale, t_max)
+        .with_time(self.time)
+    }
+
+    // Factor from distances along this ray to distances along the ray transformed
+    // by `transform`
+    pub fn transformed_scale(&self, transform: &Mat4) -> f32 {
+        (transform * self.direction.extend(0.0))
+            .truncate()
+            .magnitude()
     }
 }
 
@@ -184,6 +240,13 @@ pub struct HitRecord {
     pub object_id: u32,
     pub primitive_id: u32,
     pub obj_to_world: Mat4,
+    // World space hit point, computed by the primitive so it is usually more
+    // accurate than `ray.origin + ray.direction * t`
+    pub position: Position,
+    // Normalized world space normal of the surface itself, not interpolated
+    pub geometric_normal: Direction,
+    // Bound on the absolute error of `position` on every axis
+    pub position_error: Vec3,
 }
 
 impl HitRecord {
@@ -196,8 +259,39 @@ impl HitRecord {
             object_id: 0,
             primitive_id: 0,
             obj_to_world: Mat4::identity(),
+            position: Position::new(0.0, 0.0, 0.0),
+            geometric_normal: Direction::new(0.0, 0.0, 0.0),
+            position_error: Vec3::new(0.0, 0.0, 0.0),
         }
     }
+
+    // Ray leaving the hit point in `direction`, starting far enough from the
+    // surface that it can't hit it again, see `offset_ray_origin`
+    pub fn spawn_ray(&self, direction: Direction) -> Ray {
+        let origin = offset_ray_origin(
+            self.position,
+            self.position_error,
+            self.geometric_normal,
+            direction,
+        );
+        Ray::new(origin, direction.normalize()).with_time(self.ray.time)
+    }
+
+    // Ray from the hit point to `target` that stops just before it, for shadow
+    // rays. When `target` lies on a surface as well it should be offset too.
+    pub fn spawn_ray_to(&self, target: Position) -> Ray {
+        let origin = offset_ray_origin(
+            self.position,
+            self.position_error,
+            self.geometric_normal,
+            target - self.position,
+        );
+        let direction = target - origin;
+        let distance = direction.magnitude();
+        Ray::new(origin, direction / distance)
+            .with_interval(0.0, distance * (1.0 - 0.0001))
+            .with_time(self.ray.time)
+    }
 }
 
 impl Default for HitRecord {
@@ -205,3 +299,15 @@ impl Default for HitRecord {
         Self::new()
     }
 }
+
+// Result of a closest point query. `u` and `v` are the barycentrics of `point` in
+// the triangle, like for a `HitRecord`.
+#[derive(Clone, Copy)]
+pub struct ClosestPoint {
+    pub point: Position,
+    pub distance: f32,
+    pub u: f32,
+    pub v: f32,
+    pub object_id: u32,
+    pub primitive_id: u32,
+}
@@ -0,0 +1,505 @@
+use crate::{
+    bvh::Node,
+    types::{Ray, Vec3},
+};
+
+// Marks an unused child slot. Its bounds are inverted so it never gets hit.
+pub const EMPTY_CHILD: u32 = u32::MAX;
+// Every level pushes at most N - 1 children and the binary tree is at most 64 deep
+const STACK_SIZE: usize = 512;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BvhWidth {
+    Four,
+    Eight,
+}
+
+// Ordered from the fewest to the most instructions needed
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum SimdLevel {
+    Scalar,
+    Sse,
+    Avx,
+}
+
+impl SimdLevel {
+    pub fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx") {
+                return SimdLevel::Avx;
+            }
+            if is_x86_feature_detected!("sse2") {
+                return SimdLevel::Sse;
+            }
+        }
+
+        SimdLevel::Scalar
+    }
+
+    // The lower of this level and the one the cpu supports, so forcing a level
+    // can never run instructions the cpu lacks
+    pub fn supported(self) -> Self {
+        self.min(Self::detect())
+    }
+}
+
+// Child bounds are stored per axis so N children can be tested at once. Leaf
+// children have a primitive count and point into the triangle list, interior
+// children have a count of zero and point at another wide node.
+#[derive(Clone, Copy)]
+#[repr(C, align(32))]
+pub struct WideNode<const N: usize> {
+    pub min_x: [f32; N],
+    pub min_y: [f32; N],
+    pub min_z: [f32; N],
+    pub max_x: [f32; N],
+    pub max_y: [f32; N],
+    pub max_z: [f32; N],
+    pub child: [u32; N],
+    pub count: [u32; N],
+}
+
+impl<const N: usize> Default for WideNode<N> {
+    fn default() -> Self {
+        Self {
+            min_x: [f32::MAX; N],
+            min_y: [f32::MAX; N],
+            min_z: [f32::MAX; N],
+            max_x: [f32::MIN; N],
+            max_y: [f32::MIN; N],
+            max_z: [f32::MIN; N],
+            child: [EMPTY_CHILD; N],
+            count: [0; N],
+        }
+    }
+}
+
+impl<const N: usize> WideNode<N> {
+    fn set_child(&mut self, slot: usize, node: &Node, child: u32, count: u32) {
+        self.min_x[slot] = node.aabb.min.x;
+        self.min_y[slot] = node.aabb.min.y;
+        self.min_z[slot] = node.aabb.min.z;
+        self.max_x[slot] = node.aabb.max.x;
+        self.max_y[slot] = node.aabb.max.y;
+        self.max_z[slot] = node.aabb.max.z;
+        self.child[slot] = child;
+        self.count[slot] = count;
+    }
+}
+
+pub enum WideNodes {
+    Four(Vec<WideNode<4>>),
+    Eight(Vec<WideNode<8>>),
+}
+
+pub struct WideBvh {
+    nodes: WideNodes,
+    simd: SimdLevel,
+}
+
+impl WideBvh {
+    pub fn new(nodes: &[Node], width: BvhWidth, simd: SimdLevel) -> Self {
+        let nodes = match width {
+            BvhWidth::Four => WideNodes::Four(collapse(nodes)),
+            BvhWidth::Eight => WideNodes::Eight(collapse(nodes)),
+        };
+        Self {
+            nodes,
+            simd: simd.supported(),
+        }
+    }
+
+    pub fn width(&self) -> BvhWidth {
+        match self.nodes {
+            WideNodes::Four(_) => BvhWidth::Four,
+            WideNodes::Eight(_) => BvhWidth::Eight,
+        }
+    }
+
+    pub fn simd(&self) -> SimdLevel {
+        self.simd
+    }
+
+    pub fn nodes(&self) -> &WideNodes {
+        &self.nodes
+    }
+
+    pub fn size(&self) -> usize {
+        match &self.nodes {
+            WideNodes::Four(nodes) => std::mem::size_of_val(nodes.as_slice()),
+            WideNodes::Eight(nodes) => std::mem::size_of_val(nodes.as_slice()),
+        }
+    }
+
+    // Visits the leaves hit by the ray from near to far, see `Bvh::walk`
+    pub(crate) fn walk<F>(&self, inv_ray: &Ray, leaf: F)
+    where
+        F: FnMut(usize, usize, &mut f32) -> bool,
+    {
+        match &self.nodes {
+            WideNodes::Four(nodes) => walk(nodes, self.simd, inv_ray, leaf),
+            WideNodes::Eight(nodes) => walk(nodes, self.simd, inv_ray, leaf),
+        }
+    }
+}
+
+fn collapse<const N: usize>(nodes: &[Node]) -> Vec<WideNode<N>> {
+    let mut wide = vec![WideNode::default()];
+    let root = &nodes[0];
+    if root.primitive_count > 0 || nodes.len() == 1 {
+        wide[0].set_child(0, root, root.first_primitive, root.primitive_count);
+    } else {
+        collapse_node(nodes, 0, 0, &mut wide);
+    }
+    wide
+}
+
+// Pulls the grandchildren of the largest interior children up into this node
+// until all N slots are used or only leaves are left.
+fn collapse_node<const N: usize>(
+    nodes: &[Node],
+    idx: usize,
+    wide_idx: usize,
+    wide: &mut Vec<WideNode<N>>,
+) {
+    let left_child_index = nodes[idx].first_primitive as usize;
+    let mut children = vec![left_child_index, left_child_index + 1];
+    while children.len() < N {
+        let largest = children
+            .iter()
+            .enumerate()
+            .filter(|(_, child)| nodes[**child].primitive_count == 0)
+            .max_by(|(_, lhs), (_, rhs)| {
+                nodes[**lhs]
+                    .aabb
+                    .area()
+                    .total_cmp(&nodes[**rhs].aabb.area())
+            })
+            .map(|(slot, _)| slot);
+
+        match largest {
+            Some(slot) => {
+                let child = children.remove(slot);
+                let grand_child = nodes[child].first_primitive as usize;
+                children.extend([grand_child, grand_child + 1]);
+            }
+            None => break,
+        }
+    }
+
+    for (slot, child) in children.into_iter().enumerate() {
+        let node = &nodes[child];
+        if node.primitive_count > 0 {
+            wide[wide_idx].set_child(slot, node, node.first_primitive, node.primitive_count);
+        } else {
+            let child_wide_idx = wide.len();
+            wide.push(WideNode::default());
+            wide[wide_idx].set_child(slot, node, child_wide_idx as u32, 0);
+            collapse_node(nodes, child, child_wide_idx, wide);
+        }
+    }
+}
+
+// Ray data shared by every node test. The near and far planes per axis are
+// picked once from the direction signs, which also makes the inverted bounds of
+// empty slots miss without an extra mask.
+struct WideRay {
+    origin: Vec3,
+    inv_direction: Vec3,
+    negative: [bool; 3],
+    t_min: f32,
+}
+
+impl WideRay {
+    fn new(ray: &Ray) -> Self {
+        let inv_direction = ray.direction.map(|x| 1.0 / x);
+        Self {
+            origin: ray.origin,
+            inv_direction,
+            negative: [
+                inv_direction.x < 0.0,
+                inv_direction.y < 0.0,
+                inv_direction.z < 0.0,
+            ],
+            t_min: ray.t_min,
+        }
+    }
+}
+
+fn planes<'a, const N: usize>(
+    min: &'a [f32; N],
+    max: &'a [f32; N],
+    negative: bool,
+) -> (&'a [f32; N], &'a [f32; N]) {
+    if negative {
+        (max, min)
+    } else {
+        (min, max)
+    }
+}
+
+// Tests the ray against all children. Returns a bit mask of the children that were
+// hit between the ray's `t_min` and `t_max` and writes their entry distances to
+// `t_near`.
+fn intersect_children<const N: usize>(
+    node: &WideNode<N>,
+    ray: &WideRay,
+    t_max: f32,
+    simd: SimdLevel,
+    t_near: &mut [f32; N],
+) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if simd == SimdLevel::Avx && N.is_multiple_of(8) {
+            // Safety: `WideBvh::new` only keeps levels the cpu supports
+            return unsafe { x86::intersect_children_avx(node, ray, t_max, t_near) };
+        }
+        if simd != SimdLevel::Scalar && N.is_multiple_of(4) {
+            // Safety: `WideBvh::new` only keeps levels the cpu supports
+            return unsafe { x86::intersect_children_sse(node, ray, t_max, t_near) };
+        }
+    }
+
+    intersect_children_scalar(node, ray, t_max, t_near)
+}
+
+fn intersect_children_scalar<const N: usize>(
+    node: &WideNode<N>,
+    ray: &WideRay,
+    t_max: f32,
+    t_near: &mut [f32; N],
+) -> u32 {
+    let (near_x, far_x) = planes(&node.min_x, &node.max_x, ray.negative[0]);
+    let (near_y, far_y) = planes(&node.min_y, &node.max_y, ray.negative[1]);
+    let (near_z, far_z) = planes(&node.min_z, &node.max_z, ray.negative[2]);
+    let o = ray.origin;
+    let inv = ray.inv_direction;
+
+    let mut mask = 0;
+    for i in 0..N {
+        let near = ((near_x[i] - o.x) * inv.x)
+            .max((near_y[i] - o.y) * inv.y)
+            .max((near_z[i] - o.z) * inv.z)
+            .max(ray.t_min);
+        let far = ((far_x[i] - o.x) * inv.x)
+            .min((far_y[i] - o.y) * inv.y)
+            .min((far_z[i] - o.z) * inv.z)
+            .min(t_max);
+        t_near[i] = near;
+        if near <= far {
+            mask |= 1 << i;
+        }
+    }
+    mask
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::{planes, WideNode, WideRay};
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn intersect_children_sse<const N: usize>(
+        node: &WideNode<N>,
+        ray: &WideRay,
+        t_max: f32,
+        t_near: &mut [f32; N],
+    ) -> u32 {
+        let (near_x, far_x) = planes(&node.min_x, &node.max_x, ray.negative[0]);
+        let (near_y, far_y) = planes(&node.min_y, &node.max_y, ray.negative[1]);
+        let (near_z, far_z) = planes(&node.min_z, &node.max_z, ray.negative[2]);
+        let ox = _mm_set1_ps(ray.origin.x);
+        let oy = _mm_set1_ps(ray.origin.y);
+        let oz = _mm_set1_ps(ray.origin.z);
+        let ix = _mm_set1_ps(ray.inv_direction.x);
+        let iy = _mm_set1_ps(ray.inv_direction.y);
+        let iz = _mm_set1_ps(ray.inv_direction.z);
+        let t_min = _mm_set1_ps(ray.t_min);
+        let t_max = _mm_set1_ps(t_max);
+
+        let mut mask = 0;
+        for k in (0..N).step_by(4) {
+            let slab = |planes: &[f32; N], o: __m128, inv: __m128| {
+                _mm_mul_ps(_mm_sub_ps(_mm_loadu_ps(planes[k..].as_ptr()), o), inv)
+            };
+            let near = _mm_max_ps(
+                _mm_max_ps(slab(near_x, ox, ix), slab(near_y, oy, iy)),
+                _mm_max_ps(slab(near_z, oz, iz), t_min),
+            );
+            let far = _mm_min_ps(
+                _mm_min_ps(slab(far_x, ox, ix), slab(far_y, oy, iy)),
+                _mm_min_ps(slab(far_z, oz, iz), t_max),
+            );
+            _mm_storeu_ps(t_near[k..].as_mut_ptr(), near);
+            mask |= (_mm_movemask_ps(_mm_cmple_ps(near, far)) as u32) << k;
+        }
+        mask
+    }
+
+    #[target_feature(enable = "avx")]
+    pub unsafe fn intersect_children_avx<const N: usize>(
+        node: &WideNode<N>,
+        ray: &WideRay,
+        t_max: f32,
+        t_near: &mut [f32; N],
+    ) -> u32 {
+        let (near_x, far_x) = planes(&node.min_x, &node.max_x, ray.negative[0]);
+        let (near_y, far_y) = planes(&node.min_y, &node.max_y, ray.negative[1]);
+        let (near_z, far_z) = planes(&node.min_z, &node.max_z, ray.negative[2]);
+        let ox = _mm256_set1_ps(ray.origin.x);
+        let oy = _mm256_set1_ps(ray.origin.y);
+        let oz = _mm256_set1_ps(ray.origin.z);
+        let ix = _mm256_set1_ps(ray.inv_direction.x);
+        let iy = _mm256_set1_ps(ray.inv_direction.y);
+        let iz = _mm256_set1_ps(ray.inv_direction.z);
+        let t_min = _mm256_set1_ps(ray.t_min);
+        let t_max = _mm256_set1_ps(t_max);
+
+        let mut mask = 0;
+        for k in (0..N).step_by(8) {
+            let slab = |planes: &[f32; N], o: __m256, inv: __m256| {
+                _mm256_mul_ps(_mm256_sub_ps(_mm256_loadu_ps(planes[k..].as_ptr()), o), inv)
+            };
+            let near = _mm256_max_ps(
+                _mm256_max_ps(slab(near_x, ox, ix), slab(near_y, oy, iy)),
+                _mm256_max_ps(slab(near_z, oz, iz), t_min),
+            );
+            let far = _mm256_min_ps(
+                _mm256_min_ps(slab(far_x, ox, ix), slab(far_y, oy, iy)),
+                _mm256_min_ps(slab(far_z, oz, iz), t_max),
+            );
+            _mm256_storeu_ps(t_near[k..].as_mut_ptr(), near);
+            mask |= (_mm256_movemask_ps(_mm256_cmp_ps::<_CMP_LE_OQ>(near, far)) as u32) << k;
+        }
+        mask
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct StackEntry {
+    child: u32,
+    count: u32,
+    t: f32,
+}
+
+fn walk<const N: usize, F>(nodes: &[WideNode<N>], simd: SimdLevel, inv_ray: &Ray, mut leaf: F)
+where
+    F: FnMut(usize, usize, &mut f32) -> bool,
+{
+    let wide_ray = WideRay::new(inv_ray);
+
+    let mut stack = [StackEntry::default(); STACK_SIZE];
+    let mut stack_ptr = 1;
+    let mut d = inv_ray.t_max;
+    while stack_ptr > 0 {
+        stack_ptr -= 1;
+        let entry = stack[stack_ptr];
+        if entry.t > d {
+            continue;
+        }
+
+        if entry.count > 0 {
+            if leaf(entry.child as usize, entry.count as usize, &mut d) {
+                return;
+            }
+            continue;
+        }
+
+        let node = &nodes[entry.child as usize];
+        let mut t_near = [0.0; N];
+        let mut mask = intersect_children(node, &wide_ray, d, simd, &mut t_near);
+
+        // Push the hit children far to near so the closest one is popped first
+        let first_pushed = stack_ptr;
+        while mask != 0 {
+            let slot = mask.trailing_zeros() as usize;
+            mask &= mask - 1;
+            let mut i = stack_ptr;
+            while i > first_pushed && stack[i - 1].t < t_near[slot] {
+                stack[i] = stack[i - 1];
+                i -= 1;
+            }
+            stack[i] = StackEntry {
+                child: node.child[slot],
+                count: node.count[slot],
+                t: t_near[slot],
+            };
+            stack_ptr += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::SquareMatrix;
+
+    use super::{BvhWidth, SimdLevel};
+    use crate::{
+        bvh::Bvh,
+        testing::{soup, Rng},
+        types::{HitRecord, Mat4, Vec3, Vertex},
+    };
+
+    fn assert_same_hits(binary: &Bvh, wide: &Bvh, transform: &Mat4, seed: u64) {
+        let mut rng = Rng::new(seed);
+        for _ in 0..1000 {
+            let ray = rng.ray();
+            let mut expected = HitRecord::new();
+            let mut hit = HitRecord::new();
+            binary.traverse(&ray, transform, &mut expected);
+            wide.traverse(&ray, transform, &mut hit);
+            assert_eq!(
+                (hit.t, hit.u, hit.v, hit.primitive_id),
+                (expected.t, expected.u, expected.v, expected.primitive_id)
+            );
+            assert_eq!(hit.position, expected.position);
+            assert_eq!(hit.geometric_normal, expected.geometric_normal);
+        }
+    }
+
+    #[test]
+    fn wide_trees_match_binary() {
+        let (vertices, indices) = soup(3000, 15);
+        let binary = Bvh::new(&vertices, &indices);
+        let transform = Mat4::from_translation(Vec3::new(0.5, 0.0, 1.0)) * Mat4::from_scale(0.8);
+        for width in [BvhWidth::Four, BvhWidth::Eight] {
+            for simd in [SimdLevel::Scalar, SimdLevel::Sse, SimdLevel::Avx] {
+                let mut wide = Bvh::new(&vertices, &indices);
+                wide.collapse_with_simd(width, simd);
+                assert!(wide.wide().is_some());
+                assert_same_hits(&binary, &wide, &Mat4::identity(), 16);
+                assert_same_hits(&binary, &wide, &transform, 17);
+            }
+        }
+    }
+
+    #[test]
+    fn forced_levels_fall_back_to_supported_ones() {
+        let detected = SimdLevel::detect();
+        let (vertices, indices) = soup(100, 19);
+        for simd in [SimdLevel::Scalar, SimdLevel::Sse, SimdLevel::Avx] {
+            let mut wide = Bvh::new(&vertices, &indices);
+            wide.collapse_with_simd(BvhWidth::Eight, simd);
+            let kept = wide.wide().unwrap().simd();
+            assert_eq!(kept, simd.min(detected));
+            assert!(kept <= detected);
+        }
+    }
+
+    #[test]
+    fn single_leaf_trees_collapse() {
+        let vertices = [
+            Vertex::new(-3.0, -3.0, 0.0),
+            Vertex::new(3.0, -3.0, 0.0),
+            Vertex::new(0.0, 3.0, 0.0),
+        ];
+        let binary = Bvh::new(&vertices, &[0, 1, 2]);
+        for width in [BvhWidth::Four, BvhWidth::Eight] {
+            let mut wide = Bvh::new(&vertices, &[0, 1, 2]);
+            wide.collapse(width);
+            assert_same_hits(&binary, &wide, &Mat4::identity(), 18);
+        }
+    }
+}